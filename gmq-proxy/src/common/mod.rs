@@ -1,14 +1,92 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gmq_remoting::{common::code::ResponseCode, util};
+use prost_types::Timestamp;
 use thiserror::Error;
 
+use crate::pb::{Code, Status};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("The topic {0} does not exist, reason: {1}")]
     TopicNotFound(String, anyhow::Error),
+    #[error("The broker responds with code {code}, remark: {remark}")]
+    BrokerError { code: i32, remark: String },
+    #[error("The receipt handle {0:?} is invalid")]
+    InvalidReceiptHandle(String),
+    #[error("The client {0} is not connected")]
+    ClientNotConnected(String),
     #[error(transparent)]
     InternalError(#[from] anyhow::Error),
 }
 
-#[repr(u8)]
-pub enum RequestCode {
-    GetTopicRouteInfo = 105,
+impl Error {
+    /// Converts the error into the status returned to gRPC clients.
+    pub fn to_status(&self) -> Status {
+        let code = match self {
+            Error::TopicNotFound(..) => Code::TopicNotFound,
+            Error::InvalidReceiptHandle(_) => Code::InvalidReceiptHandle,
+            Error::ClientNotConnected(_) => Code::InternalServerError,
+            Error::BrokerError { code, .. } => match ResponseCode::try_from(*code) {
+                Ok(ResponseCode::TopicNotExist) => Code::TopicNotFound,
+                Ok(ResponseCode::NoPermission) => Code::Forbidden,
+                Ok(ResponseCode::MessageIllegal) => Code::BadRequest,
+                Ok(ResponseCode::SystemBusy) | Ok(ResponseCode::PollingFull) => {
+                    Code::TooManyRequests
+                }
+                Ok(ResponseCode::FlushDiskTimeout) => Code::MasterPersistenceTimeout,
+                Ok(ResponseCode::FlushSlaveTimeout) => Code::SlavePersistenceTimeout,
+                Ok(ResponseCode::SlaveNotAvailable) => Code::HaNotAvailable,
+                _ => Code::InternalServerError,
+            },
+            Error::InternalError(e) => match e.downcast_ref::<util::Error>() {
+                Some(util::Error::Timeout) => Code::ProxyTimeout,
+                _ => Code::InternalServerError,
+            },
+        };
+        status(code, self.to_string())
+    }
+}
+
+pub fn status(code: Code, message: impl Into<String>) -> Status {
+    Status {
+        code: code as i32,
+        message: message.into(),
+    }
+}
+
+pub fn ok_status() -> Status {
+    status(Code::Ok, "ok")
+}
+
+/// The status of a batch request: the status shared by all the entries, or `MultipleResults`
+/// if they differ.
+pub fn batch_status<'a>(statuses: impl IntoIterator<Item = &'a Status>) -> Status {
+    let mut statuses = statuses.into_iter();
+    let Some(first) = statuses.next() else {
+        return ok_status();
+    };
+    if statuses.all(|status| status.code == first.code) {
+        first.clone()
+    } else {
+        status(Code::MultipleResults, "multiple results")
+    }
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+pub fn timestamp_to_millis(timestamp: &Timestamp) -> i64 {
+    timestamp.seconds * 1000 + timestamp.nanos as i64 / 1_000_000
+}
+
+pub fn millis_to_timestamp(millis: i64) -> Timestamp {
+    Timestamp {
+        seconds: millis.div_euclid(1000),
+        nanos: (millis.rem_euclid(1000) * 1_000_000) as i32,
+    }
 }
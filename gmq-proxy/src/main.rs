@@ -1,14 +1,50 @@
-use gmq_proxy::{remoting::client::MQClient, service::server::GrpcMessagingServer};
+use std::sync::Arc;
+
+use gmq_proxy::{
+    remoting::client::MQClient,
+    service::{
+        group_config::GroupConfigManager, proxy_config::ProxyConfig, server::GrpcMessagingServer,
+        topic_config::TopicConfigManager,
+    },
+};
+use parking_lot::RwLock;
 
 #[tokio::main]
 async fn main() {
-    println!("Hello, world!");
-    let mut mq_client = MQClient::new("127.0.0.1:8080");
-    let _ = mq_client.start().await;
-    let result = mq_client.query_route("bbb").await;
-    println!("result: {:?}", result);
+    let proxy_config = match ProxyConfig::load(".") {
+        Ok(config) => config,
+        Err(e) => {
+            println!("load proxy config failed: {:?}", e);
+            return;
+        }
+    };
+
+    let mut mq_client = MQClient::new(proxy_config.namesrv_addr());
+    if let Err(e) = mq_client.start().await {
+        println!("start mq client failed: {:?}", e);
+        return;
+    }
+
+    let mut topic_config_manager = TopicConfigManager::new(".");
+    if let Err(e) = topic_config_manager.load() {
+        println!("load topic config failed: {:?}", e);
+        return;
+    }
 
-    println!("result2: {:?}", mq_client.query_route("bbb").await);
+    let mut group_config_manager = GroupConfigManager::new(".");
+    if let Err(e) = group_config_manager.load() {
+        println!("load group config failed: {:?}", e);
+        return;
+    }
 
-    mq_client.shutdown().await;
-}
\ No newline at end of file
+    let mut server = GrpcMessagingServer::new(
+        Arc::new(mq_client),
+        Arc::new(RwLock::new(topic_config_manager)),
+        Arc::new(RwLock::new(group_config_manager)),
+        proxy_config.setting_policy(),
+        proxy_config.admin_addr(),
+    );
+    if let Err(e) = server.start().await {
+        println!("grpc server exits with error: {:?}", e);
+    }
+}
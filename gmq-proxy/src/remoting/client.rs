@@ -1,17 +1,56 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use gmq_remoting::{channel::Channel, common::command::Command};
-use serde::Deserialize;
+use gmq_remoting::{
+    channel::ChannelState,
+    common::{
+        code::{RequestCode, ResponseCode},
+        command::Command,
+    },
+    pool::ChannelPool,
+    processor::RequestProcessor,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{select, time::interval};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::common::{Error, RequestCode};
+use super::{
+    header::{
+        decode_offset, AckMessageRequestHeader, ChangeInvisibleTimeRequestHeader,
+        ChangeInvisibleTimeResponseHeader, ConsumerSendMsgBackRequestHeader,
+        EndTransactionRequestHeader, PopMessageRequestHeader, PopMessageResponseHeader,
+        PullMessageRequestHeader, PullMessageResponseHeader, QueryConsumerOffsetRequestHeader,
+        QueueOffsetRequestHeader, SendMessageRequestHeader, SendMessageResponseHeader,
+        UpdateConsumerOffsetRequestHeader,
+    },
+    message::{decode_messages, MessageExt},
+};
+use crate::common::Error;
+
+const NAMESRV_SELECT_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct MQClient {
-    addr: String,
-    channel: Option<Channel>,
+    namesrv_addrs: Arc<Vec<String>>,
+    namesrv_index: Arc<AtomicUsize>,
+    pool: Arc<ChannelPool>,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
 }
 
+pub const MASTER_ID: i64 = 0;
+
+const PERM_WRITE: u32 = 0x1 << 1;
+const PERM_READ: u32 = 0x1 << 2;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct QueueData {
     #[serde(alias = "brokerName")]
@@ -40,59 +79,523 @@ pub struct TopicRouteData {
     broker_datas: Vec<BrokerData>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ProducerData {
+    #[serde(rename = "groupName")]
+    pub group_name: String,
+}
+
+/**
+ * The body of HEART_BEAT requests, which registers the client as a member of its groups on
+ * brokers.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct HeartbeatData {
+    #[serde(rename = "clientID")]
+    pub client_id: String,
+    #[serde(rename = "producerDataSet")]
+    pub producer_data_set: Vec<ProducerData>,
+    #[serde(rename = "consumerDataSet")]
+    pub consumer_data_set: Vec<serde_json::Value>,
+}
+
+impl QueueData {
+    pub fn broker_name(&self) -> &str {
+        &self.broker_name
+    }
+
+    pub fn read_queue_nums(&self) -> i32 {
+        self.read_queue_nums
+    }
+
+    pub fn write_queue_nums(&self) -> i32 {
+        self.write_queue_nums
+    }
+
+    pub fn perm(&self) -> u32 {
+        self.perm
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.perm & PERM_READ == PERM_READ
+    }
+
+    pub fn is_writeable(&self) -> bool {
+        self.perm & PERM_WRITE == PERM_WRITE
+    }
+}
+
+impl BrokerData {
+    pub fn cluster(&self) -> &str {
+        &self.cluster
+    }
+
+    pub fn broker_name(&self) -> &str {
+        &self.broker_name
+    }
+
+    pub fn broker_addrs(&self) -> &HashMap<i64, String> {
+        &self.broker_addrs
+    }
+
+    pub fn master_addr(&self) -> Option<&String> {
+        self.broker_addrs.get(&MASTER_ID)
+    }
+}
+
+impl TopicRouteData {
+    pub fn queue_datas(&self) -> &[QueueData] {
+        &self.queue_datas
+    }
+
+    pub fn broker_datas(&self) -> &[BrokerData] {
+        &self.broker_datas
+    }
+}
+
 impl MQClient {
-    pub fn new(addr: &str) -> Self {
+    /// Creates a client of the NameServers in `namesrv_addr`, separated by `;`. One of them
+    /// is picked randomly and the others are failed over to when it becomes unavailable.
+    pub fn new(namesrv_addr: &str) -> Self {
+        let namesrv_addrs: Vec<String> = namesrv_addr
+            .split(';')
+            .map(|addr| addr.trim())
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| addr.to_string())
+            .collect();
+        let namesrv_index = if namesrv_addrs.is_empty() {
+            0
+        } else {
+            rand::thread_rng().gen_range(0..namesrv_addrs.len())
+        };
         Self {
-            addr: addr.to_string(),
-            channel: None,
+            namesrv_addrs: Arc::new(namesrv_addrs),
+            namesrv_index: Arc::new(AtomicUsize::new(namesrv_index)),
+            pool: Arc::new(ChannelPool::new()),
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
         }
     }
 
     pub async fn start(&mut self) -> Result<(), Error> {
-        let result = Channel::new(&self.addr).await;
-        match result {
-            Ok(channel) => {
-                self.channel = Some(channel);
-                return Ok(());
-            }
-            Err(e) => {
-                return Err(Error::InternalError(e.into()));
+        MQClient::select_namesrv(&self.pool, &self.namesrv_addrs, &self.namesrv_index).await?;
+        self.pool.start();
+
+        let pool = Arc::clone(&self.pool);
+        let namesrv_addrs = Arc::clone(&self.namesrv_addrs);
+        let namesrv_index = Arc::clone(&self.namesrv_index);
+        let shutdown_token = self.shutdown_token.clone();
+        self.shutdown_tracker.spawn(async move {
+            let mut ticker = interval(NAMESRV_SELECT_INTERVAL);
+            loop {
+                select! {
+                    _ = ticker.tick() => {
+                        if let Err(e) = MQClient::select_namesrv(&pool, &namesrv_addrs, &namesrv_index).await {
+                            println!("select NameServer failed: {:?}", e);
+                        }
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
             }
-        }
+        });
+        Ok(())
     }
 
-    pub async fn query_route(&mut self, topic: &str) -> Result<TopicRouteData, Error> {
-        if let Some(channel) = self.channel.as_ref() {
-            let mut headers = HashMap::new();
-            headers.insert("topic".to_string(), topic.to_string());
-            headers.insert("acceptStandardJsonOnly".to_string(), "true".to_string());
-            let cmd = Command::new_with_header(RequestCode::GetTopicRouteInfo as u8, headers);
-            let result = channel.request(cmd).await;
-            match result {
-                Ok(command) => {
-                    if let Some(body) = command.body() {
-                        return serde_json::from_slice(body).map_err(|e| {
-                            return Error::TopicNotFound(topic.to_string(), e.into());
-                        });
-                    } else {
-                        return Err(Error::TopicNotFound(
-                            topic.to_string(),
-                            anyhow!("no body in response"),
-                        ));
+    /// The address of the NameServer currently in use.
+    pub fn namesrv_addr(&self) -> Option<&str> {
+        self.namesrv_addrs
+            .get(self.namesrv_index.load(Ordering::Relaxed))
+            .map(|addr| addr.as_str())
+    }
+
+    /// Keeps the selected NameServer if it is connected, otherwise selects the next reachable one.
+    async fn select_namesrv(
+        pool: &ChannelPool,
+        namesrv_addrs: &[String],
+        namesrv_index: &AtomicUsize,
+    ) -> Result<(), Error> {
+        let start = namesrv_index.load(Ordering::Relaxed);
+        for i in 0..namesrv_addrs.len() {
+            let index = (start + i) % namesrv_addrs.len();
+            let addr = &namesrv_addrs[index];
+            match pool.get_channel(addr).await {
+                Ok(channel) if channel.state() == ChannelState::Active => {
+                    if index != start {
+                        println!("select NameServer {}", addr);
                     }
+                    namesrv_index.store(index, Ordering::Relaxed);
+                    return Ok(());
                 }
+                Ok(_) => pool.evict(addr).await,
+                Err(e) => println!("connect to NameServer {} failed: {:?}", addr, e),
+            }
+        }
+        Err(Error::InternalError(anyhow!(
+            "no NameServer is reachable among {:?}",
+            namesrv_addrs
+        )))
+    }
+
+    /// Sends the command to the selected NameServer, failing over to the next one on errors.
+    async fn invoke_namesrv(&self, cmd: Command) -> Result<Command, Error> {
+        let mut last_error = anyhow!("no NameServer address");
+        for _ in 0..self.namesrv_addrs.len() {
+            let index = self.namesrv_index.load(Ordering::Relaxed);
+            let addr = &self.namesrv_addrs[index];
+            match self.pool.invoke(addr, cmd.clone()).await {
+                Ok(response) => return Ok(response),
                 Err(e) => {
-                    return Err(Error::TopicNotFound(topic.to_string(), e.into()));
+                    println!("invoke NameServer {} failed: {:?}, fail over", addr, e);
+                    let next = (index + 1) % self.namesrv_addrs.len();
+                    let _ = self.namesrv_index.compare_exchange(
+                        index,
+                        next,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                    last_error = e.into();
                 }
             }
-        } else {
-            Err(Error::InternalError(anyhow!("channel is not ready")))
+        }
+        Err(Error::InternalError(last_error))
+    }
+
+    /// Sends the command to the NameServer or broker of the address and waits for the response.
+    pub async fn invoke(&self, addr: &str, cmd: Command) -> Result<Command, Error> {
+        self.pool
+            .invoke(addr, cmd)
+            .await
+            .map_err(|e| Error::InternalError(e.into()))
+    }
+
+    pub async fn invoke_with_timeout(
+        &self,
+        addr: &str,
+        cmd: Command,
+        timeout: Duration,
+    ) -> Result<Command, Error> {
+        self.pool
+            .invoke_with_timeout(addr, cmd, timeout)
+            .await
+            .map_err(|e| Error::InternalError(e.into()))
+    }
+
+    pub async fn query_route(&self, topic: &str) -> Result<TopicRouteData, Error> {
+        let mut headers = HashMap::new();
+        headers.insert("topic".to_string(), topic.to_string());
+        headers.insert("acceptStandardJsonOnly".to_string(), "true".to_string());
+        let cmd = Command::new_with_header(RequestCode::GetTopicRouteInfo, headers);
+        let command = self.invoke_namesrv(cmd).await?;
+        match ResponseCode::try_from(command.code()) {
+            Ok(ResponseCode::Success) => match command.body() {
+                Some(body) => {
+                    serde_json::from_slice(body).map_err(|e| Error::InternalError(e.into()))
+                }
+                None => Err(Error::TopicNotFound(
+                    topic.to_string(),
+                    anyhow!("no body in response"),
+                )),
+            },
+            Ok(ResponseCode::TopicNotExist) => Err(Error::TopicNotFound(
+                topic.to_string(),
+                anyhow!("{}", command.remark().unwrap_or_default()),
+            )),
+            _ => Err(Error::InternalError(anyhow!(
+                "query route failed, code: {}, remark: {}",
+                command.code(),
+                command.remark().unwrap_or_default()
+            ))),
+        }
+    }
+
+    /// Sends a message to the broker of the address with SEND_MESSAGE_V2.
+    pub async fn send_message(
+        &self,
+        addr: &str,
+        header: SendMessageRequestHeader,
+        body: Vec<u8>,
+    ) -> Result<SendMessageResponseHeader, Error> {
+        let response = self.invoke(addr, header.into_command(body)).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => SendMessageResponseHeader::decode(&response),
+            _ => Err(broker_error(&response)),
         }
     }
 
-    pub async fn shutdown(self) {
-        if let Some(channel) = self.channel.as_ref() {
-            channel.shutdown().await;
+    /// Pops messages from the broker of the address, returns `None` if no message shows up
+    /// before the long polling times out.
+    pub async fn pop_message(
+        &self,
+        addr: &str,
+        header: PopMessageRequestHeader,
+        timeout: Duration,
+    ) -> Result<Option<PopResult>, Error> {
+        let response = self
+            .invoke_with_timeout(addr, header.into_command(), timeout)
+            .await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => Ok(Some(PopResult {
+                header: PopMessageResponseHeader::decode(&response)?,
+                messages: decode_messages(response.body().unwrap_or_default())?,
+            })),
+            Ok(ResponseCode::PollingTimeout)
+            | Ok(ResponseCode::PullNotFound)
+            | Ok(ResponseCode::NoMessage) => Ok(None),
+            _ => Err(broker_error(&response)),
         }
     }
+
+    /// Pulls messages from a queue from the offset, the broker suspends the request until new
+    /// messages arrive if there is none.
+    pub async fn pull_message(
+        &self,
+        addr: &str,
+        header: PullMessageRequestHeader,
+        timeout: Duration,
+    ) -> Result<PullResult, Error> {
+        let response = self
+            .invoke_with_timeout(addr, header.into_command(), timeout)
+            .await?;
+        let status = match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => PullStatus::Found,
+            Ok(ResponseCode::PullNotFound) => PullStatus::NoNewMessage,
+            Ok(ResponseCode::PullRetryImmediately) => PullStatus::NoMatchedMessage,
+            Ok(ResponseCode::PullOffsetMoved) => PullStatus::OffsetIllegal,
+            _ => return Err(broker_error(&response)),
+        };
+        let messages = match status {
+            PullStatus::Found => decode_messages(response.body().unwrap_or_default())?,
+            _ => vec![],
+        };
+        Ok(PullResult {
+            status,
+            header: PullMessageResponseHeader::decode(&response)?,
+            messages,
+        })
+    }
+
+    /// Returns the consume offset of the group in the queue, `None` if the group has not
+    /// committed any.
+    pub async fn query_consumer_offset(
+        &self,
+        addr: &str,
+        header: QueryConsumerOffsetRequestHeader,
+    ) -> Result<Option<i64>, Error> {
+        let response = self.invoke(addr, header.into_command()).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => decode_offset(&response).map(Some),
+            Ok(ResponseCode::QueryNotFound) => Ok(None),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    pub async fn update_consumer_offset(
+        &self,
+        addr: &str,
+        header: UpdateConsumerOffsetRequestHeader,
+    ) -> Result<(), Error> {
+        let response = self.invoke(addr, header.into_command()).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => Ok(()),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    /// Looks up an offset of the queue with GET_MIN_OFFSET, GET_MAX_OFFSET or
+    /// SEARCH_OFFSET_BY_TIMESTAMP.
+    pub async fn query_queue_offset(
+        &self,
+        addr: &str,
+        code: RequestCode,
+        header: QueueOffsetRequestHeader,
+    ) -> Result<i64, Error> {
+        let response = self.invoke(addr, header.into_command(code)).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => decode_offset(&response),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    pub async fn ack_message(
+        &self,
+        addr: &str,
+        header: AckMessageRequestHeader,
+    ) -> Result<(), Error> {
+        let response = self.invoke(addr, header.into_command()).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => Ok(()),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    /// Sends a consumed message back to the broker, which stores it to the retry topic or the
+    /// dead letter queue of the group.
+    pub async fn consumer_send_msg_back(
+        &self,
+        addr: &str,
+        header: ConsumerSendMsgBackRequestHeader,
+    ) -> Result<(), Error> {
+        let response = self.invoke(addr, header.into_command()).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => Ok(()),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    /// Handles the requests of the code brokers send to the proxy, such as transaction checks.
+    pub fn register_processor(&self, code: RequestCode, processor: Arc<dyn RequestProcessor>) {
+        self.pool.register_processor(code, processor);
+    }
+
+    pub async fn send_heartbeat(&self, addr: &str, heartbeat: &HeartbeatData) -> Result<(), Error> {
+        let mut cmd = Command::new_with_header(RequestCode::HeartBeat, HashMap::new());
+        cmd.set_body(serde_json::to_vec(heartbeat).map_err(|e| Error::InternalError(e.into()))?);
+        let response = self.invoke(addr, cmd).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => Ok(()),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    /// Commits or rolls back a half message, brokers do not respond END_TRANSACTION requests.
+    pub async fn end_transaction(
+        &self,
+        addr: &str,
+        header: EndTransactionRequestHeader,
+    ) -> Result<(), Error> {
+        self.pool
+            .invoke_oneway(addr, header.into_command())
+            .await
+            .map_err(|e| Error::InternalError(e.into()))
+    }
+
+    /// Changes the invisible time of a popped message, the broker checkpoints the message again
+    /// and responds the new pop time.
+    pub async fn change_invisible_time(
+        &self,
+        addr: &str,
+        header: ChangeInvisibleTimeRequestHeader,
+    ) -> Result<ChangeInvisibleTimeResponseHeader, Error> {
+        let response = self.invoke(addr, header.into_command()).await?;
+        match ResponseCode::try_from(response.code()) {
+            Ok(ResponseCode::Success) => ChangeInvisibleTimeResponseHeader::decode(&response),
+            _ => Err(broker_error(&response)),
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+        self.pool.shutdown().await;
+    }
+}
+
+#[derive(Debug)]
+pub struct PopResult {
+    pub header: PopMessageResponseHeader,
+    pub messages: Vec<MessageExt>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullStatus {
+    Found,
+    NoNewMessage,
+    /// There are new messages, but none of them matches the subscription.
+    NoMatchedMessage,
+    /// The offset is out of the range of the queue, pulls should restart from the next begin
+    /// offset.
+    OffsetIllegal,
+}
+
+#[derive(Debug)]
+pub struct PullResult {
+    pub status: PullStatus,
+    pub header: PullMessageResponseHeader,
+    pub messages: Vec<MessageExt>,
+}
+
+fn broker_error(response: &Command) -> Error {
+    Error::BrokerError {
+        code: response.code(),
+        remark: response.remark().unwrap_or_default().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use gmq_remoting::{processor::RequestProcessor, server::RemotingServer, util};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    struct RouteProcessor;
+
+    #[async_trait]
+    impl RequestProcessor for RouteProcessor {
+        async fn process(&self, request: &Command) -> Result<Option<Command>, util::Error> {
+            if request.get_property("topic").unwrap() != "test" {
+                return Ok(Some(Command::new_response(
+                    request,
+                    ResponseCode::TopicNotExist,
+                )));
+            }
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            let route = json!({
+                "queueDatas": [
+                    {"brokerName": "broker-a", "readQueueNums": 4, "writeQueueNums": 4, "perm": 6}
+                ],
+                "brokerDatas": [
+                    {"cluster": "c1", "brokerName": "broker-a", "brokerAddrs": {"0": "127.0.0.1:10911"}}
+                ]
+            });
+            response.set_body(route.to_string().into_bytes());
+            Ok(Some(response))
+        }
+    }
+
+    async fn unreachable_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_fail_over_namesrv() {
+        let server = RemotingServer::new("127.0.0.1:0");
+        server.register_processor(RequestCode::GetTopicRouteInfo, Arc::new(RouteProcessor));
+        let addr = server.start().await.unwrap().to_string();
+        let unreachable = unreachable_addr().await;
+
+        let mut client = MQClient::new(&format!("{};{}", unreachable, addr));
+        client.namesrv_index.store(0, Ordering::Relaxed);
+        client.start().await.unwrap();
+        assert_eq!(Some(addr.as_str()), client.namesrv_addr());
+
+        let route = client.query_route("test").await.unwrap();
+        assert_eq!(1, route.queue_datas().len());
+        assert!(matches!(
+            client.query_route("unknown").await,
+            Err(Error::TopicNotFound(..))
+        ));
+
+        // the selected NameServer goes away, requests fail over to the next one.
+        client.namesrv_index.store(0, Ordering::Relaxed);
+        assert!(client.query_route("test").await.is_ok());
+        assert_eq!(Some(addr.as_str()), client.namesrv_addr());
+
+        client.shutdown().await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_no_namesrv_reachable() {
+        let mut client = MQClient::new(&unreachable_addr().await);
+        assert!(client.start().await.is_err());
+        let mut client = MQClient::new(" ; ");
+        assert!(client.start().await.is_err());
+    }
 }
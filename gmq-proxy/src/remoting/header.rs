@@ -0,0 +1,674 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use gmq_remoting::common::{code::RequestCode, command::Command};
+
+use crate::common::Error;
+
+pub const PROPERTY_KEYS: &str = "KEYS";
+pub const PROPERTY_TAGS: &str = "TAGS";
+pub const PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX: &str = "UNIQ_KEY";
+pub const PROPERTY_TIMER_DELIVER_MS: &str = "TIMER_DELIVER_MS";
+pub const PROPERTY_SHARDING_KEY: &str = "__SHARDINGKEY";
+pub const PROPERTY_TRACE_CONTEXT: &str = "TRACE_CONTEXT";
+pub const PROPERTY_TRANSACTION_PREPARED: &str = "TRAN_MSG";
+pub const PROPERTY_DELAY_TIME_LEVEL: &str = "DELAY";
+pub const PROPERTY_RETRY_TOPIC: &str = "RETRY_TOPIC";
+pub const PROPERTY_POP_CK: &str = "POP_CK";
+pub const PROPERTY_PRODUCER_GROUP: &str = "PGROUP";
+pub const PROPERTY_WAIT_STORE_MSG_OK: &str = "WAIT";
+pub const PROPERTY_DLQ_ORIGIN_TOPIC: &str = "DLQ_ORIGIN_TOPIC";
+pub const PROPERTY_DLQ_ORIGIN_MESSAGE_ID: &str = "DLQ_ORIGIN_MESSAGE_ID";
+pub const KEY_SEPARATOR: &str = " ";
+pub const RETRY_GROUP_TOPIC_PREFIX: &str = "%RETRY%";
+pub const DLQ_GROUP_TOPIC_PREFIX: &str = "%DLQ%";
+/// Brokers hold the pull request until new messages arrive or the suspend timeout runs out.
+pub const PULL_FLAG_SUSPEND: i32 = 0x2;
+/// The pull request carries the subscription to filter messages with.
+pub const PULL_FLAG_SUBSCRIPTION: i32 = 0x4;
+
+const SYSTEM_PROPERTIES: [&str; 14] = [
+    PROPERTY_KEYS,
+    PROPERTY_TAGS,
+    PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX,
+    PROPERTY_TIMER_DELIVER_MS,
+    PROPERTY_SHARDING_KEY,
+    PROPERTY_TRACE_CONTEXT,
+    PROPERTY_TRANSACTION_PREPARED,
+    PROPERTY_DELAY_TIME_LEVEL,
+    PROPERTY_RETRY_TOPIC,
+    PROPERTY_POP_CK,
+    PROPERTY_PRODUCER_GROUP,
+    PROPERTY_WAIT_STORE_MSG_OK,
+    PROPERTY_DLQ_ORIGIN_TOPIC,
+    PROPERTY_DLQ_ORIGIN_MESSAGE_ID,
+];
+
+const NAME_VALUE_SEPARATOR: char = '\u{1}';
+const PROPERTY_SEPARATOR: char = '\u{2}';
+
+const DEFAULT_TOPIC: &str = "TBW102";
+const DEFAULT_TOPIC_QUEUE_NUMS: i32 = 4;
+
+/// Encodes message properties the way brokers store them: `name\u{1}value\u{2}` per property.
+pub fn properties_to_string(properties: &HashMap<String, String>) -> String {
+    let mut result = String::new();
+    for (name, value) in properties {
+        result.push_str(name);
+        result.push(NAME_VALUE_SEPARATOR);
+        result.push_str(value);
+        result.push(PROPERTY_SEPARATOR);
+    }
+    result
+}
+
+pub fn string_to_properties(properties: &str) -> HashMap<String, String> {
+    properties
+        .split(PROPERTY_SEPARATOR)
+        .filter_map(|property| property.split_once(NAME_VALUE_SEPARATOR))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Properties reserved by the system, which are not exposed to users as user properties.
+pub fn is_system_property(key: &str) -> bool {
+    SYSTEM_PROPERTIES.contains(&key)
+}
+
+/// Returns whether the string can be put into the properties without breaking their encoding.
+pub fn is_valid_property(s: &str) -> bool {
+    !s.contains(NAME_VALUE_SEPARATOR) && !s.contains(PROPERTY_SEPARATOR)
+}
+
+/**
+ * The header of SEND_MESSAGE_V2 requests, which is encoded with single letter field names
+ * to save bytes on the wire.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SendMessageRequestHeader {
+    pub producer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+    pub sys_flag: i32,
+    pub born_timestamp: i64,
+    pub flag: i32,
+    pub properties: HashMap<String, String>,
+    pub reconsume_times: i32,
+    pub max_reconsume_times: Option<i32>,
+    pub batch: bool,
+    pub broker_name: String,
+}
+
+impl SendMessageRequestHeader {
+    pub fn into_command(self, body: Vec<u8>) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("a".to_string(), self.producer_group);
+        headers.insert("b".to_string(), self.topic);
+        headers.insert("c".to_string(), DEFAULT_TOPIC.to_string());
+        headers.insert("d".to_string(), DEFAULT_TOPIC_QUEUE_NUMS.to_string());
+        headers.insert("e".to_string(), self.queue_id.to_string());
+        headers.insert("f".to_string(), self.sys_flag.to_string());
+        headers.insert("g".to_string(), self.born_timestamp.to_string());
+        headers.insert("h".to_string(), self.flag.to_string());
+        headers.insert("i".to_string(), properties_to_string(&self.properties));
+        headers.insert("j".to_string(), self.reconsume_times.to_string());
+        headers.insert("k".to_string(), false.to_string());
+        if let Some(max_reconsume_times) = self.max_reconsume_times {
+            headers.insert("l".to_string(), max_reconsume_times.to_string());
+        }
+        headers.insert("m".to_string(), self.batch.to_string());
+        headers.insert("n".to_string(), self.broker_name);
+        let mut cmd = Command::new_with_header(RequestCode::SendMessageV2, headers);
+        cmd.set_body(body);
+        cmd
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SendMessageResponseHeader {
+    pub msg_id: String,
+    pub queue_id: i32,
+    pub queue_offset: i64,
+    pub transaction_id: Option<String>,
+}
+
+impl SendMessageResponseHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        Ok(Self {
+            msg_id: required(cmd, "msgId")?.to_string(),
+            queue_id: parse(cmd, "queueId")?,
+            queue_offset: parse(cmd, "queueOffset")?,
+            transaction_id: cmd.get_property("transactionId").cloned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PopMessageRequestHeader {
+    pub consumer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+    pub max_msg_nums: i32,
+    pub invisible_time: i64,
+    pub poll_time: i64,
+    pub born_time: i64,
+    pub init_mode: i32,
+    pub exp_type: Option<String>,
+    pub exp: Option<String>,
+    pub order: bool,
+}
+
+impl PopMessageRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("consumerGroup".to_string(), self.consumer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        headers.insert("maxMsgNums".to_string(), self.max_msg_nums.to_string());
+        headers.insert("invisibleTime".to_string(), self.invisible_time.to_string());
+        headers.insert("pollTime".to_string(), self.poll_time.to_string());
+        headers.insert("bornTime".to_string(), self.born_time.to_string());
+        headers.insert("initMode".to_string(), self.init_mode.to_string());
+        if let Some(exp_type) = self.exp_type {
+            headers.insert("expType".to_string(), exp_type);
+        }
+        if let Some(exp) = self.exp {
+            headers.insert("exp".to_string(), exp);
+        }
+        headers.insert("order".to_string(), self.order.to_string());
+        Command::new_with_header(RequestCode::PopMessage, headers)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PopMessageResponseHeader {
+    pub pop_time: i64,
+    pub invisible_time: i64,
+    pub revive_qid: i32,
+    pub rest_num: i64,
+    pub start_offset_info: Option<String>,
+    pub msg_offset_info: Option<String>,
+}
+
+impl PopMessageResponseHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        Ok(Self {
+            pop_time: parse(cmd, "popTime")?,
+            invisible_time: parse(cmd, "invisibleTime")?,
+            revive_qid: parse(cmd, "reviveQid")?,
+            rest_num: parse(cmd, "restNum")?,
+            start_offset_info: cmd.get_property("startOffsetInfo").cloned(),
+            msg_offset_info: cmd.get_property("msgOffsetInfo").cloned(),
+        })
+    }
+
+    /// Returns the offset the pop starts from in the queue, which identifies the checkpoint
+    /// of the popped messages. `startOffsetInfo` is formatted as
+    /// `retryFlag queueId startOffset;...`.
+    pub fn start_offset(&self, topic: &str, queue_id: i32) -> Option<i64> {
+        let retry_flag = retry_flag(topic);
+        self.start_offset_info
+            .as_deref()?
+            .split(';')
+            .find_map(|info| {
+                let mut fields = info.split(KEY_SEPARATOR);
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(flag), Some(id), Some(offset))
+                        if flag == retry_flag && id.parse() == Ok(queue_id) =>
+                    {
+                        offset.parse().ok()
+                    }
+                    _ => None,
+                }
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PullMessageRequestHeader {
+    pub consumer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+    pub queue_offset: i64,
+    pub max_msg_nums: i32,
+    pub sys_flag: i32,
+    pub commit_offset: i64,
+    pub suspend_timeout_millis: i64,
+    pub subscription: String,
+    pub sub_version: i64,
+    pub expression_type: String,
+}
+
+impl PullMessageRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("consumerGroup".to_string(), self.consumer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        headers.insert("queueOffset".to_string(), self.queue_offset.to_string());
+        headers.insert("maxMsgNums".to_string(), self.max_msg_nums.to_string());
+        headers.insert("sysFlag".to_string(), self.sys_flag.to_string());
+        headers.insert("commitOffset".to_string(), self.commit_offset.to_string());
+        headers.insert(
+            "suspendTimeoutMillis".to_string(),
+            self.suspend_timeout_millis.to_string(),
+        );
+        headers.insert("subscription".to_string(), self.subscription);
+        headers.insert("subVersion".to_string(), self.sub_version.to_string());
+        headers.insert("expressionType".to_string(), self.expression_type);
+        Command::new_with_header(RequestCode::PullMessage, headers)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PullMessageResponseHeader {
+    pub next_begin_offset: i64,
+    pub min_offset: i64,
+    pub max_offset: i64,
+}
+
+impl PullMessageResponseHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        Ok(Self {
+            next_begin_offset: parse(cmd, "nextBeginOffset")?,
+            min_offset: parse(cmd, "minOffset")?,
+            max_offset: parse(cmd, "maxOffset")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryConsumerOffsetRequestHeader {
+    pub consumer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+}
+
+impl QueryConsumerOffsetRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("consumerGroup".to_string(), self.consumer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        Command::new_with_header(RequestCode::QueryConsumerOffset, headers)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateConsumerOffsetRequestHeader {
+    pub consumer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+    pub commit_offset: i64,
+}
+
+impl UpdateConsumerOffsetRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("consumerGroup".to_string(), self.consumer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        headers.insert("commitOffset".to_string(), self.commit_offset.to_string());
+        Command::new_with_header(RequestCode::UpdateConsumerOffset, headers)
+    }
+}
+
+/**
+ * The header of the requests looking up an offset of a queue: GET_MIN_OFFSET, GET_MAX_OFFSET,
+ * and SEARCH_OFFSET_BY_TIMESTAMP with the timestamp.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct QueueOffsetRequestHeader {
+    pub topic: String,
+    pub queue_id: i32,
+    /// The store time to search the offset of, in milliseconds.
+    pub timestamp: Option<i64>,
+}
+
+impl QueueOffsetRequestHeader {
+    pub fn into_command(self, code: RequestCode) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        if let Some(timestamp) = self.timestamp {
+            headers.insert("timestamp".to_string(), timestamp.to_string());
+        }
+        Command::new_with_header(code, headers)
+    }
+}
+
+/// Decodes the offset in the responses of the offset requests.
+pub fn decode_offset(cmd: &Command) -> Result<i64, Error> {
+    parse(cmd, "offset")
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AckMessageRequestHeader {
+    pub consumer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+    pub extra_info: String,
+    pub offset: i64,
+}
+
+impl AckMessageRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("consumerGroup".to_string(), self.consumer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        headers.insert("extraInfo".to_string(), self.extra_info);
+        headers.insert("offset".to_string(), self.offset.to_string());
+        Command::new_with_header(RequestCode::AckMessage, headers)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChangeInvisibleTimeRequestHeader {
+    pub consumer_group: String,
+    pub topic: String,
+    pub queue_id: i32,
+    pub extra_info: String,
+    pub offset: i64,
+    pub invisible_time: i64,
+}
+
+impl ChangeInvisibleTimeRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("consumerGroup".to_string(), self.consumer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert("queueId".to_string(), self.queue_id.to_string());
+        headers.insert("extraInfo".to_string(), self.extra_info);
+        headers.insert("offset".to_string(), self.offset.to_string());
+        headers.insert("invisibleTime".to_string(), self.invisible_time.to_string());
+        Command::new_with_header(RequestCode::ChangeMessageInvisibleTime, headers)
+    }
+}
+
+/**
+ * The header of CONSUMER_SEND_MSG_BACK requests, which put a consumed message back to the retry
+ * topic of the group, or to its dead letter queue once it is reconsumed `max_reconsume_times`.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerSendMsgBackRequestHeader {
+    /// The commit log offset of the message.
+    pub offset: i64,
+    pub group: String,
+    pub delay_level: i32,
+    pub origin_msg_id: String,
+    pub origin_topic: String,
+    pub max_reconsume_times: i32,
+}
+
+impl ConsumerSendMsgBackRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("offset".to_string(), self.offset.to_string());
+        headers.insert("group".to_string(), self.group);
+        headers.insert("delayLevel".to_string(), self.delay_level.to_string());
+        headers.insert("originMsgId".to_string(), self.origin_msg_id);
+        headers.insert("originTopic".to_string(), self.origin_topic);
+        headers.insert("unitMode".to_string(), false.to_string());
+        headers.insert(
+            "maxReconsumeTimes".to_string(),
+            self.max_reconsume_times.to_string(),
+        );
+        Command::new_with_header(RequestCode::ConsumerSendMsgBack, headers)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeInvisibleTimeResponseHeader {
+    pub pop_time: i64,
+    pub invisible_time: i64,
+    pub revive_qid: i32,
+}
+
+impl ChangeInvisibleTimeResponseHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        Ok(Self {
+            pop_time: parse(cmd, "popTime")?,
+            invisible_time: parse(cmd, "invisibleTime")?,
+            revive_qid: parse(cmd, "reviveQid")?,
+        })
+    }
+}
+
+/**
+ * The header of GET_CONSUMER_RUNNING_INFO requests, which ask a consumer about its state and
+ * optionally its thread stack trace.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GetConsumerRunningInfoRequestHeader {
+    pub consumer_group: String,
+    pub client_id: String,
+    pub jstack_enable: bool,
+}
+
+impl GetConsumerRunningInfoRequestHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        Ok(Self {
+            consumer_group: required(cmd, "consumerGroup")?.to_string(),
+            client_id: required(cmd, "clientId")?.to_string(),
+            jstack_enable: cmd
+                .get_property("jstackEnable")
+                .is_some_and(|value| value == "true"),
+        })
+    }
+}
+
+/**
+ * The header of CONSUME_MESSAGE_DIRECTLY requests, which ask a consumer to consume the message
+ * in the body and report the result.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsumeMessageDirectlyResultRequestHeader {
+    pub consumer_group: String,
+    pub client_id: String,
+    pub msg_id: Option<String>,
+    pub broker_name: Option<String>,
+}
+
+impl ConsumeMessageDirectlyResultRequestHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        let optional = |name: &str| {
+            cmd.get_property(name)
+                .filter(|value| !value.is_empty())
+                .cloned()
+        };
+        Ok(Self {
+            consumer_group: required(cmd, "consumerGroup")?.to_string(),
+            client_id: required(cmd, "clientId")?.to_string(),
+            msg_id: optional("msgId"),
+            broker_name: optional("brokerName"),
+        })
+    }
+}
+
+/**
+ * The header of END_TRANSACTION requests, which commit or roll back a half message.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct EndTransactionRequestHeader {
+    pub producer_group: String,
+    pub topic: String,
+    pub tran_state_table_offset: i64,
+    pub commit_log_offset: i64,
+    /// `TRANSACTION_COMMIT_TYPE`, `TRANSACTION_ROLLBACK_TYPE` or `TRANSACTION_NOT_TYPE`.
+    pub commit_or_rollback: i32,
+    pub from_transaction_check: bool,
+    pub msg_id: String,
+    pub transaction_id: String,
+    pub broker_name: String,
+}
+
+impl EndTransactionRequestHeader {
+    pub fn into_command(self) -> Command {
+        let mut headers = HashMap::new();
+        headers.insert("producerGroup".to_string(), self.producer_group);
+        headers.insert("topic".to_string(), self.topic);
+        headers.insert(
+            "tranStateTableOffset".to_string(),
+            self.tran_state_table_offset.to_string(),
+        );
+        headers.insert(
+            "commitLogOffset".to_string(),
+            self.commit_log_offset.to_string(),
+        );
+        headers.insert(
+            "commitOrRollback".to_string(),
+            self.commit_or_rollback.to_string(),
+        );
+        headers.insert(
+            "fromTransactionCheck".to_string(),
+            self.from_transaction_check.to_string(),
+        );
+        headers.insert("msgId".to_string(), self.msg_id);
+        headers.insert("transactionId".to_string(), self.transaction_id);
+        headers.insert("brokerName".to_string(), self.broker_name);
+        Command::new_with_header(RequestCode::EndTransaction, headers)
+    }
+}
+
+/**
+ * The header of CHECK_TRANSACTION_STATE requests, which brokers send to producers to resolve
+ * the half messages left unresolved.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckTransactionStateRequestHeader {
+    pub topic: Option<String>,
+    pub tran_state_table_offset: i64,
+    pub commit_log_offset: i64,
+    pub msg_id: Option<String>,
+    pub transaction_id: Option<String>,
+    pub offset_msg_id: Option<String>,
+    pub broker_name: Option<String>,
+}
+
+impl CheckTransactionStateRequestHeader {
+    pub fn decode(cmd: &Command) -> Result<Self, Error> {
+        let optional = |name: &str| {
+            cmd.get_property(name)
+                .filter(|value| !value.is_empty())
+                .cloned()
+        };
+        Ok(Self {
+            topic: optional("topic"),
+            tran_state_table_offset: parse(cmd, "tranStateTableOffset")?,
+            commit_log_offset: parse(cmd, "commitLogOffset")?,
+            msg_id: optional("msgId"),
+            transaction_id: optional("transactionId"),
+            offset_msg_id: optional("offsetMsgId"),
+            broker_name: optional("brokerName"),
+        })
+    }
+}
+
+/// Brokers mark the offsets of normal topics with `0`, pop retry topics named
+/// `%RETRY%group_topic` with `1`, and the ones named `%RETRY%group+topic` with `2`.
+pub fn retry_flag(topic: &str) -> &'static str {
+    if !topic.starts_with(RETRY_GROUP_TOPIC_PREFIX) {
+        "0"
+    } else if topic.contains('+') {
+        "2"
+    } else {
+        "1"
+    }
+}
+
+fn required<'a>(cmd: &'a Command, name: &str) -> Result<&'a str, Error> {
+    cmd.get_property(name)
+        .map(|value| value.as_str())
+        .ok_or_else(|| Error::InternalError(anyhow!("field {} is missing in response", name)))
+}
+
+fn parse<T: std::str::FromStr>(cmd: &Command, name: &str) -> Result<T, Error> {
+    required(cmd, name)?
+        .parse()
+        .map_err(|_| Error::InternalError(anyhow!("field {} is malformed in response", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use gmq_remoting::common::code::ResponseCode;
+
+    use super::*;
+
+    #[test]
+    fn test_properties() {
+        let mut properties = HashMap::new();
+        properties.insert(PROPERTY_TAGS.to_string(), "tag-a".to_string());
+        properties.insert(PROPERTY_KEYS.to_string(), "k1 k2".to_string());
+        let encoded = properties_to_string(&properties);
+        assert_eq!(properties, string_to_properties(&encoded));
+        assert!(string_to_properties("").is_empty());
+        assert!(!is_valid_property("a\u{1}b"));
+    }
+
+    #[test]
+    fn test_send_message_header() {
+        let header = SendMessageRequestHeader {
+            producer_group: "group".to_string(),
+            topic: "test".to_string(),
+            queue_id: 3,
+            broker_name: "broker-a".to_string(),
+            ..Default::default()
+        };
+        let cmd = header.into_command(vec![1, 2, 3]);
+        assert_eq!(i32::from(RequestCode::SendMessageV2), cmd.code());
+        assert_eq!("test", cmd.get_property("b").unwrap());
+        assert_eq!("3", cmd.get_property("e").unwrap());
+        assert!(cmd.get_property("l").is_none());
+        assert_eq!(Some(&[1, 2, 3][..]), cmd.body());
+
+        let mut response = Command::new(ResponseCode::Success);
+        response.add_property("msgId", "id");
+        response.add_property("queueId", "3");
+        response.add_property("queueOffset", "100");
+        let header = SendMessageResponseHeader::decode(&response).unwrap();
+        assert_eq!(100, header.queue_offset);
+        assert_eq!(None, header.transaction_id);
+
+        let response = Command::new(ResponseCode::Success);
+        assert!(SendMessageResponseHeader::decode(&response).is_err());
+    }
+
+    #[test]
+    fn test_pop_message_header() {
+        let mut response = Command::new(ResponseCode::Success);
+        response.add_property("popTime", "1000");
+        response.add_property("invisibleTime", "30000");
+        response.add_property("reviveQid", "2");
+        response.add_property("restNum", "0");
+        response.add_property("startOffsetInfo", "0 1 100;1 0 5");
+        let header = PopMessageResponseHeader::decode(&response).unwrap();
+        assert_eq!(Some(100), header.start_offset("test", 1));
+        assert_eq!(Some(5), header.start_offset("%RETRY%group", 0));
+        assert_eq!(None, header.start_offset("test", 0));
+    }
+
+    #[test]
+    fn test_offset_headers() {
+        let header = QueueOffsetRequestHeader {
+            topic: "test".to_string(),
+            queue_id: 1,
+            timestamp: Some(1000),
+        };
+        let cmd = header.into_command(RequestCode::SearchOffsetByTimestamp);
+        assert_eq!(i32::from(RequestCode::SearchOffsetByTimestamp), cmd.code());
+        assert_eq!("1000", cmd.get_property("timestamp").unwrap());
+        let header = QueueOffsetRequestHeader {
+            topic: "test".to_string(),
+            queue_id: 1,
+            timestamp: None,
+        };
+        let cmd = header.into_command(RequestCode::GetMinOffset);
+        assert!(cmd.get_property("timestamp").is_none());
+
+        let mut response = Command::new(ResponseCode::Success);
+        response.add_property("offset", "42");
+        assert_eq!(42, decode_offset(&response).unwrap());
+        assert!(decode_offset(&Command::new(ResponseCode::Success)).is_err());
+    }
+}
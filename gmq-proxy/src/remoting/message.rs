@@ -0,0 +1,279 @@
+use std::{collections::HashMap, io::Read};
+
+use anyhow::anyhow;
+use bytes::Buf;
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use super::header::string_to_properties;
+use crate::common::Error;
+
+const MESSAGE_MAGIC_CODE_V1: i32 = -626843481;
+const MESSAGE_MAGIC_CODE_V2: i32 = -626843477;
+
+pub const COMPRESSED_FLAG: i32 = 0x1;
+/// Bodies sent by gRPC clients in gzip are compressed with the gzip header instead of zlib.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+pub const BORNHOST_V6_FLAG: i32 = 0x1 << 4;
+pub const STOREHOSTADDRESS_V6_FLAG: i32 = 0x1 << 5;
+pub const TRANSACTION_NOT_TYPE: i32 = 0;
+pub const TRANSACTION_PREPARED_TYPE: i32 = 0x1 << 2;
+pub const TRANSACTION_COMMIT_TYPE: i32 = 0x2 << 2;
+pub const TRANSACTION_ROLLBACK_TYPE: i32 = 0x3 << 2;
+
+/**
+ * A message stored by brokers, as returned by POP_MESSAGE and PULL_MESSAGE.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageExt {
+    pub topic: String,
+    pub queue_id: i32,
+    pub flag: i32,
+    pub queue_offset: i64,
+    pub commit_log_offset: i64,
+    pub sys_flag: i32,
+    pub born_timestamp: i64,
+    pub born_host: String,
+    pub store_timestamp: i64,
+    pub store_host: String,
+    pub reconsume_times: i32,
+    pub prepared_transaction_offset: i64,
+    pub body: Vec<u8>,
+    pub properties: HashMap<String, String>,
+    pub offset_msg_id: String,
+}
+
+/// Decodes all the messages packed in the body of a response, compressed bodies are inflated.
+pub fn decode_messages(mut data: &[u8]) -> Result<Vec<MessageExt>, Error> {
+    let mut messages = Vec::new();
+    while data.has_remaining() {
+        ensure(data, 4)?;
+        let store_size = (&data[..4]).get_i32();
+        if store_size < 4 || store_size as usize > data.len() {
+            return Err(malformed("store size"));
+        }
+        let (message, rest) = data.split_at(store_size as usize);
+        messages.push(decode_message(message)?);
+        data = rest;
+    }
+    Ok(messages)
+}
+
+fn decode_message(mut data: &[u8]) -> Result<MessageExt, Error> {
+    ensure(data, 4 * 6 + 8 * 3)?;
+    data.advance(4);
+    let magic_code = data.get_i32();
+    if magic_code != MESSAGE_MAGIC_CODE_V1 && magic_code != MESSAGE_MAGIC_CODE_V2 {
+        return Err(malformed("magic code"));
+    }
+    let _body_crc = data.get_i32();
+    let queue_id = data.get_i32();
+    let flag = data.get_i32();
+    let queue_offset = data.get_i64();
+    let commit_log_offset = data.get_i64();
+    let sys_flag = data.get_i32();
+    let born_timestamp = data.get_i64();
+    let born_host = decode_host(&mut data, sys_flag & BORNHOST_V6_FLAG != 0)?;
+    ensure(data, 8)?;
+    let store_timestamp = data.get_i64();
+    let store_host_start = data;
+    let store_host = decode_host(&mut data, sys_flag & STOREHOSTADDRESS_V6_FLAG != 0)?;
+    let store_host_bytes = &store_host_start[..store_host_start.len() - data.len()];
+    ensure(data, 4 + 8 + 4)?;
+    let reconsume_times = data.get_i32();
+    let prepared_transaction_offset = data.get_i64();
+
+    let body_len = data.get_i32().max(0) as usize;
+    ensure(data, body_len)?;
+    let mut body = data[..body_len].to_vec();
+    data.advance(body_len);
+    if sys_flag & COMPRESSED_FLAG != 0 {
+        let mut inflated = Vec::new();
+        let result = if body.starts_with(&GZIP_MAGIC) {
+            GzDecoder::new(body.as_slice()).read_to_end(&mut inflated)
+        } else {
+            ZlibDecoder::new(body.as_slice()).read_to_end(&mut inflated)
+        };
+        result.map_err(|e| Error::InternalError(e.into()))?;
+        body = inflated;
+    }
+
+    let topic_len = if magic_code == MESSAGE_MAGIC_CODE_V2 {
+        ensure(data, 2)?;
+        data.get_i16() as usize
+    } else {
+        ensure(data, 1)?;
+        data.get_u8() as usize
+    };
+    ensure(data, topic_len)?;
+    let topic = String::from_utf8_lossy(&data[..topic_len]).to_string();
+    data.advance(topic_len);
+
+    ensure(data, 2)?;
+    let properties_len = data.get_i16().max(0) as usize;
+    ensure(data, properties_len)?;
+    let properties = string_to_properties(&String::from_utf8_lossy(&data[..properties_len]));
+
+    let mut offset_msg_id = String::new();
+    for b in store_host_bytes
+        .iter()
+        .chain(commit_log_offset.to_be_bytes().iter())
+    {
+        offset_msg_id.push_str(&format!("{:02X}", b));
+    }
+
+    Ok(MessageExt {
+        topic,
+        queue_id,
+        flag,
+        queue_offset,
+        commit_log_offset,
+        sys_flag,
+        born_timestamp,
+        born_host,
+        store_timestamp,
+        store_host,
+        reconsume_times,
+        prepared_transaction_offset,
+        body,
+        properties,
+        offset_msg_id,
+    })
+}
+
+/// Returns the commit log offset encoded in the last 8 bytes of an offset message id.
+pub fn commit_log_offset_of(offset_msg_id: &str) -> Option<i64> {
+    if offset_msg_id.len() < 16 || !offset_msg_id.is_ascii() {
+        return None;
+    }
+    i64::from_str_radix(&offset_msg_id[offset_msg_id.len() - 16..], 16).ok()
+}
+
+fn decode_host(data: &mut &[u8], v6: bool) -> Result<String, Error> {
+    let ip_len = if v6 { 16 } else { 4 };
+    ensure(data, ip_len + 4)?;
+    let ip = if v6 {
+        let mut octets = [0u8; 16];
+        data.copy_to_slice(&mut octets);
+        std::net::IpAddr::from(octets)
+    } else {
+        let mut octets = [0u8; 4];
+        data.copy_to_slice(&mut octets);
+        std::net::IpAddr::from(octets)
+    };
+    let port = data.get_i32();
+    Ok(std::net::SocketAddr::new(ip, port as u16).to_string())
+}
+
+fn ensure(data: &[u8], len: usize) -> Result<(), Error> {
+    if data.len() < len {
+        return Err(malformed("length"));
+    }
+    Ok(())
+}
+
+fn malformed(field: &str) -> Error {
+    Error::InternalError(anyhow!("malformed message, unexpected {}", field))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use bytes::BufMut;
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+    use crate::remoting::header::properties_to_string;
+
+    /// Encodes the message in the format brokers store it.
+    pub(crate) fn encode_message(message: &MessageExt) -> Vec<u8> {
+        let mut body = message.body.clone();
+        // bodies sent in gzip by gRPC clients are stored as they are.
+        if message.sys_flag & COMPRESSED_FLAG != 0 && !body.starts_with(&GZIP_MAGIC) {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).unwrap();
+            body = encoder.finish().unwrap();
+        }
+        let properties = properties_to_string(&message.properties);
+
+        let mut buf = Vec::new();
+        buf.put_i32(MESSAGE_MAGIC_CODE_V1);
+        buf.put_i32(0);
+        buf.put_i32(message.queue_id);
+        buf.put_i32(message.flag);
+        buf.put_i64(message.queue_offset);
+        buf.put_i64(message.commit_log_offset);
+        buf.put_i32(message.sys_flag);
+        buf.put_i64(message.born_timestamp);
+        buf.put_slice(&[127, 0, 0, 1]);
+        buf.put_i32(1234);
+        buf.put_i64(message.store_timestamp);
+        buf.put_slice(&[127, 0, 0, 1]);
+        buf.put_i32(10911);
+        buf.put_i32(message.reconsume_times);
+        buf.put_i64(message.prepared_transaction_offset);
+        buf.put_i32(body.len() as i32);
+        buf.put_slice(&body);
+        buf.put_u8(message.topic.len() as u8);
+        buf.put_slice(message.topic.as_bytes());
+        buf.put_i16(properties.len() as i16);
+        buf.put_slice(properties.as_bytes());
+
+        let mut data = Vec::new();
+        data.put_i32(buf.len() as i32 + 4);
+        data.extend(buf);
+        data
+    }
+
+    #[test]
+    fn test_decode_messages() {
+        let mut message = MessageExt {
+            topic: "test".to_string(),
+            queue_id: 1,
+            queue_offset: 10,
+            commit_log_offset: 1024,
+            born_timestamp: 1000,
+            store_timestamp: 2000,
+            reconsume_times: 2,
+            body: b"hello".to_vec(),
+            ..Default::default()
+        };
+        message
+            .properties
+            .insert("UNIQ_KEY".to_string(), "id".to_string());
+        let mut compressed = message.clone();
+        compressed.sys_flag = COMPRESSED_FLAG;
+        compressed.queue_offset = 11;
+
+        let mut gzip = compressed.clone();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&message.body).unwrap();
+        gzip.body = encoder.finish().unwrap();
+
+        let mut data = encode_message(&message);
+        data.extend(encode_message(&compressed));
+        let messages = decode_messages(&data).unwrap();
+        assert_eq!(2, messages.len());
+        assert_eq!(
+            message.body,
+            decode_messages(&encode_message(&gzip)).unwrap()[0].body
+        );
+        assert_eq!("test", messages[0].topic);
+        assert_eq!("127.0.0.1:1234", messages[0].born_host);
+        assert_eq!("127.0.0.1:10911", messages[0].store_host);
+        assert_eq!(
+            "7F00000100002A9F0000000000000400",
+            messages[0].offset_msg_id
+        );
+        assert_eq!("id", messages[0].properties["UNIQ_KEY"]);
+        assert_eq!(b"hello".to_vec(), messages[1].body);
+        assert_eq!(11, messages[1].queue_offset);
+
+        assert!(decode_messages(&data[..data.len() - 1]).is_err());
+        assert_eq!(Some(1024), commit_log_offset_of(&messages[0].offset_msg_id));
+        assert_eq!(None, commit_log_offset_of("400"));
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use gmq_remoting::{
+    common::{
+        code::{RequestCode, ResponseCode},
+        command::Command as RemotingCommand,
+    },
+    processor::RequestProcessor,
+    server::RemotingServer,
+    util,
+};
+use serde_json::{json, Value};
+
+use super::{
+    consumer::to_pb_message,
+    session::{ClientSession, ClientSettingManager},
+};
+use crate::{
+    common::Error,
+    pb::{self, telemetry_command::Command, Code},
+    remoting::{
+        header::{ConsumeMessageDirectlyResultRequestHeader, GetConsumerRunningInfoRequestHeader},
+        message::decode_messages,
+    },
+};
+
+/// How long to wait for the client to reply a command pushed by operators.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * AdminService lets operators inspect the connected clients, by pushing commands over their
+ * telemetry streams. It serves the GET_CONSUMER_RUNNING_INFO and CONSUME_MESSAGE_DIRECTLY
+ * requests of admin tools on a remoting server.
+ */
+#[derive(Debug, Clone)]
+pub struct AdminService {
+    setting_manager: Arc<ClientSettingManager>,
+}
+
+impl AdminService {
+    pub fn new(setting_manager: Arc<ClientSettingManager>) -> Self {
+        Self { setting_manager }
+    }
+
+    /// Serves the admin requests on the remoting server.
+    pub fn register_processors(&self, server: &RemotingServer) {
+        let processor = Arc::new(self.clone());
+        server.register_processor(RequestCode::GetConsumerRunningInfo, processor.clone());
+        server.register_processor(RequestCode::ConsumeMessageDirectly, processor);
+    }
+
+    pub fn list_clients(&self) -> Vec<ClientSession> {
+        self.setting_manager.sessions()
+    }
+
+    /// Asks the client to consume the message without acking it, returns the status the client
+    /// reports.
+    pub async fn verify_message(
+        &self,
+        client_id: &str,
+        message: pb::Message,
+    ) -> Result<pb::Status, Error> {
+        let nonce = nonce();
+        let command = Command::VerifyMessageCommand(pb::VerifyMessageCommand {
+            nonce: nonce.clone(),
+            message: Some(message),
+        });
+        let reply = self
+            .setting_manager
+            .invoke_command(client_id, &nonce, command, COMMAND_TIMEOUT)
+            .await?;
+        match reply.command {
+            Some(Command::VerifyMessageResult(_)) => reply
+                .status
+                .ok_or_else(|| Error::InternalError(anyhow!("no status in the reply of client"))),
+            _ => Err(unexpected_reply(client_id)),
+        }
+    }
+
+    /// Returns the thread stack trace of the client.
+    pub async fn print_thread_stack_trace(&self, client_id: &str) -> Result<String, Error> {
+        let nonce = nonce();
+        let command = Command::PrintThreadStackTraceCommand(pb::PrintThreadStackTraceCommand {
+            nonce: nonce.clone(),
+        });
+        let reply = self
+            .setting_manager
+            .invoke_command(client_id, &nonce, command, COMMAND_TIMEOUT)
+            .await?;
+        match reply.command {
+            Some(Command::ThreadStackTrace(trace)) => {
+                Ok(trace.thread_stack_trace.unwrap_or_default())
+            }
+            _ => Err(unexpected_reply(client_id)),
+        }
+    }
+}
+
+#[async_trait]
+impl RequestProcessor for AdminService {
+    async fn process(
+        &self,
+        request: &RemotingCommand,
+    ) -> Result<Option<RemotingCommand>, util::Error> {
+        let result = match RequestCode::try_from(request.code()) {
+            Ok(RequestCode::GetConsumerRunningInfo) => self.consumer_running_info(request).await,
+            Ok(RequestCode::ConsumeMessageDirectly) => self.consume_message_directly(request).await,
+            _ => Err(Error::InternalError(anyhow!(
+                "request code {} not supported",
+                request.code()
+            ))),
+        };
+        let response = match result {
+            Ok(body) => {
+                let mut response = RemotingCommand::new_response(request, ResponseCode::Success);
+                response.set_body(body.to_string().into_bytes());
+                response
+            }
+            Err(e) => {
+                let mut response =
+                    RemotingCommand::new_response(request, ResponseCode::SystemError);
+                response.set_remark(e.to_string());
+                response
+            }
+        };
+        Ok(Some(response))
+    }
+}
+
+impl AdminService {
+    /// Answers with the running info of the client, carrying its thread stack trace if asked.
+    async fn consumer_running_info(&self, request: &RemotingCommand) -> Result<Value, Error> {
+        let header = GetConsumerRunningInfoRequestHeader::decode(request)?;
+        if !self
+            .setting_manager
+            .get_session(&header.client_id)
+            .is_some_and(|session| session.is_connected())
+        {
+            return Err(Error::ClientNotConnected(header.client_id));
+        }
+        let mut info = json!({
+            "properties": {},
+            "subscriptionSet": [],
+            "mqTable": {},
+            "statusTable": {},
+        });
+        if header.jstack_enable {
+            info["jstack"] = self
+                .print_thread_stack_trace(&header.client_id)
+                .await?
+                .into();
+        }
+        Ok(info)
+    }
+
+    /// Has the client verify the message in the body, answers with the consume result.
+    async fn consume_message_directly(&self, request: &RemotingCommand) -> Result<Value, Error> {
+        let header = ConsumeMessageDirectlyResultRequestHeader::decode(request)?;
+        let message = decode_messages(request.body().unwrap_or_default())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::InternalError(anyhow!("no message to consume")))?;
+        let topic = pb::Resource {
+            resource_namespace: "".to_string(),
+            name: message.topic.clone(),
+        };
+        let start = Instant::now();
+        let status = self
+            .verify_message(
+                &header.client_id,
+                to_pb_message(message, &topic, None, None),
+            )
+            .await?;
+        let consume_result = if status.code == Code::Ok as i32 {
+            "CR_SUCCESS"
+        } else {
+            "CR_THROW_EXCEPTION"
+        };
+        Ok(json!({
+            "order": false,
+            "autoCommit": true,
+            "consumeResult": consume_result,
+            "remark": status.message,
+            "spentTimeMills": start.elapsed().as_millis() as u64,
+        }))
+    }
+}
+
+fn nonce() -> String {
+    format!("{:016X}", rand::random::<u64>())
+}
+
+fn unexpected_reply(client_id: &str) -> Error {
+    Error::InternalError(anyhow!("unexpected reply from client {}", client_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        common::ok_status,
+        remoting::message::{tests::encode_message, MessageExt},
+        service::{mock_broker::setting_manager, session::TELEMETRY_CHANNEL_CAPACITY},
+    };
+
+    /// Registers the telemetry channel of a client replying every pushed command.
+    fn admin_service() -> AdminService {
+        let setting_manager = Arc::new(setting_manager());
+        let (sender, mut receiver) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+        setting_manager.register_channel("client", sender);
+
+        let client = Arc::clone(&setting_manager);
+        tokio::spawn(async move {
+            while let Some(pushed) = receiver.recv().await {
+                let command = match pushed.command {
+                    Some(Command::VerifyMessageCommand(command)) => {
+                        Command::VerifyMessageResult(pb::VerifyMessageResult {
+                            nonce: command.nonce,
+                        })
+                    }
+                    Some(Command::PrintThreadStackTraceCommand(command)) => {
+                        Command::ThreadStackTrace(pb::ThreadStackTrace {
+                            nonce: command.nonce,
+                            thread_stack_trace: Some("main".to_string()),
+                        })
+                    }
+                    _ => continue,
+                };
+                let reply = pb::TelemetryCommand {
+                    status: Some(ok_status()),
+                    command: Some(command),
+                };
+                client.on_telemetry("client", reply);
+            }
+        });
+        AdminService::new(setting_manager)
+    }
+
+    fn request(code: RequestCode, client_id: &str) -> RemotingCommand {
+        let mut request = RemotingCommand::new(code);
+        request.add_property("consumerGroup", "group");
+        request.add_property("clientId", client_id);
+        request
+    }
+
+    fn body(response: &RemotingCommand) -> Value {
+        assert_eq!(i32::from(ResponseCode::Success), response.code());
+        serde_json::from_slice(response.body().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_push_commands() {
+        let admin_service = admin_service();
+        let status = admin_service
+            .verify_message("client", pb::Message::default())
+            .await
+            .unwrap();
+        assert_eq!(Code::Ok as i32, status.code);
+        assert_eq!(
+            "main",
+            admin_service
+                .print_thread_stack_trace("client")
+                .await
+                .unwrap()
+        );
+        assert!(matches!(
+            admin_service.print_thread_stack_trace("unknown").await,
+            Err(Error::ClientNotConnected(_))
+        ));
+        assert_eq!(1, admin_service.list_clients().len());
+    }
+
+    #[tokio::test]
+    async fn test_process_admin_requests() {
+        let admin_service = admin_service();
+
+        let mut running_info = request(RequestCode::GetConsumerRunningInfo, "client");
+        running_info.add_property("jstackEnable", "true");
+        let response = admin_service.process(&running_info).await.unwrap().unwrap();
+        assert_eq!(running_info.opaque(), response.opaque());
+        assert_eq!("main", body(&response)["jstack"]);
+
+        let mut consume = request(RequestCode::ConsumeMessageDirectly, "client");
+        consume.set_body(encode_message(&MessageExt {
+            topic: "test".to_string(),
+            body: b"hello".to_vec(),
+            ..Default::default()
+        }));
+        let response = admin_service.process(&consume).await.unwrap().unwrap();
+        assert_eq!("CR_SUCCESS", body(&response)["consumeResult"]);
+
+        let running_info = request(RequestCode::GetConsumerRunningInfo, "unknown");
+        let response = admin_service.process(&running_info).await.unwrap().unwrap();
+        assert_eq!(i32::from(ResponseCode::SystemError), response.code());
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+};
+
+use parking_lot::RwLock;
+
+use super::{
+    group_config::{AllocateStrategy, GroupConfigManager},
+    message_queue::MessageQueue,
+    route::RouteService,
+    session::ClientSettingManager,
+};
+use crate::{
+    common::{ok_status, status},
+    pb::{self, Code, Status},
+};
+
+/// Decides the queues a consumer of a group pops from.
+pub trait QueueAllocator: Send + Sync {
+    /// Returns the queues assigned to `client_id` among the `consumers` of the group. Both the
+    /// consumers and the queues are sorted, so that every consumer computes the same result.
+    fn allocate(
+        &self,
+        client_id: &str,
+        consumers: &[String],
+        queues: &[MessageQueue],
+    ) -> Vec<MessageQueue>;
+}
+
+/// Assigns all the queues to every consumer, brokers balance the pops among the consumers.
+pub struct PopAllocator;
+
+impl QueueAllocator for PopAllocator {
+    fn allocate(
+        &self,
+        _client_id: &str,
+        _consumers: &[String],
+        queues: &[MessageQueue],
+    ) -> Vec<MessageQueue> {
+        queues.to_vec()
+    }
+}
+
+/// Divides the queues into contiguous ranges evenly, the first consumers take one more queue
+/// if they can not be divided evenly.
+pub struct AverageAllocator;
+
+impl QueueAllocator for AverageAllocator {
+    fn allocate(
+        &self,
+        client_id: &str,
+        consumers: &[String],
+        queues: &[MessageQueue],
+    ) -> Vec<MessageQueue> {
+        let Some(index) = consumers.iter().position(|c| c == client_id) else {
+            return vec![];
+        };
+        let size = queues.len() / consumers.len();
+        let remainder = queues.len() % consumers.len();
+        let (start, size) = if index < remainder {
+            (index * (size + 1), size + 1)
+        } else {
+            (index * size + remainder, size)
+        };
+        queues.iter().skip(start).take(size).cloned().collect()
+    }
+}
+
+/// Deals the brokers to the consumers in turn, each consumer takes all the queues of the
+/// brokers dealt to it.
+pub struct RoundRobinByBrokerAllocator;
+
+impl QueueAllocator for RoundRobinByBrokerAllocator {
+    fn allocate(
+        &self,
+        client_id: &str,
+        consumers: &[String],
+        queues: &[MessageQueue],
+    ) -> Vec<MessageQueue> {
+        let Some(index) = consumers.iter().position(|c| c == client_id) else {
+            return vec![];
+        };
+        let brokers: BTreeSet<&str> = queues.iter().map(|queue| queue.broker_name()).collect();
+        let assigned: HashSet<&str> = brokers
+            .into_iter()
+            .skip(index)
+            .step_by(consumers.len())
+            .collect();
+        queues
+            .iter()
+            .filter(|queue| assigned.contains(queue.broker_name()))
+            .cloned()
+            .collect()
+    }
+}
+
+impl AllocateStrategy {
+    pub fn allocator(&self) -> &'static dyn QueueAllocator {
+        match self {
+            AllocateStrategy::Pop => &PopAllocator,
+            AllocateStrategy::Average => &AverageAllocator,
+            AllocateStrategy::RoundRobinByBroker => &RoundRobinByBrokerAllocator,
+        }
+    }
+}
+
+/**
+ * AssignmentService tells the push consumers the queues to pop from, allocated among the
+ * consumers of the group with the strategy configured for the group.
+ */
+#[derive(Debug)]
+pub struct AssignmentService {
+    route_service: Arc<RouteService>,
+    setting_manager: Arc<ClientSettingManager>,
+    group_config_manager: Arc<RwLock<GroupConfigManager>>,
+}
+
+impl AssignmentService {
+    pub fn new(
+        route_service: Arc<RouteService>,
+        setting_manager: Arc<ClientSettingManager>,
+        group_config_manager: Arc<RwLock<GroupConfigManager>>,
+    ) -> Self {
+        Self {
+            route_service,
+            setting_manager,
+            group_config_manager,
+        }
+    }
+
+    pub async fn query_assignment(
+        &self,
+        client_id: &str,
+        request: pb::QueryAssignmentRequest,
+    ) -> pb::QueryAssignmentResponse {
+        match self.assign(client_id, request).await {
+            Ok(assignments) => pb::QueryAssignmentResponse {
+                status: Some(ok_status()),
+                assignments,
+            },
+            Err(status) => pb::QueryAssignmentResponse {
+                status: Some(status),
+                assignments: vec![],
+            },
+        }
+    }
+
+    async fn assign(
+        &self,
+        client_id: &str,
+        request: pb::QueryAssignmentRequest,
+    ) -> Result<Vec<pb::Assignment>, Status> {
+        if client_id.is_empty() {
+            return Err(status(Code::ClientIdRequired, "client id is required"));
+        }
+        let topic = request
+            .topic
+            .filter(|topic| !topic.name.is_empty())
+            .ok_or_else(|| status(Code::IllegalTopic, "topic is required"))?;
+        let group = request
+            .group
+            .map(|group| group.name)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| status(Code::IllegalConsumerGroup, "group is required"))?;
+        let endpoints = request
+            .endpoints
+            .ok_or_else(|| status(Code::BadRequest, "endpoints is required"))?;
+
+        let route = self
+            .route_service
+            .get_topic_route(&topic.name)
+            .await
+            .map_err(|e| e.to_status())?;
+        let message_type = self.route_service.accept_message_type(&topic.name);
+        let mut message_queues: Vec<pb::MessageQueue> = route
+            .to_message_queues(&topic, &endpoints, message_type)
+            .into_iter()
+            .filter(|queue| {
+                queue.permission == pb::Permission::Read as i32
+                    || queue.permission == pb::Permission::ReadWrite as i32
+            })
+            .collect();
+        message_queues.sort_by(|a, b| (broker_name(a), a.id).cmp(&(broker_name(b), b.id)));
+        let queues: Vec<MessageQueue> = message_queues
+            .iter()
+            .map(|queue| MessageQueue::new(&topic.name, broker_name(queue), queue.id))
+            .collect();
+
+        let strategy = self
+            .group_config_manager
+            .read()
+            .get_group_config(&group)
+            .map(|config| config.allocate_strategy())
+            .unwrap_or_default();
+        let consumers = self.consumers(client_id, &group);
+        let assigned: HashSet<MessageQueue> = strategy
+            .allocator()
+            .allocate(client_id, &consumers, &queues)
+            .into_iter()
+            .collect();
+        Ok(message_queues
+            .into_iter()
+            .zip(queues)
+            .filter(|(_, queue)| assigned.contains(queue))
+            .map(|(message_queue, _)| pb::Assignment {
+                message_queue: Some(message_queue),
+            })
+            .collect())
+    }
+
+    /// Returns the sorted ids of the live push consumers of the group, including the requesting
+    /// one even if it has not sent heartbeats yet.
+    fn consumers(&self, client_id: &str, group: &str) -> Vec<String> {
+        let mut consumers: Vec<String> = self
+            .setting_manager
+            .sessions()
+            .into_iter()
+            .filter(|session| {
+                session.client_type() == pb::ClientType::PushConsumer
+                    && session.group() == Some(group)
+            })
+            .map(|session| session.client_id().to_string())
+            .chain([client_id.to_string()])
+            .collect();
+        consumers.sort();
+        consumers.dedup();
+        consumers
+    }
+}
+
+fn broker_name(queue: &pb::MessageQueue) -> &str {
+    queue
+        .broker
+        .as_ref()
+        .map(|broker| broker.name.as_str())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use gmq_remoting::common::{
+        code::{RequestCode, ResponseCode},
+        command::Command,
+    };
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+    use super::*;
+    use crate::{
+        pb::receive_message_response::Content,
+        remoting::{
+            header::PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX,
+            message::{tests::encode_message, MessageExt},
+        },
+        service::{
+            consumer::ConsumerService,
+            group_config::GroupConfig,
+            mock_broker::{resource, MockBroker},
+            receipt_handle::ReceiptHandle,
+            renew::RenewService,
+            session::SettingPolicy,
+        },
+    };
+
+    fn queues() -> Vec<MessageQueue> {
+        let mut queues = Vec::new();
+        for broker_name in ["broker-a", "broker-b", "broker-c"] {
+            for queue_id in 0..2 {
+                queues.push(MessageQueue::new("test", broker_name, queue_id));
+            }
+        }
+        queues
+    }
+
+    fn consumers(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("c{}", i)).collect()
+    }
+
+    fn describe(queues: Vec<MessageQueue>) -> Vec<String> {
+        queues
+            .iter()
+            .map(|queue| format!("{}-{}", queue.broker_name(), queue.queue_id()))
+            .collect()
+    }
+
+    #[test]
+    fn test_allocators() {
+        let queues = queues();
+        assert_eq!(6, PopAllocator.allocate("c1", &consumers(4), &queues).len());
+
+        let consumers = consumers(4);
+        let average: Vec<Vec<String>> = consumers
+            .iter()
+            .map(|c| describe(AverageAllocator.allocate(c, &consumers, &queues)))
+            .collect();
+        assert_eq!(
+            vec![
+                vec!["broker-a-0", "broker-a-1"],
+                vec!["broker-b-0", "broker-b-1"],
+                vec!["broker-c-0"],
+                vec!["broker-c-1"],
+            ],
+            average
+        );
+        // more consumers than queues leave the last ones idle.
+        let many = self::consumers(8);
+        assert_eq!(1, AverageAllocator.allocate("c5", &many, &queues).len());
+        assert!(AverageAllocator.allocate("c7", &many, &queues).is_empty());
+        assert!(AverageAllocator
+            .allocate("unknown", &consumers, &queues)
+            .is_empty());
+
+        let two = self::consumers(2);
+        assert_eq!(
+            vec!["broker-a-0", "broker-a-1", "broker-c-0", "broker-c-1"],
+            describe(RoundRobinByBrokerAllocator.allocate("c0", &two, &queues))
+        );
+        assert_eq!(
+            vec!["broker-b-0", "broker-b-1"],
+            describe(RoundRobinByBrokerAllocator.allocate("c1", &two, &queues))
+        );
+        // the brokers are dealt in order of their names whatever order the queues come in.
+        let mut interleaved = queues.clone();
+        interleaved.sort_by_key(|queue| queue.queue_id());
+        assert_eq!(
+            vec!["broker-b-0", "broker-b-1"],
+            describe(RoundRobinByBrokerAllocator.allocate("c1", &two, &interleaved))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_assignment() {
+        let broker = MockBroker::start().await;
+        broker.route(json!({
+            "queueDatas": [
+                {"brokerName": "broker-a", "readQueueNums": 2, "writeQueueNums": 2, "perm": 6},
+                {"brokerName": "broker-b", "readQueueNums": 2, "writeQueueNums": 2, "perm": 6}
+            ],
+            "brokerDatas": [
+                {"cluster": "c1", "brokerName": "broker-a", "brokerAddrs": {"0": broker.addr()}},
+                {"cluster": "c1", "brokerName": "broker-b", "brokerAddrs": {"0": broker.addr()}}
+            ]
+        }));
+        let route_service = broker.route_service();
+        let dir = tempfile::tempdir().unwrap();
+        let mut group_config_manager = GroupConfigManager::new(dir.path().to_str().unwrap());
+        group_config_manager.load().unwrap();
+        let group_config_manager = Arc::new(RwLock::new(group_config_manager));
+        let setting_manager = Arc::new(ClientSettingManager::new(
+            Arc::new(RenewService::new(
+                broker.mq_client(),
+                Arc::clone(&route_service),
+            )),
+            Arc::clone(&group_config_manager),
+            SettingPolicy::default(),
+        ));
+        let service = AssignmentService::new(
+            route_service,
+            Arc::clone(&setting_manager),
+            Arc::clone(&group_config_manager),
+        );
+
+        let request = |group: &str| pb::QueryAssignmentRequest {
+            topic: resource("test"),
+            group: resource(group),
+            endpoints: Some(pb::Endpoints::default()),
+        };
+        let assigned = |response: pb::QueryAssignmentResponse| -> Vec<String> {
+            assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+            response
+                .assignments
+                .into_iter()
+                .map(|assignment| {
+                    let queue = assignment.message_queue.unwrap();
+                    format!("{}-{}", broker_name(&queue), queue.id)
+                })
+                .collect()
+        };
+
+        // every consumer pops from all the queues by default.
+        setting_manager.heartbeat(
+            "c0",
+            pb::ClientType::PushConsumer,
+            Some("group".to_string()),
+        );
+        let response = service.query_assignment("c1", request("group")).await;
+        assert_eq!(4, assigned(response).len());
+
+        let mut config: GroupConfig = serde_json::from_value(json!({
+            "name": "group",
+            "allocate_strategy": "ROUND_ROBIN_BY_BROKER"
+        }))
+        .unwrap();
+        group_config_manager
+            .write()
+            .add_or_update_group(config.clone())
+            .unwrap();
+        let response = service.query_assignment("c1", request("group")).await;
+        assert_eq!(vec!["broker-b-0", "broker-b-1"], assigned(response));
+
+        config = serde_json::from_value(json!({"name": "group", "allocate_strategy": "AVERAGE"}))
+            .unwrap();
+        group_config_manager
+            .write()
+            .add_or_update_group(config)
+            .unwrap();
+        setting_manager.heartbeat(
+            "c2",
+            pb::ClientType::PushConsumer,
+            Some("group".to_string()),
+        );
+        setting_manager.heartbeat("p0", pb::ClientType::Producer, None);
+        // simple consumers of the group pop by themselves and take no queues.
+        setting_manager.heartbeat(
+            "s0",
+            pb::ClientType::SimpleConsumer,
+            Some("group".to_string()),
+        );
+        let response = service.query_assignment("c0", request("group")).await;
+        assert_eq!(vec!["broker-a-0", "broker-a-1"], assigned(response));
+        let response = service.query_assignment("c1", request("group")).await;
+        let assignment = response.assignments[0].message_queue.clone();
+        assert_eq!(vec!["broker-b-0"], assigned(response));
+
+        // the consumer pops from its assigned queue only.
+        broker.handle(RequestCode::PopMessage, |request| {
+            let queue_id = request.get_property("queueId").unwrap();
+            let mut message = MessageExt {
+                topic: "test".to_string(),
+                queue_id: queue_id.parse().unwrap(),
+                queue_offset: 100,
+                ..Default::default()
+            };
+            message.properties.insert(
+                PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX.to_string(),
+                "id".to_string(),
+            );
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            response.add_property("popTime", "1000");
+            response.add_property("invisibleTime", "30000");
+            response.add_property("reviveQid", "0");
+            response.add_property("restNum", "0");
+            response.add_property("startOffsetInfo", format!("0 {} 100", queue_id).as_str());
+            response.set_body(encode_message(&message));
+            response
+        });
+        let consumer = ConsumerService::new(
+            broker.mq_client(),
+            broker.route_service(),
+            Arc::new(RenewService::new(
+                broker.mq_client(),
+                broker.route_service(),
+            )),
+        );
+        for _ in 0..4 {
+            let (sender, receiver) = mpsc::channel(4);
+            let receive = pb::ReceiveMessageRequest {
+                group: resource("group"),
+                message_queue: assignment.clone(),
+                batch_size: 1,
+                invisible_duration: Some(prost_types::Duration {
+                    seconds: 30,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            };
+            consumer.receive_message("c1", receive, sender).await;
+            let responses: Vec<_> = ReceiverStream::new(receiver).collect().await;
+            let Some(Content::Message(message)) = &responses[0].as_ref().unwrap().content else {
+                panic!("message expected");
+            };
+            let receipt_handle = message
+                .system_properties
+                .as_ref()
+                .unwrap()
+                .receipt_handle
+                .as_ref();
+            let handle: ReceiptHandle = receipt_handle.unwrap().parse().unwrap();
+            assert_eq!(
+                ("broker-b", 0),
+                (handle.broker_name.as_str(), handle.queue_id)
+            );
+        }
+
+        let response = service.query_assignment("c0", request("")).await;
+        assert_eq!(
+            Code::IllegalConsumerGroup as i32,
+            response.status.unwrap().code
+        );
+        let response = service.query_assignment("", request("group")).await;
+        assert_eq!(Code::ClientIdRequired as i32, response.status.unwrap().code);
+
+        broker.shutdown().await;
+    }
+}
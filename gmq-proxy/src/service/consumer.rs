@@ -0,0 +1,1103 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+
+use super::{
+    receipt_handle::{ReceiptHandle, ReceiptTopicType},
+    renew::RenewService,
+    route::RouteService,
+};
+use crate::{
+    common::{batch_status, millis_to_timestamp, now_millis, ok_status, status, Error},
+    pb::{self, receive_message_response::Content, Code, Status},
+    remoting::{
+        client::{MQClient, PopResult},
+        header::{
+            is_system_property, AckMessageRequestHeader, ChangeInvisibleTimeRequestHeader,
+            ConsumerSendMsgBackRequestHeader, PopMessageRequestHeader, PopMessageResponseHeader,
+            KEY_SEPARATOR, PROPERTY_DELAY_TIME_LEVEL, PROPERTY_DLQ_ORIGIN_MESSAGE_ID,
+            PROPERTY_DLQ_ORIGIN_TOPIC, PROPERTY_KEYS, PROPERTY_POP_CK, PROPERTY_SHARDING_KEY,
+            PROPERTY_TAGS, PROPERTY_TIMER_DELIVER_MS, PROPERTY_TRACE_CONTEXT,
+            PROPERTY_TRANSACTION_PREPARED, PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX,
+        },
+        message::MessageExt,
+    },
+};
+
+pub(crate) const MAX_BATCH_SIZE: i32 = 32;
+const DEFAULT_INVISIBLE_DURATION: Duration = Duration::from_secs(60);
+const MIN_INVISIBLE_DURATION: Duration = Duration::from_secs(10);
+const MAX_INVISIBLE_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
+const DEFAULT_LONG_POLLING_TIMEOUT: Duration = Duration::from_secs(20);
+const MIN_LONG_POLLING_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_LONG_POLLING_TIMEOUT: Duration = Duration::from_secs(20);
+/// Extra time given to brokers to respond a pop request after its long polling times out.
+const POP_TIMEOUT_MARGIN: Duration = Duration::from_secs(3);
+/// Brokers initialize the consume offset of a new group to the max offset of the queue.
+const CONSUME_INIT_MODE_MAX: i32 = 1;
+/// Brokers store the messages sent back with a negative delay level to the dead letter queue.
+const DLQ_DELAY_LEVEL: i32 = -1;
+
+/// Streams the responses of a ReceiveMessage call to the client.
+pub type ReceiveMessageSender = mpsc::Sender<Result<pb::ReceiveMessageResponse, tonic::Status>>;
+
+/**
+ * ConsumerService pops messages from brokers for the gRPC clients consuming in the simple
+ * consumer or push consumer way.
+ */
+#[derive(Debug)]
+pub struct ConsumerService {
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+    renew_service: Arc<RenewService>,
+    queue_index: AtomicUsize,
+}
+
+impl ConsumerService {
+    pub fn new(
+        mq_client: Arc<MQClient>,
+        route_service: Arc<RouteService>,
+        renew_service: Arc<RenewService>,
+    ) -> Self {
+        Self {
+            mq_client,
+            route_service,
+            renew_service,
+            queue_index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pops messages for the request and streams them to the sender one by one, followed by
+    /// the status and the time the delivery starts. Messages received with `auto_renew` are
+    /// kept invisible for the client until they are acked.
+    pub async fn receive_message(
+        &self,
+        client_id: &str,
+        request: pb::ReceiveMessageRequest,
+        sender: ReceiveMessageSender,
+    ) {
+        let send = |content| {
+            sender.send(Ok(pb::ReceiveMessageResponse {
+                content: Some(content),
+            }))
+        };
+        let result = match self.receive(client_id, request).await {
+            Ok(messages) if messages.is_empty() => status(Code::MessageNotFound, "no new message"),
+            Ok(messages) => {
+                let handles: Vec<String> = messages
+                    .iter()
+                    .filter_map(|message| {
+                        message.system_properties.as_ref()?.receipt_handle.clone()
+                    })
+                    .collect();
+                for (sent, message) in messages.into_iter().enumerate() {
+                    if send(Content::Message(message)).await.is_err() {
+                        // the client is gone, the messages not delivered are redelivered once
+                        // invisible time runs out.
+                        for handle in &handles[sent..] {
+                            self.renew_service.remove(client_id, handle).await;
+                        }
+                        return;
+                    }
+                }
+                ok_status()
+            }
+            Err(status) => status,
+        };
+        if send(Content::Status(result)).await.is_ok() {
+            let _ = send(Content::DeliveryTimestamp(
+                millis_to_timestamp(now_millis()),
+            ))
+            .await;
+        }
+    }
+
+    async fn receive(
+        &self,
+        client_id: &str,
+        request: pb::ReceiveMessageRequest,
+    ) -> Result<Vec<pb::Message>, Status> {
+        let group = request
+            .group
+            .filter(|group| !group.name.is_empty())
+            .ok_or_else(|| status(Code::IllegalConsumerGroup, "group is required"))?;
+        let message_queue = request.message_queue.unwrap_or_default();
+        let topic = message_queue
+            .topic
+            .filter(|topic| !topic.name.is_empty())
+            .ok_or_else(|| status(Code::IllegalTopic, "topic is required"))?;
+        // push consumers pop from the queues assigned to them, simple consumers leave it to
+        // the proxy.
+        let assigned_broker = message_queue
+            .broker
+            .map(|broker| broker.name)
+            .filter(|name| !name.is_empty());
+        if request.batch_size <= 0 || request.batch_size > MAX_BATCH_SIZE {
+            return Err(status(
+                Code::BadRequest,
+                format!("batch size should be in [1, {}]", MAX_BATCH_SIZE),
+            ));
+        }
+        let invisible_duration = if request.auto_renew {
+            DEFAULT_INVISIBLE_DURATION
+        } else {
+            request
+                .invisible_duration
+                .and_then(|d| Duration::try_from(d).ok())
+                .filter(|d| (MIN_INVISIBLE_DURATION..=MAX_INVISIBLE_DURATION).contains(d))
+                .ok_or_else(|| {
+                    status(
+                        Code::IllegalInvisibleTime,
+                        format!(
+                            "invisible duration should be in [{:?}, {:?}]",
+                            MIN_INVISIBLE_DURATION, MAX_INVISIBLE_DURATION
+                        ),
+                    )
+                })?
+        };
+        let long_polling_timeout = long_polling_timeout(request.long_polling_timeout)?;
+        let (exp_type, exp) = filter_expression(request.filter_expression);
+
+        let route = self
+            .route_service
+            .get_topic_route(&topic.name)
+            .await
+            .map_err(|e| e.to_status())?;
+        let queues = route.readable_queues();
+        // a queue id of -1 pops from all the queues of the broker.
+        let (broker_name, queue_id) = match assigned_broker {
+            Some(broker_name) => {
+                let readable = queues.iter().any(|queue| {
+                    queue.broker_name() == broker_name
+                        && (message_queue.id == -1 || queue.queue_id() == message_queue.id)
+                });
+                if !readable {
+                    return Err(status(
+                        Code::Forbidden,
+                        format!(
+                            "queue {} of broker {} is not readable for topic {}",
+                            message_queue.id, broker_name, topic.name
+                        ),
+                    ));
+                }
+                (broker_name, message_queue.id)
+            }
+            None => {
+                if queues.is_empty() {
+                    return Err(status(
+                        Code::Forbidden,
+                        format!("no readable queue for topic {}", topic.name),
+                    ));
+                }
+                let index = self.queue_index.fetch_add(1, Ordering::Relaxed);
+                (queues[index % queues.len()].broker_name().to_string(), -1)
+            }
+        };
+        let addr = route
+            .master_addr(&broker_name)
+            .map(|addr| addr.to_string())
+            .ok_or_else(|| {
+                status(
+                    Code::InternalServerError,
+                    format!("no master of broker {}", broker_name),
+                )
+            })?;
+
+        let header = PopMessageRequestHeader {
+            consumer_group: group.name.clone(),
+            topic: topic.name.clone(),
+            queue_id,
+            max_msg_nums: request.batch_size,
+            invisible_time: invisible_duration.as_millis() as i64,
+            poll_time: long_polling_timeout.as_millis() as i64,
+            born_time: now_millis(),
+            init_mode: CONSUME_INIT_MODE_MAX,
+            exp_type: Some(exp_type.to_string()),
+            exp: Some(exp),
+            order: false,
+        };
+        let result = self
+            .mq_client
+            .pop_message(&addr, header, long_polling_timeout + POP_TIMEOUT_MARGIN)
+            .await
+            .map_err(|e| e.to_status())?;
+        let Some(PopResult { header, messages }) = result else {
+            return Ok(vec![]);
+        };
+        Ok(messages
+            .into_iter()
+            .map(|message| {
+                let receipt_handle = receipt_handle(&header, &broker_name, &message);
+                if request.auto_renew {
+                    self.renew_service.add(
+                        client_id,
+                        &group.name,
+                        &topic.name,
+                        receipt_handle.clone(),
+                    );
+                }
+                to_pb_message(
+                    message,
+                    &topic,
+                    Some(receipt_handle),
+                    Some(invisible_duration),
+                )
+            })
+            .collect())
+    }
+
+    /// Acks the messages one by one, the result of every message is reported in its own entry.
+    pub async fn ack_message(
+        &self,
+        client_id: &str,
+        request: pb::AckMessageRequest,
+    ) -> pb::AckMessageResponse {
+        let (group, topic) = match group_and_topic(request.group, request.topic) {
+            Ok(resources) => resources,
+            Err(status) => {
+                return pb::AckMessageResponse {
+                    status: Some(status),
+                    entries: vec![],
+                }
+            }
+        };
+
+        let mut entries = Vec::with_capacity(request.entries.len());
+        for entry in request.entries {
+            let status = match self
+                .ack(client_id, &group, &topic, &entry.receipt_handle)
+                .await
+            {
+                Ok(()) => ok_status(),
+                Err(status) => status,
+            };
+            entries.push(pb::AckMessageResultEntry {
+                message_id: entry.message_id,
+                receipt_handle: entry.receipt_handle,
+                status: Some(status),
+            });
+        }
+        let status = batch_status(entries.iter().filter_map(|entry| entry.status.as_ref()));
+        pb::AckMessageResponse {
+            status: Some(status),
+            entries,
+        }
+    }
+
+    async fn ack(
+        &self,
+        client_id: &str,
+        group: &str,
+        topic: &str,
+        receipt_handle: &str,
+    ) -> Result<(), Status> {
+        // the message may have been renewed since it is delivered to the client.
+        let (handle, extra_info) = match self.renew_service.remove(client_id, receipt_handle).await
+        {
+            Some(handle) => {
+                let extra_info = handle.to_string();
+                (handle, extra_info)
+            }
+            None => {
+                let handle: ReceiptHandle =
+                    receipt_handle.parse().map_err(|e: Error| e.to_status())?;
+                (handle, receipt_handle.to_string())
+            }
+        };
+        let addr = self
+            .route_service
+            .get_master_addr(topic, &handle.broker_name)
+            .await
+            .map_err(|e| e.to_status())?;
+        let header = AckMessageRequestHeader {
+            consumer_group: group.to_string(),
+            topic: handle.real_topic(topic, group),
+            queue_id: handle.queue_id,
+            extra_info,
+            offset: handle.offset,
+        };
+        self.mq_client
+            .ack_message(&addr, header)
+            .await
+            .map_err(|e| e.to_status())
+    }
+
+    /// Changes the invisible duration of a received message and issues a new receipt handle,
+    /// the old one is no longer valid. The message is no longer renewed automatically.
+    pub async fn change_invisible_duration(
+        &self,
+        client_id: &str,
+        request: pb::ChangeInvisibleDurationRequest,
+    ) -> pb::ChangeInvisibleDurationResponse {
+        match self.change_invisible(client_id, request).await {
+            Ok(receipt_handle) => pb::ChangeInvisibleDurationResponse {
+                status: Some(ok_status()),
+                receipt_handle,
+            },
+            Err(status) => pb::ChangeInvisibleDurationResponse {
+                status: Some(status),
+                receipt_handle: String::new(),
+            },
+        }
+    }
+
+    async fn change_invisible(
+        &self,
+        client_id: &str,
+        request: pb::ChangeInvisibleDurationRequest,
+    ) -> Result<String, Status> {
+        let (group, topic) = group_and_topic(request.group, request.topic)?;
+        let invisible_duration = request
+            .invisible_duration
+            .and_then(|d| Duration::try_from(d).ok())
+            .filter(|d| *d <= MAX_INVISIBLE_DURATION)
+            .ok_or_else(|| {
+                status(
+                    Code::IllegalInvisibleTime,
+                    format!(
+                        "invisible duration should not exceed {:?}",
+                        MAX_INVISIBLE_DURATION
+                    ),
+                )
+            })?;
+        let handle = match self
+            .renew_service
+            .remove(client_id, &request.receipt_handle)
+            .await
+        {
+            Some(handle) => handle,
+            None => request
+                .receipt_handle
+                .parse()
+                .map_err(|e: Error| e.to_status())?,
+        };
+        if handle.is_expired() {
+            return Err(status(
+                Code::InvalidReceiptHandle,
+                format!("receipt handle {:?} is expired", request.receipt_handle),
+            ));
+        }
+        let handle = change_invisible_time(
+            &self.mq_client,
+            &self.route_service,
+            &group,
+            &topic,
+            &handle,
+            invisible_duration,
+        )
+        .await?;
+        Ok(handle.to_string())
+    }
+
+    /// Moves a received message to the dead letter queue of the group, then acks the original
+    /// one so that it is not redelivered.
+    pub async fn forward_message_to_dead_letter_queue(
+        &self,
+        client_id: &str,
+        request: pb::ForwardMessageToDeadLetterQueueRequest,
+    ) -> pb::ForwardMessageToDeadLetterQueueResponse {
+        let status = match self.forward_to_dead_letter_queue(client_id, request).await {
+            Ok(()) => ok_status(),
+            Err(status) => status,
+        };
+        pb::ForwardMessageToDeadLetterQueueResponse {
+            status: Some(status),
+        }
+    }
+
+    async fn forward_to_dead_letter_queue(
+        &self,
+        client_id: &str,
+        request: pb::ForwardMessageToDeadLetterQueueRequest,
+    ) -> Result<(), Status> {
+        let (group, topic) = group_and_topic(request.group, request.topic)?;
+        let handle: ReceiptHandle = request
+            .receipt_handle
+            .parse()
+            .map_err(|e: Error| e.to_status())?;
+        // brokers locate the message to send back by its commit log offset.
+        if handle.commit_log_offset < 0 {
+            return Err(status(
+                Code::InvalidReceiptHandle,
+                format!(
+                    "receipt handle {:?} has no commit log offset",
+                    request.receipt_handle
+                ),
+            ));
+        }
+
+        let addr = self
+            .route_service
+            .get_master_addr(&topic, &handle.broker_name)
+            .await
+            .map_err(|e| e.to_status())?;
+        let header = ConsumerSendMsgBackRequestHeader {
+            offset: handle.commit_log_offset,
+            group: group.clone(),
+            delay_level: DLQ_DELAY_LEVEL,
+            origin_msg_id: request.message_id,
+            origin_topic: topic.clone(),
+            max_reconsume_times: request.max_delivery_attempts,
+        };
+        self.mq_client
+            .consumer_send_msg_back(&addr, header)
+            .await
+            .map_err(|e| e.to_status())?;
+        self.ack(client_id, &group, &topic, &request.receipt_handle)
+            .await
+    }
+}
+
+/// Changes the invisible time of the message on the broker, returns the new receipt handle.
+pub(crate) async fn change_invisible_time(
+    mq_client: &MQClient,
+    route_service: &RouteService,
+    group: &str,
+    topic: &str,
+    handle: &ReceiptHandle,
+    invisible_duration: Duration,
+) -> Result<ReceiptHandle, Status> {
+    let addr = route_service
+        .get_master_addr(topic, &handle.broker_name)
+        .await
+        .map_err(|e| e.to_status())?;
+    let header = ChangeInvisibleTimeRequestHeader {
+        consumer_group: group.to_string(),
+        topic: handle.real_topic(topic, group),
+        queue_id: handle.queue_id,
+        extra_info: handle.to_string(),
+        offset: handle.offset,
+        invisible_time: invisible_duration.as_millis() as i64,
+    };
+    let result = mq_client
+        .change_invisible_time(&addr, header)
+        .await
+        .map_err(|e| e.to_status())?;
+    Ok(handle.renew(result.pop_time, result.invisible_time, result.revive_qid))
+}
+
+/// Returns the long polling timeout requested by the client, capped by the max one.
+pub(crate) fn long_polling_timeout(
+    timeout: Option<prost_types::Duration>,
+) -> Result<Duration, Status> {
+    match timeout {
+        Some(timeout) => Ok(Duration::try_from(timeout)
+            .ok()
+            .filter(|timeout| *timeout >= MIN_LONG_POLLING_TIMEOUT)
+            .ok_or_else(|| {
+                status(
+                    Code::IllegalPollingTime,
+                    format!(
+                        "long polling timeout should not be less than {:?}",
+                        MIN_LONG_POLLING_TIMEOUT
+                    ),
+                )
+            })?
+            .min(MAX_LONG_POLLING_TIMEOUT)),
+        None => Ok(DEFAULT_LONG_POLLING_TIMEOUT),
+    }
+}
+
+/// Returns the expression type and the expression brokers filter messages with, all the
+/// messages are matched without a filter expression.
+pub(crate) fn filter_expression(filter: Option<pb::FilterExpression>) -> (&'static str, String) {
+    match filter {
+        Some(filter) if filter.r#type == pb::FilterType::Sql as i32 => ("SQL92", filter.expression),
+        Some(filter) if !filter.expression.is_empty() => ("TAG", filter.expression),
+        _ => ("TAG", "*".to_string()),
+    }
+}
+
+fn group_and_topic(
+    group: Option<pb::Resource>,
+    topic: Option<pb::Resource>,
+) -> Result<(String, String), Status> {
+    let group = group
+        .map(|group| group.name)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| status(Code::IllegalConsumerGroup, "group is required"))?;
+    let topic = topic
+        .map(|topic| topic.name)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| status(Code::IllegalTopic, "topic is required"))?;
+    Ok((group, topic))
+}
+
+/// Returns the receipt handle of a popped message. Brokers restore the original topic of the
+/// messages popped from retry topics and keep their checkpoints in `POP_CK`, the handles of the
+/// others are built from the start offsets of their queues.
+fn receipt_handle(
+    header: &PopMessageResponseHeader,
+    broker_name: &str,
+    message: &MessageExt,
+) -> ReceiptHandle {
+    let pop_ck = message
+        .properties
+        .get(PROPERTY_POP_CK)
+        .and_then(|pop_ck| pop_ck.parse::<ReceiptHandle>().ok());
+    if let Some(handle) = pop_ck {
+        return ReceiptHandle {
+            commit_log_offset: message.commit_log_offset,
+            ..handle
+        };
+    }
+    let start_offset = header
+        .start_offset(&message.topic, message.queue_id)
+        .unwrap_or(message.queue_offset);
+    ReceiptHandle {
+        start_offset,
+        retrieve_time: header.pop_time,
+        invisible_time: header.invisible_time,
+        revive_queue_id: header.revive_qid,
+        topic_type: ReceiptTopicType::from_topic(&message.topic),
+        broker_name: broker_name.to_string(),
+        queue_id: message.queue_id,
+        offset: message.queue_offset,
+        commit_log_offset: message.commit_log_offset,
+    }
+}
+
+/// Converts a message stored by brokers to the one delivered to gRPC clients, along with the
+/// receipt handle if the message is popped.
+pub(crate) fn to_pb_message(
+    message: MessageExt,
+    topic: &pb::Resource,
+    receipt_handle: Option<ReceiptHandle>,
+    invisible_duration: Option<Duration>,
+) -> pb::Message {
+    let properties = message.properties;
+    let message_type = if properties
+        .get(PROPERTY_TRANSACTION_PREPARED)
+        .is_some_and(|v| v == "true")
+    {
+        pb::MessageType::Transaction
+    } else if properties.contains_key(PROPERTY_SHARDING_KEY) {
+        pb::MessageType::Fifo
+    } else if properties.contains_key(PROPERTY_TIMER_DELIVER_MS)
+        || properties.contains_key(PROPERTY_DELAY_TIME_LEVEL)
+    {
+        pb::MessageType::Delay
+    } else {
+        pb::MessageType::Normal
+    };
+
+    let system_properties = pb::SystemProperties {
+        tag: properties.get(PROPERTY_TAGS).cloned(),
+        keys: properties
+            .get(PROPERTY_KEYS)
+            .map(|keys| {
+                keys.split(KEY_SEPARATOR)
+                    .filter(|key| !key.is_empty())
+                    .map(|key| key.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        message_id: properties
+            .get(PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX)
+            .cloned()
+            .unwrap_or(message.offset_msg_id),
+        body_digest: None,
+        body_encoding: pb::Encoding::Identity as i32,
+        message_type: message_type as i32,
+        born_timestamp: Some(millis_to_timestamp(message.born_timestamp)),
+        born_host: message.born_host,
+        store_timestamp: Some(millis_to_timestamp(message.store_timestamp)),
+        store_host: message.store_host,
+        delivery_timestamp: properties
+            .get(PROPERTY_TIMER_DELIVER_MS)
+            .and_then(|ms| ms.parse().ok())
+            .map(millis_to_timestamp),
+        receipt_handle: receipt_handle.map(|handle| handle.to_string()),
+        queue_id: message.queue_id,
+        queue_offset: Some(message.queue_offset),
+        invisible_duration: invisible_duration
+            .and_then(|duration| prost_types::Duration::try_from(duration).ok()),
+        delivery_attempt: Some(message.reconsume_times + 1),
+        message_group: properties.get(PROPERTY_SHARDING_KEY).cloned(),
+        trace_context: properties.get(PROPERTY_TRACE_CONTEXT).cloned(),
+        dead_letter_queue: properties
+            .get(PROPERTY_DLQ_ORIGIN_TOPIC)
+            .map(|origin_topic| pb::DeadLetterQueue {
+                topic: origin_topic.clone(),
+                message_id: properties
+                    .get(PROPERTY_DLQ_ORIGIN_MESSAGE_ID)
+                    .cloned()
+                    .unwrap_or_default(),
+            }),
+        ..Default::default()
+    };
+    let user_properties: HashMap<String, String> = properties
+        .into_iter()
+        .filter(|(key, _)| !is_system_property(key))
+        .collect();
+    pb::Message {
+        topic: Some(topic.clone()),
+        user_properties,
+        system_properties: Some(system_properties),
+        body: message.body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gmq_remoting::common::{
+        code::{RequestCode, ResponseCode},
+        command::Command,
+    };
+
+    use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+    use super::*;
+    use crate::{
+        remoting::{header::DLQ_GROUP_TOPIC_PREFIX, message::tests::encode_message},
+        service::mock_broker::{resource, MockBroker},
+    };
+
+    /// Pops the messages at offsets from 100 of queue 1 with tag `tag-a`, unless the filter
+    /// expression is `none`.
+    fn pop_message(request: &Command) -> Command {
+        assert_eq!("-1", request.get_property("queueId").unwrap());
+        if request.get_property("exp").unwrap() == "none" {
+            return Command::new_response(request, ResponseCode::PollingTimeout);
+        }
+        let mut data = Vec::new();
+        for offset in 0..request.get_property("maxMsgNums").unwrap().parse().unwrap() {
+            let mut message = MessageExt {
+                topic: "test".to_string(),
+                queue_id: 1,
+                queue_offset: 100 + offset,
+                body: b"hello".to_vec(),
+                ..Default::default()
+            };
+            message.properties.insert(
+                PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX.to_string(),
+                format!("id-{}", offset),
+            );
+            message
+                .properties
+                .insert(PROPERTY_TAGS.to_string(), "tag-a".to_string());
+            message
+                .properties
+                .insert("custom".to_string(), "value".to_string());
+            data.extend(encode_message(&message));
+        }
+        let mut response = Command::new_response(request, ResponseCode::Success);
+        response.add_property("popTime", "1000");
+        response.add_property("invisibleTime", "30000");
+        response.add_property("reviveQid", "0");
+        response.add_property("restNum", "0");
+        response.add_property("startOffsetInfo", "0 1 100");
+        response.set_body(data);
+        response
+    }
+
+    fn request(batch_size: i32, tag: &str) -> pb::ReceiveMessageRequest {
+        pb::ReceiveMessageRequest {
+            group: Some(pb::Resource {
+                resource_namespace: "".to_string(),
+                name: "group".to_string(),
+            }),
+            message_queue: Some(pb::MessageQueue {
+                topic: Some(pb::Resource {
+                    resource_namespace: "".to_string(),
+                    name: "test".to_string(),
+                }),
+                ..Default::default()
+            }),
+            filter_expression: Some(pb::FilterExpression {
+                r#type: pb::FilterType::Tag as i32,
+                expression: tag.to_string(),
+            }),
+            batch_size,
+            invisible_duration: Some(prost_types::Duration {
+                seconds: 30,
+                nanos: 0,
+            }),
+            auto_renew: false,
+            long_polling_timeout: Some(prost_types::Duration {
+                seconds: 5,
+                nanos: 0,
+            }),
+            attempt_id: None,
+        }
+    }
+
+    fn status_code(responses: &[pb::ReceiveMessageResponse]) -> i32 {
+        responses
+            .iter()
+            .find_map(|response| match &response.content {
+                Some(Content::Status(status)) => Some(status.code),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    /// The broker acks the message at offset 101 only, and forwards the one at offset 4096 to
+    /// the dead letter queue only.
+    async fn setup() -> (MockBroker, ConsumerService) {
+        let broker = MockBroker::start().await;
+        broker.handle(RequestCode::PopMessage, pop_message);
+        broker.handle(RequestCode::AckMessage, |request| {
+            let code = match request.get_property("offset").unwrap().as_str() {
+                "101" => ResponseCode::Success,
+                _ => ResponseCode::NoMessage,
+            };
+            assert_eq!("test", request.get_property("topic").unwrap());
+            Command::new_response(request, code)
+        });
+        broker.handle(RequestCode::ChangeMessageInvisibleTime, |request| {
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            response.add_property("popTime", "2000");
+            response.add_property(
+                "invisibleTime",
+                request.get_property("invisibleTime").unwrap().as_str(),
+            );
+            response.add_property("reviveQid", "1");
+            response
+        });
+        broker.handle(RequestCode::ConsumerSendMsgBack, |request| {
+            assert_eq!("group", request.get_property("group").unwrap());
+            assert_eq!("-1", request.get_property("delayLevel").unwrap());
+            assert_eq!("16", request.get_property("maxReconsumeTimes").unwrap());
+            let code = match request.get_property("offset").unwrap().as_str() {
+                "4096" => ResponseCode::Success,
+                _ => ResponseCode::SystemError,
+            };
+            Command::new_response(request, code)
+        });
+
+        let renew_service = Arc::new(RenewService::new(
+            broker.mq_client(),
+            broker.route_service(),
+        ));
+        let consumer =
+            ConsumerService::new(broker.mq_client(), broker.route_service(), renew_service);
+        (broker, consumer)
+    }
+
+    /// Collects the responses streamed for the request.
+    async fn receive_message(
+        consumer: &ConsumerService,
+        request: pb::ReceiveMessageRequest,
+    ) -> Vec<pb::ReceiveMessageResponse> {
+        // a single slot makes every response wait for the previous one to be read.
+        let (sender, receiver) = mpsc::channel(1);
+        let responses = ReceiverStream::new(receiver).map(Result::unwrap).collect();
+        let ((), responses) = tokio::join!(
+            consumer.receive_message("client", request, sender),
+            responses
+        );
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_receive_message() {
+        let (broker, consumer) = setup().await;
+
+        let responses = receive_message(&consumer, request(2, "tag-a")).await;
+        assert_eq!(4, responses.len());
+        assert_eq!(Code::Ok as i32, status_code(&responses));
+        assert!(matches!(
+            responses.last().unwrap().content,
+            Some(Content::DeliveryTimestamp(_))
+        ));
+        let Some(Content::Message(message)) = &responses[1].content else {
+            panic!("message expected");
+        };
+        let system_properties = message.system_properties.as_ref().unwrap();
+        assert_eq!("id-1", system_properties.message_id);
+        assert_eq!(Some("tag-a"), system_properties.tag.as_deref());
+        assert_eq!(
+            Some("100 1000 30000 0 0 broker-a 1 101 0"),
+            system_properties.receipt_handle.as_deref()
+        );
+        assert_eq!(Some(1), system_properties.delivery_attempt);
+        assert_eq!("value", message.user_properties["custom"]);
+        assert!(!message.user_properties.contains_key(PROPERTY_TAGS));
+
+        let responses = receive_message(&consumer, request(2, "none")).await;
+        assert_eq!(Code::MessageNotFound as i32, status_code(&responses));
+
+        let responses = receive_message(&consumer, request(64, "tag-a")).await;
+        assert_eq!(Code::BadRequest as i32, status_code(&responses));
+
+        // messages received with auto renew are tracked until acked.
+        let mut auto_renew = request(2, "tag-a");
+        auto_renew.auto_renew = true;
+        auto_renew.invisible_duration = None;
+        let responses = receive_message(&consumer, auto_renew.clone()).await;
+        assert_eq!(Code::Ok as i32, status_code(&responses));
+        let response = consumer
+            .ack_message(
+                "client",
+                pb::AckMessageRequest {
+                    group: resource("group"),
+                    topic: resource("test"),
+                    entries: vec![pb::AckMessageEntry {
+                        message_id: "id-1".to_string(),
+                        receipt_handle: "100 1000 30000 0 0 broker-a 1 101 0".to_string(),
+                    }],
+                },
+            )
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!(1, consumer.renew_service.remove_client("client"));
+
+        // messages left unread by a gone client are no longer renewed.
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        consumer.receive_message("client", auto_renew, sender).await;
+        assert_eq!(0, consumer.renew_service.remove_client("client"));
+
+        let mut illegal = request(1, "tag-a");
+        illegal.invisible_duration = None;
+        let responses = receive_message(&consumer, illegal).await;
+        assert_eq!(Code::IllegalInvisibleTime as i32, status_code(&responses));
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_receive_retry_message() {
+        let (broker, consumer) = setup().await;
+        // the message is popped from queue 0 of the retry topic at offset 7.
+        broker.handle(RequestCode::PopMessage, |request| {
+            let mut message = MessageExt {
+                topic: "test".to_string(),
+                queue_id: 0,
+                queue_offset: 7,
+                commit_log_offset: 4096,
+                reconsume_times: 1,
+                body: b"hello".to_vec(),
+                ..Default::default()
+            };
+            message.properties.insert(
+                PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX.to_string(),
+                "id-1".to_string(),
+            );
+            message.properties.insert(
+                PROPERTY_POP_CK.to_string(),
+                "5 1000 30000 0 1 broker-a 0 7".to_string(),
+            );
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            response.add_property("popTime", "1000");
+            response.add_property("invisibleTime", "30000");
+            response.add_property("reviveQid", "0");
+            response.add_property("restNum", "0");
+            response.add_property("startOffsetInfo", "0 1 100;1 0 5");
+            response.set_body(encode_message(&message));
+            response
+        });
+        broker.handle(RequestCode::AckMessage, |request| {
+            assert_eq!("%RETRY%group_test", request.get_property("topic").unwrap());
+            assert_eq!("0", request.get_property("queueId").unwrap());
+            assert_eq!("7", request.get_property("offset").unwrap());
+            Command::new_response(request, ResponseCode::Success)
+        });
+
+        let responses = receive_message(&consumer, request(1, "tag-a")).await;
+        assert_eq!(Code::Ok as i32, status_code(&responses));
+        let Some(Content::Message(message)) = &responses[0].content else {
+            panic!("message expected");
+        };
+        let receipt_handle = message
+            .system_properties
+            .as_ref()
+            .unwrap()
+            .receipt_handle
+            .clone()
+            .unwrap();
+        assert_eq!("5 1000 30000 0 1 broker-a 0 7 4096", receipt_handle);
+        assert!(!message.user_properties.contains_key(PROPERTY_POP_CK));
+
+        let response = consumer
+            .ack_message(
+                "client",
+                pb::AckMessageRequest {
+                    group: resource("group"),
+                    topic: resource("test"),
+                    entries: vec![pb::AckMessageEntry {
+                        message_id: "id-1".to_string(),
+                        receipt_handle,
+                    }],
+                },
+            )
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_ack_and_change_invisible_duration() {
+        let (broker, consumer) = setup().await;
+
+        let entry = |message_id: &str, receipt_handle: &str| pb::AckMessageEntry {
+            message_id: message_id.to_string(),
+            receipt_handle: receipt_handle.to_string(),
+        };
+        let response = consumer
+            .ack_message(
+                "client",
+                pb::AckMessageRequest {
+                    group: resource("group"),
+                    topic: resource("test"),
+                    entries: vec![
+                        entry("id-1", "100 1000 30000 0 0 broker-a 1 101"),
+                        entry("id-2", "100 1000 30000 0 0 broker-a 1 102"),
+                        entry("id-3", "invalid"),
+                    ],
+                },
+            )
+            .await;
+        assert_eq!(Code::MultipleResults as i32, response.status.unwrap().code);
+        let codes: Vec<i32> = response
+            .entries
+            .iter()
+            .map(|entry| entry.status.as_ref().unwrap().code)
+            .collect();
+        assert_eq!(
+            vec![
+                Code::Ok as i32,
+                Code::InternalServerError as i32,
+                Code::InvalidReceiptHandle as i32
+            ],
+            codes
+        );
+
+        let change = |receipt_handle: String| pb::ChangeInvisibleDurationRequest {
+            group: resource("group"),
+            topic: resource("test"),
+            receipt_handle,
+            invisible_duration: Some(prost_types::Duration {
+                seconds: 5,
+                nanos: 0,
+            }),
+            message_id: "id-1".to_string(),
+        };
+        let receipt_handle = format!("100 {} 30000 0 0 broker-a 1 101 4096", now_millis());
+        let response = consumer
+            .change_invisible_duration("client", change(receipt_handle))
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!(
+            "101 2000 5000 1 0 broker-a 1 101 4096",
+            response.receipt_handle
+        );
+
+        let response = consumer
+            .change_invisible_duration(
+                "client",
+                change("100 1000 30000 0 0 broker-a 1 101".to_string()),
+            )
+            .await;
+        assert_eq!(
+            Code::InvalidReceiptHandle as i32,
+            response.status.unwrap().code
+        );
+
+        let response = consumer
+            .ack_message(
+                "client",
+                pb::AckMessageRequest {
+                    group: None,
+                    topic: resource("test"),
+                    entries: vec![],
+                },
+            )
+            .await;
+        assert_eq!(
+            Code::IllegalConsumerGroup as i32,
+            response.status.unwrap().code
+        );
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_forward_message_to_dead_letter_queue() {
+        let (broker, consumer) = setup().await;
+
+        let forward = |receipt_handle: &str| pb::ForwardMessageToDeadLetterQueueRequest {
+            group: resource("group"),
+            topic: resource("test"),
+            receipt_handle: receipt_handle.to_string(),
+            message_id: "id-1".to_string(),
+            delivery_attempt: 17,
+            max_delivery_attempts: 16,
+        };
+        let code =
+            |response: pb::ForwardMessageToDeadLetterQueueResponse| response.status.unwrap().code;
+        assert_eq!(
+            Code::Ok as i32,
+            code(
+                consumer
+                    .forward_message_to_dead_letter_queue(
+                        "client",
+                        forward("100 1000 30000 0 0 broker-a 1 101 4096"),
+                    )
+                    .await
+            )
+        );
+        // the original message is not acked if the broker fails to store it to the queue.
+        assert_eq!(
+            Code::InternalServerError as i32,
+            code(
+                consumer
+                    .forward_message_to_dead_letter_queue(
+                        "client",
+                        forward("100 1000 30000 0 0 broker-a 1 101 2048"),
+                    )
+                    .await
+            )
+        );
+        assert_eq!(
+            Code::InvalidReceiptHandle as i32,
+            code(
+                consumer
+                    .forward_message_to_dead_letter_queue(
+                        "client",
+                        forward("100 1000 30000 0 0 broker-a 1 101"),
+                    )
+                    .await
+            )
+        );
+
+        broker.shutdown().await;
+    }
+
+    #[test]
+    fn test_dead_letter_queue_message() {
+        let mut message = MessageExt {
+            topic: format!("{}group", DLQ_GROUP_TOPIC_PREFIX),
+            ..Default::default()
+        };
+        message
+            .properties
+            .insert(PROPERTY_DLQ_ORIGIN_TOPIC.to_string(), "test".to_string());
+        message.properties.insert(
+            PROPERTY_DLQ_ORIGIN_MESSAGE_ID.to_string(),
+            "id-1".to_string(),
+        );
+        let topic = resource(&message.topic.clone()).unwrap();
+        let message = to_pb_message(message, &topic, None, None);
+        assert_eq!(
+            Some(pb::DeadLetterQueue {
+                topic: "test".to_string(),
+                message_id: "id-1".to_string(),
+            }),
+            message.system_properties.unwrap().dead_letter_queue
+        );
+        assert!(message.user_properties.is_empty());
+
+        let message = to_pb_message(MessageExt::default(), &topic, None, None);
+        assert_eq!(None, message.system_properties.unwrap().dead_letter_queue);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::pb::{self, retry_policy::Strategy};
+
+const DEFAULT_MAX_RETRY_TIMES: i32 = 16;
+const DEFAULT_RECEIVE_BATCH_SIZE: i32 = 32;
+const DEFAULT_LONG_POLLING_TIMEOUT_MS: u64 = 20_000;
+/// The delays between redeliveries of retry messages: brokers start at delay level 3 plus the
+/// reconsume times, i.e. from 10s up to 2h.
+const DEFAULT_RETRY_DELAYS_MS: [u64; 16] = [
+    10_000, 30_000, 60_000, 120_000, 180_000, 240_000, 300_000, 360_000, 420_000, 480_000, 540_000,
+    600_000, 1_200_000, 1_800_000, 3_600_000, 7_200_000,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BackoffPolicy {
+    Exponential {
+        initial_ms: u64,
+        max_ms: u64,
+        multiplier: f32,
+    },
+    Customized {
+        next_ms: Vec<u64>,
+    },
+}
+
+/// How the queues of a topic are assigned to the push consumers of a group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AllocateStrategy {
+    /// Every consumer pops from all the readable queues.
+    #[default]
+    Pop,
+    /// The queues are divided evenly among the consumers.
+    Average,
+    /// The brokers are dealt to the consumers in turn, each consumer pops from all the queues
+    /// of its brokers.
+    RoundRobinByBroker,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GroupConfig {
+    name: String,
+    max_retry_times: i32,
+    fifo: bool,
+    receive_batch_size: i32,
+    long_polling_timeout_ms: u64,
+    backoff_policy: BackoffPolicy,
+    allocate_strategy: AllocateStrategy,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            max_retry_times: DEFAULT_MAX_RETRY_TIMES,
+            fifo: false,
+            receive_batch_size: DEFAULT_RECEIVE_BATCH_SIZE,
+            long_polling_timeout_ms: DEFAULT_LONG_POLLING_TIMEOUT_MS,
+            backoff_policy: BackoffPolicy::Customized {
+                next_ms: DEFAULT_RETRY_DELAYS_MS.to_vec(),
+            },
+            allocate_strategy: AllocateStrategy::default(),
+        }
+    }
+}
+
+impl GroupConfig {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn max_retry_times(&self) -> i32 {
+        self.max_retry_times
+    }
+
+    pub fn fifo(&self) -> bool {
+        self.fifo
+    }
+
+    pub fn receive_batch_size(&self) -> i32 {
+        self.receive_batch_size
+    }
+
+    pub fn long_polling_timeout(&self) -> Duration {
+        Duration::from_millis(self.long_polling_timeout_ms)
+    }
+
+    pub fn backoff_policy(&self) -> &BackoffPolicy {
+        &self.backoff_policy
+    }
+
+    pub fn allocate_strategy(&self) -> AllocateStrategy {
+        self.allocate_strategy
+    }
+
+    /// The retry policy clients of the group follow, a message is delivered at most
+    /// `max_retry_times + 1` times.
+    pub fn retry_policy(&self) -> pb::RetryPolicy {
+        let duration = |ms: u64| prost_types::Duration::try_from(Duration::from_millis(ms)).ok();
+        let strategy = match &self.backoff_policy {
+            BackoffPolicy::Exponential {
+                initial_ms,
+                max_ms,
+                multiplier,
+            } => Strategy::ExponentialBackoff(pb::ExponentialBackoff {
+                initial: duration(*initial_ms),
+                max: duration(*max_ms),
+                multiplier: *multiplier,
+            }),
+            BackoffPolicy::Customized { next_ms } => {
+                Strategy::CustomizedBackoff(pb::CustomizedBackoff {
+                    next: next_ms.iter().filter_map(|ms| duration(*ms)).collect(),
+                })
+            }
+        };
+        pb::RetryPolicy {
+            max_attempts: self.max_retry_times + 1,
+            strategy: Some(strategy),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GroupConfigManager {
+    path: String,
+    group_config_table: HashMap<String, GroupConfig>,
+    backup_path: String,
+}
+
+impl GroupConfigManager {
+    pub fn new(path: &str) -> Self {
+        let group_config_path = path.to_string() + "/group_config.json";
+        let backup_path = group_config_path.clone() + ".bak";
+        Self {
+            group_config_table: HashMap::new(),
+            path: group_config_path,
+            backup_path,
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Path::new(self.path.as_str());
+        let result = fs::read_to_string(path);
+        if let Ok(data) = result {
+            let group_config_table: HashMap<String, GroupConfig> = serde_json::from_str(&data)?;
+            self.group_config_table = group_config_table;
+        } else {
+            fs::write(path, "{}")?;
+        }
+        Ok(())
+    }
+
+    pub fn get_group_config(&self, group_name: &str) -> Option<&GroupConfig> {
+        self.group_config_table.get(group_name)
+    }
+
+    pub fn add_or_update_group(
+        &mut self,
+        config: GroupConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let group_name = config.name().to_string();
+        if group_name.is_empty() {
+            return Err("group name is empty".into());
+        }
+        self.group_config_table.insert(group_name, config);
+        self.persist()
+    }
+
+    pub fn delete_group(&mut self, group_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let result = self.group_config_table.remove(group_name);
+        if result.is_some() {
+            return self.persist();
+        }
+        Ok(())
+    }
+
+    fn persist(&self) -> Result<(), Box<dyn std::error::Error>> {
+        fs::copy(self.path.as_str(), self.backup_path.as_str())?;
+        let data = serde_json::to_string(&self.group_config_table)?;
+        fs::write(self.path.as_str(), data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_load_and_update_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = json!({
+            "group1": {
+                "name": "group1",
+                "fifo": true,
+                "allocate_strategy": "AVERAGE",
+                "backoff_policy": {
+                    "type": "EXPONENTIAL",
+                    "initial_ms": 1000,
+                    "max_ms": 60000,
+                    "multiplier": 2.0
+                }
+            }
+        });
+        fs::write(dir.path().join("group_config.json"), data.to_string()).unwrap();
+        let mut group_config_manager = GroupConfigManager::new(dir.path().to_str().unwrap());
+        group_config_manager.load().unwrap();
+
+        // missing fields fall back to the defaults.
+        let group_config = group_config_manager.get_group_config("group1").unwrap();
+        assert!(group_config.fifo());
+        assert_eq!(AllocateStrategy::Average, group_config.allocate_strategy());
+        assert_eq!(DEFAULT_MAX_RETRY_TIMES, group_config.max_retry_times());
+        assert_eq!(Duration::from_secs(20), group_config.long_polling_timeout());
+        let retry_policy = group_config.retry_policy();
+        assert_eq!(17, retry_policy.max_attempts);
+        assert!(matches!(
+            retry_policy.strategy,
+            Some(Strategy::ExponentialBackoff(_))
+        ));
+
+        group_config_manager
+            .add_or_update_group(GroupConfig::new("group2".to_string()))
+            .unwrap();
+        group_config_manager.delete_group("group1").unwrap();
+        assert!(group_config_manager.get_group_config("group1").is_none());
+        let Some(Strategy::CustomizedBackoff(backoff)) = group_config_manager
+            .get_group_config("group2")
+            .unwrap()
+            .retry_policy()
+            .strategy
+        else {
+            panic!("customized backoff expected");
+        };
+        assert_eq!(16, backoff.next.len());
+        assert_eq!(10, backoff.next[0].seconds);
+        assert_eq!(7200, backoff.next[15].seconds);
+    }
+}
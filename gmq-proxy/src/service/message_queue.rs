@@ -1,3 +1,28 @@
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct MessageQueue {
+    topic: String,
+    broker_name: String,
+    queue_id: i32,
+}
 
-}
\ No newline at end of file
+impl MessageQueue {
+    pub fn new(topic: &str, broker_name: &str, queue_id: i32) -> Self {
+        Self {
+            topic: topic.to_string(),
+            broker_name: broker_name.to_string(),
+            queue_id,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn broker_name(&self) -> &str {
+        &self.broker_name
+    }
+
+    pub fn queue_id(&self) -> i32 {
+        self.queue_id
+    }
+}
@@ -0,0 +1,156 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use gmq_remoting::{
+    common::{
+        code::{RequestCode, ResponseCode},
+        command::Command,
+    },
+    processor::RequestProcessor,
+    server::RemotingServer,
+    util,
+};
+use parking_lot::RwLock;
+use serde_json::json;
+
+use super::{
+    group_config::GroupConfigManager,
+    renew::RenewService,
+    route::RouteService,
+    session::{ClientSettingManager, SettingPolicy},
+    topic_config::TopicConfigManager,
+};
+use crate::{pb, remoting::client::MQClient};
+
+/// The name of the broker played by the mock.
+pub(crate) const BROKER_NAME: &str = "broker-a";
+
+/**
+ * MockBroker plays both the NameServer and the broker `broker-a` on a single remoting server.
+ * Every topic is routed to 2 queues of the broker, the other requests are answered by the
+ * handlers registered by code.
+ */
+pub(crate) struct MockBroker {
+    server: RemotingServer,
+    addr: String,
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+}
+
+impl MockBroker {
+    /// Starts the server and a client taking it as the NameServer.
+    pub(crate) async fn start() -> Self {
+        let server = RemotingServer::new("127.0.0.1:0");
+        let addr = server.start().await.unwrap().to_string();
+        let route = json!({
+            "queueDatas": [
+                {"brokerName": BROKER_NAME, "readQueueNums": 2, "writeQueueNums": 2, "perm": 6}
+            ],
+            "brokerDatas": [
+                {"cluster": "c1", "brokerName": BROKER_NAME, "brokerAddrs": {"0": addr}}
+            ]
+        });
+
+        let mut mq_client = MQClient::new(&addr);
+        mq_client.start().await.unwrap();
+        let mq_client = Arc::new(mq_client);
+        let route_service = Arc::new(RouteService::new(
+            Arc::clone(&mq_client),
+            Arc::new(RwLock::new(TopicConfigManager::new("."))),
+        ));
+        let broker = Self {
+            server,
+            addr,
+            mq_client,
+            route_service,
+        };
+        broker.route(route);
+        broker
+    }
+
+    /// The address of the server, which routes may point other brokers to.
+    pub(crate) fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub(crate) fn mq_client(&self) -> Arc<MQClient> {
+        Arc::clone(&self.mq_client)
+    }
+
+    pub(crate) fn route_service(&self) -> Arc<RouteService> {
+        Arc::clone(&self.route_service)
+    }
+
+    /// Answers the route queries of every topic with the route.
+    pub(crate) fn route(&self, route: serde_json::Value) {
+        self.handle(RequestCode::GetTopicRouteInfo, move |request| {
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            response.set_body(route.to_string().into_bytes());
+            response
+        });
+    }
+
+    /// Answers the requests of the code with the handler, replacing the previous one.
+    pub(crate) fn handle<F>(&self, code: RequestCode, handler: F)
+    where
+        F: Fn(&Command) -> Command + Send + Sync + 'static,
+    {
+        self.handle_slowly(code, Duration::ZERO, handler);
+    }
+
+    /// Like `handle`, but holds the responses for `delay` after the handler runs.
+    pub(crate) fn handle_slowly<F>(&self, code: RequestCode, delay: Duration, handler: F)
+    where
+        F: Fn(&Command) -> Command + Send + Sync + 'static,
+    {
+        self.server
+            .register_processor(code, Arc::new(Handler { handler, delay }));
+    }
+
+    pub(crate) async fn shutdown(&self) {
+        self.route_service.shutdown().await;
+        self.mq_client.shutdown().await;
+        self.server.shutdown().await;
+    }
+}
+
+struct Handler<F> {
+    handler: F,
+    delay: Duration,
+}
+
+#[async_trait]
+impl<F> RequestProcessor for Handler<F>
+where
+    F: Fn(&Command) -> Command + Send + Sync + 'static,
+{
+    async fn process(&self, request: &Command) -> Result<Option<Command>, util::Error> {
+        let response = (self.handler)(request);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        Ok(Some(response))
+    }
+}
+
+pub(crate) fn resource(name: &str) -> Option<pb::Resource> {
+    Some(pb::Resource {
+        resource_namespace: "".to_string(),
+        name: name.to_string(),
+    })
+}
+
+/// Creates a session manager for the tests that need no broker, nothing is sent to the
+/// NameServer it is given.
+pub(crate) fn setting_manager() -> ClientSettingManager {
+    let mq_client = Arc::new(MQClient::new("127.0.0.1:9876"));
+    let route_service = Arc::new(RouteService::new(
+        Arc::clone(&mq_client),
+        Arc::new(RwLock::new(TopicConfigManager::new("."))),
+    ));
+    ClientSettingManager::new(
+        Arc::new(RenewService::new(mq_client, route_service)),
+        Arc::new(RwLock::new(GroupConfigManager::new("."))),
+        SettingPolicy::default(),
+    )
+}
@@ -1,4 +1,17 @@
 pub mod server;
 pub mod topic_config;
 pub mod route;
-pub mod message_queue;
\ No newline at end of file
+pub mod message_queue;
+pub mod producer;
+pub mod consumer;
+pub mod receipt_handle;
+pub mod renew;
+pub mod session;
+pub mod group_config;
+pub mod admin;
+pub mod transaction;
+pub mod pull;
+pub mod assignment;
+pub mod proxy_config;
+#[cfg(test)]
+mod mock_broker;
\ No newline at end of file
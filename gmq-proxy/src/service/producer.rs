@@ -0,0 +1,662 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::anyhow;
+
+use super::{
+    message_queue::MessageQueue,
+    route::RouteService,
+    session::SettingPolicy,
+    transaction::{TransactionData, TransactionService},
+};
+use crate::{
+    common::{batch_status, now_millis, ok_status, status, timestamp_to_millis, Error},
+    pb::{self, Code, Status},
+    remoting::{
+        client::MQClient,
+        header::{
+            is_system_property, is_valid_property, SendMessageRequestHeader, KEY_SEPARATOR,
+            PROPERTY_KEYS, PROPERTY_PRODUCER_GROUP, PROPERTY_SHARDING_KEY, PROPERTY_TAGS,
+            PROPERTY_TIMER_DELIVER_MS, PROPERTY_TRACE_CONTEXT, PROPERTY_TRANSACTION_PREPARED,
+            PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX,
+        },
+        message::{
+            commit_log_offset_of, COMPRESSED_FLAG, TRANSACTION_NOT_TYPE, TRANSACTION_PREPARED_TYPE,
+        },
+    },
+};
+
+pub const PRODUCER_GROUP: &str = "PROXY_PRODUCER_GROUP";
+
+const MAX_TOPIC_LENGTH: usize = 127;
+const MAX_MESSAGE_GROUP_LENGTH: usize = 64;
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/**
+ * ProducerService forwards the messages sent by gRPC clients to the master brokers of the
+ * queues selected from the topic routes. Transaction messages are sent as half messages.
+ */
+#[derive(Debug)]
+pub struct ProducerService {
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+    transaction_service: Arc<TransactionService>,
+    /// The limits the messages are checked against before being sent.
+    policy: SettingPolicy,
+    queue_index: AtomicUsize,
+}
+
+impl ProducerService {
+    pub fn new(
+        mq_client: Arc<MQClient>,
+        route_service: Arc<RouteService>,
+        transaction_service: Arc<TransactionService>,
+        policy: SettingPolicy,
+    ) -> Self {
+        Self {
+            mq_client,
+            route_service,
+            transaction_service,
+            policy,
+            queue_index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sends the messages one by one, the result of every message is reported in its own entry.
+    pub async fn send_message(
+        &self,
+        client_id: &str,
+        messages: Vec<pb::Message>,
+    ) -> pb::SendMessageResponse {
+        if messages.is_empty() {
+            return pb::SendMessageResponse {
+                status: Some(status(Code::BadRequest, "no message to send")),
+                entries: vec![],
+            };
+        }
+
+        let mut entries = Vec::with_capacity(messages.len());
+        for message in messages {
+            let entry = match self.send(client_id, message).await {
+                Ok(entry) => entry,
+                Err(status) => pb::SendResultEntry {
+                    status: Some(status),
+                    ..Default::default()
+                },
+            };
+            entries.push(entry);
+        }
+
+        let status = batch_status(entries.iter().filter_map(|entry| entry.status.as_ref()));
+        pb::SendMessageResponse {
+            status: Some(status),
+            entries,
+        }
+    }
+
+    async fn send(
+        &self,
+        client_id: &str,
+        message: pb::Message,
+    ) -> Result<pb::SendResultEntry, Status> {
+        let topic_type = message
+            .topic
+            .as_ref()
+            .map(|t| self.route_service.accept_message_type(&t.name))
+            .unwrap_or(pb::MessageType::Normal);
+        validate_message(&message, &self.policy, topic_type)?;
+        let topic = message.topic.as_ref().map(|t| t.name.clone()).unwrap();
+        let system_properties = message.system_properties.clone().unwrap();
+        let transactional = system_properties.message_type == pb::MessageType::Transaction as i32;
+
+        let route = self
+            .route_service
+            .get_topic_route(&topic)
+            .await
+            .map_err(|e| e.to_status())?;
+        let queue = self
+            .select_queue(
+                &route.writable_queues(),
+                system_properties.message_group.as_deref(),
+            )
+            .ok_or_else(|| {
+                status(
+                    Code::Forbidden,
+                    format!("no writable queue for topic {}", topic),
+                )
+            })?;
+        let addr = route
+            .master_addr(queue.broker_name())
+            .map(|addr| addr.to_string())
+            .ok_or_else(|| {
+                status(
+                    Code::InternalServerError,
+                    format!("no master of broker {}", queue.broker_name()),
+                )
+            })?;
+
+        let born_timestamp = system_properties
+            .born_timestamp
+            .as_ref()
+            .map(timestamp_to_millis)
+            .unwrap_or_else(now_millis);
+        let mut properties = build_properties(&message, &system_properties);
+        if transactional {
+            properties.insert(PROPERTY_TRANSACTION_PREPARED.to_string(), true.to_string());
+            properties.insert(
+                PROPERTY_PRODUCER_GROUP.to_string(),
+                PRODUCER_GROUP.to_string(),
+            );
+        }
+        let mut sys_flag = if transactional {
+            TRANSACTION_PREPARED_TYPE
+        } else {
+            TRANSACTION_NOT_TYPE
+        };
+        // gzip bodies are stored as they are, consumers inflate them by the flag.
+        if system_properties.body_encoding == pb::Encoding::Gzip as i32 {
+            sys_flag |= COMPRESSED_FLAG;
+        }
+        let header = SendMessageRequestHeader {
+            producer_group: PRODUCER_GROUP.to_string(),
+            topic: topic.clone(),
+            queue_id: queue.queue_id(),
+            sys_flag,
+            born_timestamp,
+            properties,
+            broker_name: queue.broker_name().to_string(),
+            ..Default::default()
+        };
+        let result = self
+            .mq_client
+            .send_message(&addr, header, message.body)
+            .await
+            .map_err(|e| e.to_status())?;
+
+        // clients end transactions by the transaction id, which defaults to the message id.
+        let transaction_id = result
+            .transaction_id
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| system_properties.message_id.clone());
+        if transactional {
+            let commit_log_offset = commit_log_offset_of(&result.msg_id).ok_or_else(|| {
+                Error::InternalError(anyhow!("malformed offset message id {}", result.msg_id))
+                    .to_status()
+            })?;
+            self.transaction_service.add(TransactionData {
+                client_id: client_id.to_string(),
+                producer_group: PRODUCER_GROUP.to_string(),
+                topic,
+                broker_name: queue.broker_name().to_string(),
+                message_id: system_properties.message_id.clone(),
+                transaction_id: transaction_id.clone(),
+                tran_state_table_offset: result.queue_offset,
+                commit_log_offset,
+                created_at: Instant::now(),
+            });
+        }
+        Ok(pb::SendResultEntry {
+            status: Some(ok_status()),
+            message_id: system_properties.message_id,
+            transaction_id: if transactional {
+                transaction_id
+            } else {
+                String::new()
+            },
+            offset: result.queue_offset,
+        })
+    }
+
+    /// Messages of the same group always go to the same queue to keep their order, the others
+    /// are spread over the queues in a round-robin way.
+    fn select_queue(
+        &self,
+        queues: &[MessageQueue],
+        message_group: Option<&str>,
+    ) -> Option<MessageQueue> {
+        if queues.is_empty() {
+            return None;
+        }
+        let index = match message_group {
+            Some(group) => {
+                let mut hasher = DefaultHasher::new();
+                group.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            None => self.queue_index.fetch_add(1, Ordering::Relaxed),
+        };
+        Some(queues[index % queues.len()].clone())
+    }
+}
+
+/// Checks the message against the policy, `topic_type` is the type of messages its topic accepts.
+fn validate_message(
+    message: &pb::Message,
+    policy: &SettingPolicy,
+    topic_type: pb::MessageType,
+) -> Result<(), Status> {
+    let topic = message
+        .topic
+        .as_ref()
+        .map(|t| t.name.as_str())
+        .unwrap_or_default();
+    if topic.is_empty()
+        || topic.len() > MAX_TOPIC_LENGTH
+        || !topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "%|_-".contains(c))
+    {
+        return Err(status(
+            Code::IllegalTopic,
+            format!("topic {:?} is illegal", topic),
+        ));
+    }
+
+    let system_properties = message
+        .system_properties
+        .as_ref()
+        .ok_or_else(|| status(Code::BadRequest, "system properties are required"))?;
+    if system_properties.message_id.is_empty() {
+        return Err(status(Code::IllegalMessageId, "message id is required"));
+    }
+    if let Some(tag) = &system_properties.tag {
+        if tag.trim().is_empty() || tag.contains('|') || !is_valid_property(tag) {
+            return Err(status(
+                Code::IllegalMessageTag,
+                format!("tag {:?} is illegal", tag),
+            ));
+        }
+    }
+    for key in &system_properties.keys {
+        if key.trim().is_empty() || key.contains(KEY_SEPARATOR) || !is_valid_property(key) {
+            return Err(status(
+                Code::IllegalMessageKey,
+                format!("key {:?} is illegal", key),
+            ));
+        }
+    }
+    if let Some(group) = &system_properties.message_group {
+        if group.trim().is_empty()
+            || group.len() > MAX_MESSAGE_GROUP_LENGTH
+            || !is_valid_property(group)
+        {
+            return Err(status(
+                Code::IllegalMessageGroup,
+                format!("message group {:?} is illegal", group),
+            ));
+        }
+        if system_properties.delivery_timestamp.is_some() {
+            return Err(status(
+                Code::MessagePropertyConflictWithType,
+                "message group and delivery timestamp can not be set together",
+            ));
+        }
+    }
+    if policy.validate_message_type && system_properties.message_type != topic_type as i32 {
+        return Err(status(
+            Code::MessagePropertyConflictWithType,
+            format!(
+                "message type {:?} does not match the topic accepting {:?}",
+                system_properties.message_type(),
+                topic_type
+            ),
+        ));
+    }
+    if system_properties.message_type == pb::MessageType::Transaction as i32
+        && system_properties.delivery_timestamp.is_some()
+    {
+        return Err(status(
+            Code::MessagePropertyConflictWithType,
+            "transaction message can not be delayed",
+        ));
+    }
+    for (key, value) in &message.user_properties {
+        if key.is_empty()
+            || is_system_property(key)
+            || !is_valid_property(key)
+            || !is_valid_property(value)
+        {
+            return Err(status(
+                Code::IllegalMessagePropertyKey,
+                format!("property {:?} is illegal", key),
+            ));
+        }
+    }
+    if message.body.len() > policy.max_body_size.max(0) as usize {
+        return Err(status(
+            Code::MessageBodyTooLarge,
+            format!("message body exceeds {} bytes", policy.max_body_size),
+        ));
+    }
+    Ok(())
+}
+
+fn build_properties(
+    message: &pb::Message,
+    system_properties: &pb::SystemProperties,
+) -> HashMap<String, String> {
+    let mut properties = message.user_properties.clone();
+    if let Some(tag) = &system_properties.tag {
+        properties.insert(PROPERTY_TAGS.to_string(), tag.clone());
+    }
+    if !system_properties.keys.is_empty() {
+        properties.insert(
+            PROPERTY_KEYS.to_string(),
+            system_properties.keys.join(KEY_SEPARATOR),
+        );
+    }
+    properties.insert(
+        PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX.to_string(),
+        system_properties.message_id.clone(),
+    );
+    if let Some(delivery_timestamp) = &system_properties.delivery_timestamp {
+        properties.insert(
+            PROPERTY_TIMER_DELIVER_MS.to_string(),
+            timestamp_to_millis(delivery_timestamp).to_string(),
+        );
+    }
+    if let Some(group) = &system_properties.message_group {
+        properties.insert(PROPERTY_SHARDING_KEY.to_string(), group.clone());
+    }
+    if let Some(trace_context) = &system_properties.trace_context {
+        properties.insert(PROPERTY_TRACE_CONTEXT.to_string(), trace_context.clone());
+    }
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use gmq_remoting::common::{
+        code::{RequestCode, ResponseCode},
+        command::Command,
+    };
+    use parking_lot::RwLock;
+
+    use super::*;
+    use crate::{
+        remoting::{
+            header::string_to_properties,
+            message::{TRANSACTION_COMMIT_TYPE, TRANSACTION_ROLLBACK_TYPE},
+        },
+        service::{
+            group_config::GroupConfigManager,
+            mock_broker::MockBroker,
+            renew::RenewService,
+            session::{ClientSettingManager, SettingPolicy},
+        },
+    };
+
+    fn message(topic: &str, message_id: &str) -> pb::Message {
+        pb::Message {
+            topic: Some(pb::Resource {
+                resource_namespace: "".to_string(),
+                name: topic.to_string(),
+            }),
+            user_properties: HashMap::new(),
+            system_properties: Some(pb::SystemProperties {
+                message_id: message_id.to_string(),
+                message_type: pb::MessageType::Normal as i32,
+                tag: Some("tag-a".to_string()),
+                keys: vec!["k1".to_string(), "k2".to_string()],
+                ..Default::default()
+            }),
+            body: b"hello".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_validate_message() {
+        let policy = SettingPolicy::default();
+        let normal = pb::MessageType::Normal;
+        assert!(validate_message(&message("test", "id"), &policy, normal).is_ok());
+
+        let code = |message: pb::Message| {
+            validate_message(&message, &policy, normal)
+                .unwrap_err()
+                .code
+        };
+        assert_eq!(Code::IllegalTopic as i32, code(message("a b", "id")));
+        assert_eq!(Code::IllegalMessageId as i32, code(message("test", "")));
+
+        let mut m = message("test", "id");
+        m.system_properties.as_mut().unwrap().tag = Some("a|b".to_string());
+        assert_eq!(Code::IllegalMessageTag as i32, code(m));
+
+        let mut m = message("test", "id");
+        m.user_properties
+            .insert(PROPERTY_TAGS.to_string(), "tag".to_string());
+        assert_eq!(Code::IllegalMessagePropertyKey as i32, code(m));
+
+        let mut m = message("test", "id");
+        let system_properties = m.system_properties.as_mut().unwrap();
+        system_properties.message_group = Some("group".to_string());
+        system_properties.delivery_timestamp = Some(prost_types::Timestamp::default());
+        assert_eq!(Code::MessagePropertyConflictWithType as i32, code(m));
+
+        let fifo = pb::MessageType::Fifo;
+        let status = validate_message(&message("test", "id"), &policy, fifo).unwrap_err();
+        assert_eq!(Code::MessagePropertyConflictWithType as i32, status.code);
+        let unchecked = SettingPolicy {
+            validate_message_type: false,
+            ..Default::default()
+        };
+        assert!(validate_message(&message("test", "id"), &unchecked, fifo).is_ok());
+
+        let small = SettingPolicy {
+            max_body_size: 4,
+            ..Default::default()
+        };
+        let status = validate_message(&message("test", "id"), &small, normal).unwrap_err();
+        assert_eq!(Code::MessageBodyTooLarge as i32, status.code);
+    }
+
+    #[test]
+    fn test_build_properties() {
+        let mut m = message("test", "id");
+        m.user_properties
+            .insert("custom".to_string(), "value".to_string());
+        let system_properties = m.system_properties.as_mut().unwrap();
+        system_properties.delivery_timestamp = Some(prost_types::Timestamp {
+            seconds: 1,
+            nanos: 2_000_000,
+        });
+        let system_properties = system_properties.clone();
+        let properties = build_properties(&m, &system_properties);
+        assert_eq!("tag-a", properties[PROPERTY_TAGS]);
+        assert_eq!("k1 k2", properties[PROPERTY_KEYS]);
+        assert_eq!("id", properties[PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX]);
+        assert_eq!("1002", properties[PROPERTY_TIMER_DELIVER_MS]);
+        assert_eq!("value", properties["custom"]);
+    }
+
+    /// The requests the broker receives.
+    #[derive(Default)]
+    struct Recorded {
+        ended: parking_lot::Mutex<Vec<Command>>,
+        sys_flags: parking_lot::Mutex<Vec<i32>>,
+    }
+
+    /// The broker rejects the messages tagged with `reject`.
+    async fn setup() -> (
+        MockBroker,
+        Arc<Recorded>,
+        Arc<TransactionService>,
+        ProducerService,
+    ) {
+        let broker = MockBroker::start().await;
+        let recorded = Arc::new(Recorded::default());
+        let sent = Arc::clone(&recorded);
+        broker.handle(RequestCode::SendMessageV2, move |request| {
+            let properties = string_to_properties(request.get_property("i").unwrap());
+            let transactional = properties.contains_key(PROPERTY_TRANSACTION_PREPARED);
+            let sys_flag: i32 = request.get_property("f").unwrap().parse().unwrap();
+            assert_eq!(
+                transactional,
+                sys_flag & TRANSACTION_ROLLBACK_TYPE == TRANSACTION_PREPARED_TYPE
+            );
+            sent.sys_flags.lock().push(sys_flag);
+            if properties[PROPERTY_TAGS] == "reject" {
+                let mut response = Command::new_response(request, ResponseCode::NoPermission);
+                response.set_remark("no permission");
+                return response;
+            }
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            response.add_property("msgId", "7F00000100002A9F0000000000000400");
+            response.add_property("queueId", request.get_property("e").unwrap().as_str());
+            response.add_property("queueOffset", "42");
+            response
+        });
+        let ended = Arc::clone(&recorded);
+        broker.handle(RequestCode::EndTransaction, move |request| {
+            ended.ended.lock().push(request.clone());
+            Command::new_response(request, ResponseCode::Success)
+        });
+
+        let route_service = broker.route_service();
+        let setting_manager = Arc::new(ClientSettingManager::new(
+            Arc::new(RenewService::new(
+                broker.mq_client(),
+                Arc::clone(&route_service),
+            )),
+            Arc::new(RwLock::new(GroupConfigManager::new("."))),
+            SettingPolicy::default(),
+        ));
+        let transaction_service = Arc::new(TransactionService::new(
+            broker.mq_client(),
+            Arc::clone(&route_service),
+            setting_manager,
+        ));
+        // The topics of the mock broker are not configured, they accept normal messages only.
+        let policy = SettingPolicy {
+            validate_message_type: false,
+            ..Default::default()
+        };
+        let producer = ProducerService::new(
+            broker.mq_client(),
+            route_service,
+            Arc::clone(&transaction_service),
+            policy,
+        );
+        (broker, recorded, transaction_service, producer)
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (broker, _, _, producer) = setup().await;
+
+        let response = producer
+            .send_message(
+                "client",
+                vec![message("test", "id-1"), message("test", "id-2")],
+            )
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!(2, response.entries.len());
+        assert_eq!("id-1", response.entries[0].message_id);
+        assert_eq!(42, response.entries[0].offset);
+
+        let mut rejected = message("test", "id-3");
+        rejected.system_properties.as_mut().unwrap().tag = Some("reject".to_string());
+        let response = producer
+            .send_message("client", vec![message("test", "id-4"), rejected])
+            .await;
+        assert_eq!(Code::MultipleResults as i32, response.status.unwrap().code);
+        assert_eq!(
+            Code::Forbidden as i32,
+            response.entries[1].status.as_ref().unwrap().code
+        );
+
+        let response = producer.send_message("client", vec![]).await;
+        assert_eq!(Code::BadRequest as i32, response.status.unwrap().code);
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_gzip_message() {
+        let (broker, recorded, _, producer) = setup().await;
+
+        let mut gzip = message("test", "id-1");
+        gzip.system_properties.as_mut().unwrap().body_encoding = pb::Encoding::Gzip as i32;
+        let response = producer
+            .send_message("client", vec![gzip, message("test", "id-2")])
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!(
+            vec![COMPRESSED_FLAG, TRANSACTION_NOT_TYPE],
+            *recorded.sys_flags.lock()
+        );
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let (broker, recorded, transaction_service, producer) = setup().await;
+
+        let mut half = message("test", "id-1");
+        half.system_properties.as_mut().unwrap().message_type = pb::MessageType::Transaction as i32;
+        let response = producer.send_message("client", vec![half]).await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!("id-1", response.entries[0].transaction_id);
+        let data = transaction_service.get("id-1").unwrap();
+        assert_eq!("broker-a", data.broker_name);
+        assert_eq!(42, data.tran_state_table_offset);
+        assert_eq!(1024, data.commit_log_offset);
+
+        let end = |transaction_id: &str, resolution: pb::TransactionResolution| {
+            pb::EndTransactionRequest {
+                topic: Some(pb::Resource {
+                    resource_namespace: "".to_string(),
+                    name: "test".to_string(),
+                }),
+                message_id: "id-1".to_string(),
+                transaction_id: transaction_id.to_string(),
+                resolution: resolution as i32,
+                source: pb::TransactionSource::SourceClient as i32,
+                trace_context: String::new(),
+            }
+        };
+        let response = transaction_service
+            .end_transaction(end("id-1", pb::TransactionResolution::Unspecified))
+            .await;
+        assert_eq!(Code::BadRequest as i32, response.status.unwrap().code);
+        let response = transaction_service
+            .end_transaction(end("id-1", pb::TransactionResolution::Commit))
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert!(transaction_service.get("id-1").is_none());
+        let response = transaction_service
+            .end_transaction(end("id-1", pb::TransactionResolution::Rollback))
+            .await;
+        assert_eq!(
+            Code::InvalidTransactionId as i32,
+            response.status.unwrap().code
+        );
+
+        // END_TRANSACTION is oneway, wait for the broker to receive it.
+        for _ in 0..50 {
+            if !recorded.ended.lock().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let ended = recorded.ended.lock().pop().unwrap();
+        assert_eq!(
+            TRANSACTION_COMMIT_TYPE.to_string(),
+            *ended.get_property("commitOrRollback").unwrap()
+        );
+        assert_eq!("1024", ended.get_property("commitLogOffset").unwrap());
+        assert_eq!("42", ended.get_property("tranStateTableOffset").unwrap());
+        assert_eq!("false", ended.get_property("fromTransactionCheck").unwrap());
+
+        broker.shutdown().await;
+    }
+}
@@ -0,0 +1,101 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{producer::MAX_BODY_SIZE, session::SettingPolicy};
+
+const DEFAULT_NAMESRV_ADDR: &str = "127.0.0.1:9876";
+/// The address the admin endpoint listens on when enabled, reachable from the local host only.
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:8082";
+
+/**
+ * ProxyConfig holds the settings of the proxy itself, loaded from `proxy_config.json` under the
+ * config directory. Absent fields take their default values.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// The addresses of the NameServers separated by `;`, the proxy fails over between them.
+    namesrv_addr: String,
+    max_body_size: i32,
+    validate_message_type: bool,
+    /// Whether admin tools may query and control the gRPC clients through the proxy.
+    admin_enabled: bool,
+    admin_addr: String,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            namesrv_addr: DEFAULT_NAMESRV_ADDR.to_string(),
+            max_body_size: MAX_BODY_SIZE as i32,
+            validate_message_type: true,
+            admin_enabled: false,
+            admin_addr: DEFAULT_ADMIN_ADDR.to_string(),
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Loads the config under the directory, the default config is written if absent.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.to_string() + "/proxy_config.json";
+        let path = Path::new(path.as_str());
+        if let Ok(data) = fs::read_to_string(path) {
+            return Ok(serde_json::from_str(&data)?);
+        }
+        let config = Self::default();
+        fs::write(path, serde_json::to_string_pretty(&config)?)?;
+        Ok(config)
+    }
+
+    pub fn namesrv_addr(&self) -> &str {
+        &self.namesrv_addr
+    }
+
+    /// The settings imposed on the clients, which the proxy checks the messages against too.
+    pub fn setting_policy(&self) -> SettingPolicy {
+        SettingPolicy {
+            max_body_size: self.max_body_size,
+            validate_message_type: self.validate_message_type,
+            ..Default::default()
+        }
+    }
+
+    /// The address of the admin endpoint, none if it is disabled.
+    pub fn admin_addr(&self) -> Option<String> {
+        self.admin_enabled.then(|| self.admin_addr.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let config = ProxyConfig::load(path).unwrap();
+        assert_eq!(DEFAULT_NAMESRV_ADDR, config.namesrv_addr());
+        assert_eq!(MAX_BODY_SIZE as i32, config.max_body_size);
+        assert!(config.validate_message_type);
+        assert!(config.admin_addr().is_none());
+        assert!(dir.path().join("proxy_config.json").exists());
+
+        fs::write(
+            dir.path().join("proxy_config.json"),
+            r#"{"namesrv_addr": "10.0.0.1:9876;10.0.0.2:9876", "max_body_size": 1024,
+                "admin_enabled": true}"#,
+        )
+        .unwrap();
+        let config = ProxyConfig::load(path).unwrap();
+        assert_eq!("10.0.0.1:9876;10.0.0.2:9876", config.namesrv_addr());
+        assert_eq!(Some(DEFAULT_ADMIN_ADDR.to_string()), config.admin_addr());
+        let policy = config.setting_policy();
+        assert_eq!(1024, policy.max_body_size);
+        assert!(policy.validate_message_type);
+        assert!(policy.metric_endpoints.is_none());
+    }
+}
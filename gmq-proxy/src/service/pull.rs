@@ -0,0 +1,557 @@
+use std::{sync::Arc, time::Duration};
+
+use gmq_remoting::common::code::RequestCode;
+use tokio::sync::mpsc;
+
+use super::{
+    consumer::{filter_expression, long_polling_timeout, to_pb_message, MAX_BATCH_SIZE},
+    route::RouteService,
+};
+use crate::{
+    common::{now_millis, ok_status, status, timestamp_to_millis},
+    pb::{self, pull_message_response::Content, Code, Status},
+    remoting::{
+        client::{MQClient, PullStatus},
+        header::{
+            PullMessageRequestHeader, QueryConsumerOffsetRequestHeader, QueueOffsetRequestHeader,
+            UpdateConsumerOffsetRequestHeader, PULL_FLAG_SUBSCRIPTION, PULL_FLAG_SUSPEND,
+        },
+    },
+};
+
+/// Extra time given to brokers to respond a pull request after suspending it.
+const PULL_TIMEOUT_MARGIN: Duration = Duration::from_secs(3);
+
+/// Streams the responses of a PullMessage call to the client.
+pub type PullMessageSender = mpsc::Sender<Result<pb::PullMessageResponse, tonic::Status>>;
+
+/**
+ * PullService serves the gRPC clients consuming in the pull way, which pull messages from the
+ * queues they choose and manage the consume offsets themselves.
+ */
+#[derive(Debug)]
+pub struct PullService {
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+}
+
+/// The queue a pull consumer operates on, along with the address of its broker.
+struct Queue {
+    topic: String,
+    queue_id: i32,
+    addr: String,
+}
+
+impl PullService {
+    pub fn new(mq_client: Arc<MQClient>, route_service: Arc<RouteService>) -> Self {
+        Self {
+            mq_client,
+            route_service,
+        }
+    }
+
+    /// Pulls messages of the queue from the offset and streams them to the sender one by one,
+    /// followed by the status and the offset to pull from next time.
+    pub async fn pull_message(&self, request: pb::PullMessageRequest, sender: PullMessageSender) {
+        let send = |content| {
+            sender.send(Ok(pb::PullMessageResponse {
+                content: Some(content),
+            }))
+        };
+        let (result, next_offset) = match self.pull(request).await {
+            Ok((messages, next_offset)) if messages.is_empty() => (
+                status(Code::MessageNotFound, "no new message"),
+                Some(next_offset),
+            ),
+            Ok((messages, next_offset)) => {
+                for message in messages {
+                    // the client is gone, it pulls from the same offset again.
+                    if send(Content::Message(message)).await.is_err() {
+                        return;
+                    }
+                }
+                (ok_status(), Some(next_offset))
+            }
+            Err(error) => error,
+        };
+        if send(Content::Status(result)).await.is_ok() {
+            if let Some(next_offset) = next_offset {
+                let _ = send(Content::NextOffset(next_offset)).await;
+            }
+        }
+    }
+
+    /// Returns the pulled messages and the next offset, or the error along with the offset to
+    /// restart from if the requested one is out of the range of the queue.
+    async fn pull(
+        &self,
+        request: pb::PullMessageRequest,
+    ) -> Result<(Vec<pb::Message>, i64), (Status, Option<i64>)> {
+        let group = group(request.group).map_err(|status| (status, None))?;
+        let (topic, queue) = self
+            .queue(request.message_queue)
+            .await
+            .map_err(|status| (status, None))?;
+        if request.batch_size <= 0 || request.batch_size > MAX_BATCH_SIZE {
+            return Err((
+                status(
+                    Code::BadRequest,
+                    format!("batch size should be in [1, {}]", MAX_BATCH_SIZE),
+                ),
+                None,
+            ));
+        }
+        if request.offset < 0 {
+            return Err((
+                status(Code::IllegalOffset, "offset should not be negative"),
+                None,
+            ));
+        }
+        let long_polling_timeout =
+            long_polling_timeout(request.long_polling_timeout).map_err(|status| (status, None))?;
+        let (expression_type, subscription) = filter_expression(request.filter_expression);
+
+        let header = PullMessageRequestHeader {
+            consumer_group: group,
+            topic: queue.topic,
+            queue_id: queue.queue_id,
+            queue_offset: request.offset,
+            max_msg_nums: request.batch_size,
+            sys_flag: PULL_FLAG_SUSPEND | PULL_FLAG_SUBSCRIPTION,
+            commit_offset: 0,
+            suspend_timeout_millis: long_polling_timeout.as_millis() as i64,
+            subscription,
+            sub_version: now_millis(),
+            expression_type: expression_type.to_string(),
+        };
+        let result = self
+            .mq_client
+            .pull_message(
+                &queue.addr,
+                header,
+                long_polling_timeout + PULL_TIMEOUT_MARGIN,
+            )
+            .await
+            .map_err(|e| (e.to_status(), None))?;
+        let next_offset = result.header.next_begin_offset;
+        match result.status {
+            PullStatus::OffsetIllegal => Err((
+                status(
+                    Code::IllegalOffset,
+                    format!(
+                        "offset {} is out of the range [{}, {}]",
+                        request.offset, result.header.min_offset, result.header.max_offset
+                    ),
+                ),
+                Some(next_offset),
+            )),
+            _ => Ok((
+                result
+                    .messages
+                    .into_iter()
+                    .map(|message| to_pb_message(message, &topic, None, None))
+                    .collect(),
+                next_offset,
+            )),
+        }
+    }
+
+    /// Commits the consume offset of the group in the queue.
+    pub async fn update_offset(
+        &self,
+        request: pb::UpdateOffsetRequest,
+    ) -> pb::UpdateOffsetResponse {
+        let status = match self.update(request).await {
+            Ok(()) => ok_status(),
+            Err(status) => status,
+        };
+        pb::UpdateOffsetResponse {
+            status: Some(status),
+        }
+    }
+
+    async fn update(&self, request: pb::UpdateOffsetRequest) -> Result<(), Status> {
+        let group = group(request.group)?;
+        let (_, queue) = self.queue(request.message_queue).await?;
+        if request.offset < 0 {
+            return Err(status(Code::IllegalOffset, "offset should not be negative"));
+        }
+        let header = UpdateConsumerOffsetRequestHeader {
+            consumer_group: group,
+            topic: queue.topic,
+            queue_id: queue.queue_id,
+            commit_offset: request.offset,
+        };
+        self.mq_client
+            .update_consumer_offset(&queue.addr, header)
+            .await
+            .map_err(|e| e.to_status())
+    }
+
+    /// Returns the consume offset committed by the group in the queue.
+    pub async fn get_offset(&self, request: pb::GetOffsetRequest) -> pb::GetOffsetResponse {
+        match self.get(request).await {
+            Ok(offset) => pb::GetOffsetResponse {
+                status: Some(ok_status()),
+                offset,
+            },
+            Err(status) => pb::GetOffsetResponse {
+                status: Some(status),
+                offset: 0,
+            },
+        }
+    }
+
+    async fn get(&self, request: pb::GetOffsetRequest) -> Result<i64, Status> {
+        let group = group(request.group)?;
+        let (_, queue) = self.queue(request.message_queue).await?;
+        let header = QueryConsumerOffsetRequestHeader {
+            consumer_group: group.clone(),
+            topic: queue.topic.clone(),
+            queue_id: queue.queue_id,
+        };
+        self.mq_client
+            .query_consumer_offset(&queue.addr, header)
+            .await
+            .map_err(|e| e.to_status())?
+            .ok_or_else(|| {
+                status(
+                    Code::OffsetNotFound,
+                    format!(
+                        "group {} has no offset in queue {} of topic {}",
+                        group, queue.queue_id, queue.topic
+                    ),
+                )
+            })
+    }
+
+    /// Returns the offset of the queue at the beginning, the end, or the time of the request.
+    pub async fn query_offset(&self, request: pb::QueryOffsetRequest) -> pb::QueryOffsetResponse {
+        match self.query(request).await {
+            Ok(offset) => pb::QueryOffsetResponse {
+                status: Some(ok_status()),
+                offset,
+            },
+            Err(status) => pb::QueryOffsetResponse {
+                status: Some(status),
+                offset: 0,
+            },
+        }
+    }
+
+    async fn query(&self, request: pb::QueryOffsetRequest) -> Result<i64, Status> {
+        let (_, queue) = self.queue(request.message_queue).await?;
+        let (code, timestamp) = match pb::QueryOffsetPolicy::try_from(request.query_offset_policy) {
+            Ok(pb::QueryOffsetPolicy::Beginning) => (RequestCode::GetMinOffset, None),
+            Ok(pb::QueryOffsetPolicy::End) => (RequestCode::GetMaxOffset, None),
+            Ok(pb::QueryOffsetPolicy::Timestamp) => {
+                let timestamp = request.timestamp.as_ref().ok_or_else(|| {
+                    status(
+                        Code::BadRequest,
+                        "timestamp is required to query offset by timestamp",
+                    )
+                })?;
+                (
+                    RequestCode::SearchOffsetByTimestamp,
+                    Some(timestamp_to_millis(timestamp)),
+                )
+            }
+            Err(_) => return Err(status(Code::BadRequest, "unknown query offset policy")),
+        };
+        let header = QueueOffsetRequestHeader {
+            topic: queue.topic,
+            queue_id: queue.queue_id,
+            timestamp,
+        };
+        self.mq_client
+            .query_queue_offset(&queue.addr, code, header)
+            .await
+            .map_err(|e| e.to_status())
+    }
+
+    /// Resolves the queue of the request to the topic and the address of its broker.
+    async fn queue(
+        &self,
+        message_queue: Option<pb::MessageQueue>,
+    ) -> Result<(pb::Resource, Queue), Status> {
+        let message_queue =
+            message_queue.ok_or_else(|| status(Code::BadRequest, "message queue is required"))?;
+        let topic = message_queue
+            .topic
+            .filter(|topic| !topic.name.is_empty())
+            .ok_or_else(|| status(Code::IllegalTopic, "topic is required"))?;
+        let broker_name = message_queue
+            .broker
+            .map(|broker| broker.name)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| status(Code::BadRequest, "broker of the message queue is required"))?;
+        let addr = self
+            .route_service
+            .get_master_addr(&topic.name, &broker_name)
+            .await
+            .map_err(|e| e.to_status())?;
+        let queue = Queue {
+            topic: topic.name.clone(),
+            queue_id: message_queue.id,
+            addr,
+        };
+        Ok((topic, queue))
+    }
+}
+
+fn group(group: Option<pb::Resource>) -> Result<String, Status> {
+    group
+        .map(|group| group.name)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| status(Code::IllegalConsumerGroup, "group is required"))
+}
+
+#[cfg(test)]
+mod tests {
+    use gmq_remoting::common::{code::ResponseCode, command::Command};
+    use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+    use super::*;
+    use crate::{
+        remoting::{
+            header::{PROPERTY_TAGS, PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX},
+            message::{tests::encode_message, MessageExt},
+        },
+        service::mock_broker::{resource, MockBroker},
+    };
+
+    /// Pulls from queue 1 of topic `test`, which holds messages at offsets [100, 110).
+    fn pull_message(request: &Command) -> Command {
+        let property = |name: &str| request.get_property(name).unwrap().clone();
+        assert_eq!("1", property("queueId"));
+        assert_eq!("6", property("sysFlag"));
+        let offset: i64 = property("queueOffset").parse().unwrap();
+        let max: i64 = property("maxMsgNums").parse().unwrap();
+        let mut response = if !(100..=110).contains(&offset) {
+            let mut response = Command::new_response(request, ResponseCode::PullOffsetMoved);
+            response.add_property("nextBeginOffset", "100");
+            response
+        } else if offset == 110 {
+            let mut response = Command::new_response(request, ResponseCode::PullNotFound);
+            response.add_property("nextBeginOffset", "110");
+            response
+        } else {
+            let end = (offset + max).min(110);
+            let mut data = Vec::new();
+            for queue_offset in offset..end {
+                let mut message = MessageExt {
+                    topic: "test".to_string(),
+                    queue_id: 1,
+                    queue_offset,
+                    body: b"hello".to_vec(),
+                    ..Default::default()
+                };
+                message.properties.insert(
+                    PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX.to_string(),
+                    format!("id-{}", queue_offset),
+                );
+                message
+                    .properties
+                    .insert(PROPERTY_TAGS.to_string(), property("subscription"));
+                data.extend(encode_message(&message));
+            }
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            response.add_property("nextBeginOffset", end.to_string().as_str());
+            response.set_body(data);
+            response
+        };
+        response.add_property("minOffset", "100");
+        response.add_property("maxOffset", "110");
+        response
+    }
+
+    fn offset_response(request: &Command, offset: i64) -> Command {
+        let mut response = Command::new_response(request, ResponseCode::Success);
+        response.add_property("offset", offset.to_string().as_str());
+        response
+    }
+
+    async fn setup() -> (MockBroker, PullService) {
+        let broker = MockBroker::start().await;
+        broker.handle(RequestCode::PullMessage, pull_message);
+        let committed = Arc::new(parking_lot::Mutex::new(None));
+        let updated = Arc::clone(&committed);
+        broker.handle(RequestCode::UpdateConsumerOffset, move |request| {
+            let offset = request.get_property("commitOffset").unwrap();
+            *updated.lock() = Some(offset.parse().unwrap());
+            Command::new_response(request, ResponseCode::Success)
+        });
+        broker.handle(
+            RequestCode::QueryConsumerOffset,
+            move |request| match *committed.lock() {
+                Some(offset) => offset_response(request, offset),
+                None => Command::new_response(request, ResponseCode::QueryNotFound),
+            },
+        );
+        broker.handle(RequestCode::GetMinOffset, |request| {
+            offset_response(request, 100)
+        });
+        broker.handle(RequestCode::GetMaxOffset, |request| {
+            offset_response(request, 110)
+        });
+        broker.handle(RequestCode::SearchOffsetByTimestamp, |request| {
+            let timestamp: i64 = request.get_property("timestamp").unwrap().parse().unwrap();
+            offset_response(request, 100 + timestamp / 1000)
+        });
+
+        let pull = PullService::new(broker.mq_client(), broker.route_service());
+        (broker, pull)
+    }
+
+    fn message_queue() -> Option<pb::MessageQueue> {
+        Some(pb::MessageQueue {
+            topic: resource("test"),
+            id: 1,
+            broker: Some(pb::Broker {
+                name: "broker-a".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn request(offset: i64, batch_size: i32) -> pb::PullMessageRequest {
+        pb::PullMessageRequest {
+            group: resource("group"),
+            message_queue: message_queue(),
+            offset,
+            batch_size,
+            filter_expression: Some(pb::FilterExpression {
+                r#type: pb::FilterType::Tag as i32,
+                expression: "tag-a".to_string(),
+            }),
+            long_polling_timeout: Some(prost_types::Duration {
+                seconds: 5,
+                nanos: 0,
+            }),
+        }
+    }
+
+    /// Pulls with the request and collects the contents streamed back.
+    async fn pull(pull: &PullService, request: pb::PullMessageRequest) -> Vec<Content> {
+        // a single slot makes every response wait for the previous one to be read.
+        let (sender, receiver) = mpsc::channel(1);
+        let contents = ReceiverStream::new(receiver)
+            .map(Result::unwrap)
+            .map(|response: pb::PullMessageResponse| response.content.unwrap())
+            .collect();
+        let ((), contents) = tokio::join!(pull.pull_message(request, sender), contents);
+        contents
+    }
+
+    fn status_code(content: &Content) -> i32 {
+        match content {
+            Content::Status(status) => status.code,
+            _ => panic!("status expected, got {:?}", content),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_message() {
+        let (broker, pull) = setup().await;
+
+        let contents = self::pull(&pull, request(105, 2)).await;
+        assert_eq!(4, contents.len());
+        let Content::Message(message) = &contents[1] else {
+            panic!("message expected");
+        };
+        let system_properties = message.system_properties.as_ref().unwrap();
+        assert_eq!("id-106", system_properties.message_id);
+        assert_eq!(Some("tag-a"), system_properties.tag.as_deref());
+        assert_eq!(None, system_properties.receipt_handle);
+        assert_eq!(Code::Ok as i32, status_code(&contents[2]));
+        assert_eq!(Content::NextOffset(107), contents[3]);
+
+        let contents = self::pull(&pull, request(110, 2)).await;
+        assert_eq!(Code::MessageNotFound as i32, status_code(&contents[0]));
+        assert_eq!(Content::NextOffset(110), contents[1]);
+
+        // pulls out of the range of the queue tell the client where to restart.
+        let contents = self::pull(&pull, request(1, 2)).await;
+        assert_eq!(Code::IllegalOffset as i32, status_code(&contents[0]));
+        assert_eq!(Content::NextOffset(100), contents[1]);
+
+        let contents = self::pull(&pull, request(105, 64)).await;
+        assert_eq!(1, contents.len());
+        assert_eq!(Code::BadRequest as i32, status_code(&contents[0]));
+
+        let mut no_broker = request(105, 2);
+        no_broker.message_queue.as_mut().unwrap().broker = None;
+        let contents = self::pull(&pull, no_broker).await;
+        assert_eq!(Code::BadRequest as i32, status_code(&contents[0]));
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_offsets() {
+        let (broker, pull) = setup().await;
+
+        let get = || pb::GetOffsetRequest {
+            group: resource("group"),
+            message_queue: message_queue(),
+        };
+        let response = pull.get_offset(get()).await;
+        assert_eq!(Code::OffsetNotFound as i32, response.status.unwrap().code);
+
+        let response = pull
+            .update_offset(pb::UpdateOffsetRequest {
+                group: resource("group"),
+                message_queue: message_queue(),
+                offset: 105,
+            })
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        let response = pull.get_offset(get()).await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!(105, response.offset);
+
+        let response = pull
+            .update_offset(pb::UpdateOffsetRequest {
+                group: None,
+                message_queue: message_queue(),
+                offset: 105,
+            })
+            .await;
+        assert_eq!(
+            Code::IllegalConsumerGroup as i32,
+            response.status.unwrap().code
+        );
+
+        let query = |policy: pb::QueryOffsetPolicy, timestamp: Option<prost_types::Timestamp>| {
+            pb::QueryOffsetRequest {
+                message_queue: message_queue(),
+                query_offset_policy: policy as i32,
+                timestamp,
+            }
+        };
+        let response = pull
+            .query_offset(query(pb::QueryOffsetPolicy::Beginning, None))
+            .await;
+        assert_eq!(100, response.offset);
+        let response = pull
+            .query_offset(query(pb::QueryOffsetPolicy::End, None))
+            .await;
+        assert_eq!(110, response.offset);
+        let timestamp = prost_types::Timestamp {
+            seconds: 3,
+            nanos: 0,
+        };
+        let response = pull
+            .query_offset(query(pb::QueryOffsetPolicy::Timestamp, Some(timestamp)))
+            .await;
+        assert_eq!(Code::Ok as i32, response.status.unwrap().code);
+        assert_eq!(103, response.offset);
+        let response = pull
+            .query_offset(query(pb::QueryOffsetPolicy::Timestamp, None))
+            .await;
+        assert_eq!(Code::BadRequest as i32, response.status.unwrap().code);
+
+        broker.shutdown().await;
+    }
+}
@@ -0,0 +1,214 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    common::{now_millis, Error},
+    remoting::header::{retry_flag, KEY_SEPARATOR, RETRY_GROUP_TOPIC_PREFIX},
+};
+
+/// The kind of topic a message is popped from, brokers tell them apart in the checkpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptTopicType {
+    Normal,
+    Retry,
+    RetryV2,
+}
+
+impl ReceiptTopicType {
+    pub fn from_topic(topic: &str) -> Self {
+        retry_flag(topic)
+            .parse()
+            .unwrap_or(ReceiptTopicType::Normal)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptTopicType::Normal => "0",
+            ReceiptTopicType::Retry => "1",
+            ReceiptTopicType::RetryV2 => "2",
+        }
+    }
+}
+
+impl FromStr for ReceiptTopicType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(ReceiptTopicType::Normal),
+            "1" => Ok(ReceiptTopicType::Retry),
+            "2" => Ok(ReceiptTopicType::RetryV2),
+            _ => Err(()),
+        }
+    }
+}
+
+/**
+ * The receipt handle of a popped message, in the format of the extra info brokers use to
+ * locate the checkpoint of the message:
+ * `startOffset retrieveTime invisibleTime reviveQueueId topicType brokerName queueId offset
+ * commitLogOffset`, separated by spaces.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiptHandle {
+    pub start_offset: i64,
+    pub retrieve_time: i64,
+    pub invisible_time: i64,
+    pub revive_queue_id: i32,
+    pub topic_type: ReceiptTopicType,
+    pub broker_name: String,
+    pub queue_id: i32,
+    pub offset: i64,
+    /// `-1` for receipt handles issued without the commit log offset.
+    pub commit_log_offset: i64,
+}
+
+impl ReceiptHandle {
+    /// The time the message becomes visible again, in milliseconds.
+    pub fn next_visible_time(&self) -> i64 {
+        self.retrieve_time + self.invisible_time
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.next_visible_time() <= now_millis()
+    }
+
+    /// Returns the topic the message is popped from, which is the pop retry topic of the group
+    /// and the topic for redelivered messages: `%RETRY%group_topic` in V1, and
+    /// `%RETRY%group+topic` in V2.
+    pub fn real_topic(&self, topic: &str, group: &str) -> String {
+        match self.topic_type {
+            ReceiptTopicType::Normal => topic.to_string(),
+            ReceiptTopicType::Retry => format!("{}{}_{}", RETRY_GROUP_TOPIC_PREFIX, group, topic),
+            ReceiptTopicType::RetryV2 => {
+                format!("{}{}+{}", RETRY_GROUP_TOPIC_PREFIX, group, topic)
+            }
+        }
+    }
+
+    /// Returns the receipt handle checkpointed again by the broker when the invisible time of
+    /// the message is changed.
+    pub fn renew(&self, retrieve_time: i64, invisible_time: i64, revive_queue_id: i32) -> Self {
+        Self {
+            start_offset: self.offset,
+            retrieve_time,
+            invisible_time,
+            revive_queue_id,
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for ReceiptHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            self.start_offset.to_string(),
+            self.retrieve_time.to_string(),
+            self.invisible_time.to_string(),
+            self.revive_queue_id.to_string(),
+            self.topic_type.as_str().to_string(),
+            self.broker_name.clone(),
+            self.queue_id.to_string(),
+            self.offset.to_string(),
+            self.commit_log_offset.to_string(),
+        ];
+        write!(f, "{}", fields.join(KEY_SEPARATOR))
+    }
+}
+
+impl FromStr for ReceiptHandle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidReceiptHandle(s.to_string());
+        let fields: Vec<&str> = s.split(KEY_SEPARATOR).collect();
+        if fields.len() < 8 || fields[5].is_empty() {
+            return Err(invalid());
+        }
+        let commit_log_offset = match fields.get(8) {
+            Some(offset) => offset.parse().map_err(|_| invalid())?,
+            None => -1,
+        };
+        Ok(Self {
+            start_offset: fields[0].parse().map_err(|_| invalid())?,
+            retrieve_time: fields[1].parse().map_err(|_| invalid())?,
+            invisible_time: fields[2].parse().map_err(|_| invalid())?,
+            revive_queue_id: fields[3].parse().map_err(|_| invalid())?,
+            topic_type: fields[4].parse().map_err(|_| invalid())?,
+            broker_name: fields[5].to_string(),
+            queue_id: fields[6].parse().map_err(|_| invalid())?,
+            offset: fields[7].parse().map_err(|_| invalid())?,
+            commit_log_offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> ReceiptHandle {
+        ReceiptHandle {
+            start_offset: 100,
+            retrieve_time: 1000,
+            invisible_time: 30000,
+            revive_queue_id: 2,
+            topic_type: ReceiptTopicType::Normal,
+            broker_name: "broker-a".to_string(),
+            queue_id: 1,
+            offset: 101,
+            commit_log_offset: 4096,
+        }
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let handle = handle();
+        let encoded = handle.to_string();
+        assert_eq!("100 1000 30000 2 0 broker-a 1 101 4096", encoded);
+        assert_eq!(handle, encoded.parse().unwrap());
+
+        // receipt handles without the commit log offset are still accepted.
+        let legacy: ReceiptHandle = "100 1000 30000 2 1 broker-a 1 101".parse().unwrap();
+        assert_eq!(-1, legacy.commit_log_offset);
+        assert_eq!(ReceiptTopicType::Retry, legacy.topic_type);
+
+        for invalid in [
+            "",
+            "100 1000 30000",
+            "a 1000 30000 2 0 broker-a 1 101",
+            "100 1000 30000 2 9 broker-a 1 101",
+        ] {
+            assert!(matches!(
+                invalid.parse::<ReceiptHandle>(),
+                Err(Error::InvalidReceiptHandle(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_expiry_and_renew() {
+        let handle = handle();
+        assert_eq!(31000, handle.next_visible_time());
+        assert!(handle.is_expired());
+
+        let renewed = handle.renew(now_millis(), 30000, 3);
+        assert!(!renewed.is_expired());
+        assert_eq!(101, renewed.start_offset);
+        assert_eq!(3, renewed.revive_queue_id);
+        assert_eq!(handle.commit_log_offset, renewed.commit_log_offset);
+    }
+
+    #[test]
+    fn test_real_topic() {
+        let mut handle = handle();
+        assert_eq!("test", handle.real_topic("test", "group"));
+        handle.topic_type = ReceiptTopicType::Retry;
+        assert_eq!("%RETRY%group_test", handle.real_topic("test", "group"));
+        handle.topic_type = ReceiptTopicType::RetryV2;
+        assert_eq!("%RETRY%group+test", handle.real_topic("test", "group"));
+        assert_eq!(
+            ReceiptTopicType::RetryV2,
+            ReceiptTopicType::from_topic("%RETRY%group+test")
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::{future::join_all, stream, StreamExt};
+use parking_lot::RwLock;
+use tokio::{sync::Mutex, time::interval};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use super::{consumer::change_invisible_time, receipt_handle::ReceiptHandle, route::RouteService};
+use crate::{common::now_millis, remoting::client::MQClient};
+
+const RENEW_INTERVAL: Duration = Duration::from_secs(5);
+/// Handles are renewed this long before the messages become visible again.
+const RENEW_AHEAD: Duration = Duration::from_secs(10);
+/// The invisible duration each renewal extends.
+const RENEW_SLICE: Duration = Duration::from_secs(60);
+/// Messages held longer than this are no longer renewed and will be redelivered.
+const MAX_RENEW_DURATION: Duration = Duration::from_secs(3 * 60 * 60);
+/// Renewals in flight to a single broker.
+const MAX_RENEWS_PER_BROKER: usize = 16;
+
+#[derive(Debug, Clone)]
+struct InflightMessage {
+    group: String,
+    topic: String,
+    // locked while the handle is being renewed, the new handle replaces it on brokers.
+    handle: Arc<Mutex<ReceiptHandle>>,
+    received_at: Instant,
+}
+
+/// Client id -> receipt handle issued to the client -> in-flight message.
+type InflightTable = HashMap<String, HashMap<String, InflightMessage>>;
+
+/**
+ * RenewService keeps the messages received with `auto_renew` invisible until they are acked,
+ * by changing their invisible time before they expire. Messages no longer renewed are kept
+ * until their latest handles expire, so that acks with the handles issued to clients still
+ * reach brokers with the latest ones.
+ */
+#[derive(Debug)]
+pub struct RenewService {
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+    inflight: Arc<RwLock<InflightTable>>,
+    max_renew_duration: Duration,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
+}
+
+impl RenewService {
+    pub fn new(mq_client: Arc<MQClient>, route_service: Arc<RouteService>) -> Self {
+        Self {
+            mq_client,
+            route_service,
+            inflight: Arc::new(RwLock::new(HashMap::new())),
+            max_renew_duration: MAX_RENEW_DURATION,
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
+        }
+    }
+
+    /// Starts renewing the in-flight messages periodically.
+    pub fn start(&self) {
+        let mq_client = Arc::clone(&self.mq_client);
+        let route_service = Arc::clone(&self.route_service);
+        let inflight = Arc::clone(&self.inflight);
+        let max_renew_duration = self.max_renew_duration;
+        let shutdown_token = self.shutdown_token.clone();
+        self.shutdown_tracker.spawn(async move {
+            let mut ticker = interval(RENEW_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        RenewService::renew_expiring(&mq_client, &route_service, &inflight, max_renew_duration).await;
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Tracks a message delivered to the client until it is acked.
+    pub fn add(&self, client_id: &str, group: &str, topic: &str, handle: ReceiptHandle) {
+        self.inflight
+            .write()
+            .entry(client_id.to_string())
+            .or_default()
+            .insert(
+                handle.to_string(),
+                InflightMessage {
+                    group: group.to_string(),
+                    topic: topic.to_string(),
+                    handle: Arc::new(Mutex::new(handle)),
+                    received_at: Instant::now(),
+                },
+            );
+    }
+
+    /// Stops renewing the message, returns its latest receipt handle which replaces the one
+    /// issued to the client on brokers. A renewal in flight is waited for, since it replaces
+    /// the handle again.
+    pub async fn remove(&self, client_id: &str, receipt_handle: &str) -> Option<ReceiptHandle> {
+        let message = {
+            let mut inflight = self.inflight.write();
+            let messages = inflight.get_mut(client_id)?;
+            let message = messages.remove(receipt_handle);
+            if messages.is_empty() {
+                inflight.remove(client_id);
+            }
+            message?
+        };
+        let handle = message.handle.lock().await;
+        Some(handle.clone())
+    }
+
+    /// Stops renewing all the messages of the client, they are redelivered once invisible time
+    /// runs out.
+    pub fn remove_client(&self, client_id: &str) -> usize {
+        self.inflight
+            .write()
+            .remove(client_id)
+            .map(|messages| messages.len())
+            .unwrap_or_default()
+    }
+
+    async fn renew_expiring(
+        mq_client: &MQClient,
+        route_service: &RouteService,
+        inflight: &RwLock<InflightTable>,
+        max_renew_duration: Duration,
+    ) {
+        let deadline = now_millis() + RENEW_AHEAD.as_millis() as i64;
+        let mut expired = Vec::new();
+        // broker name -> messages to renew on the broker.
+        let mut expiring: HashMap<String, Vec<(String, String, InflightMessage)>> = HashMap::new();
+        for (client_id, messages) in inflight.read().iter() {
+            for (key, message) in messages {
+                // handles being renewed are skipped.
+                let Ok(handle) = message.handle.try_lock() else {
+                    continue;
+                };
+                if handle.is_expired() {
+                    expired.push((client_id.clone(), key.clone(), message.received_at));
+                } else if handle.next_visible_time() <= deadline
+                    && message.received_at.elapsed() < max_renew_duration
+                {
+                    expiring
+                        .entry(handle.broker_name.clone())
+                        .or_default()
+                        .push((client_id.clone(), key.clone(), message.clone()));
+                }
+            }
+        }
+
+        // the messages are redelivered, their handles are of no use to acks.
+        for (client_id, key, received_at) in expired {
+            if received_at.elapsed() >= max_renew_duration {
+                println!(
+                    "stop renewing message {} of client {}, held for {:?}",
+                    key,
+                    client_id,
+                    received_at.elapsed()
+                );
+            }
+            RenewService::remove_inflight(inflight, &client_id, &key);
+        }
+
+        // brokers are renewed concurrently, each with at most `MAX_RENEWS_PER_BROKER` in flight.
+        let mut renewals = Vec::with_capacity(expiring.len());
+        for messages in expiring.into_values() {
+            let mut broker_renewals = Vec::with_capacity(messages.len());
+            for (client_id, key, message) in messages {
+                broker_renewals.push(RenewService::renew(
+                    mq_client,
+                    route_service,
+                    inflight,
+                    client_id,
+                    key,
+                    message,
+                ));
+            }
+            renewals.push(
+                stream::iter(broker_renewals)
+                    .buffer_unordered(MAX_RENEWS_PER_BROKER)
+                    .collect::<Vec<()>>(),
+            );
+        }
+        join_all(renewals).await;
+    }
+
+    async fn renew(
+        mq_client: &MQClient,
+        route_service: &RouteService,
+        inflight: &RwLock<InflightTable>,
+        client_id: String,
+        key: String,
+        message: InflightMessage,
+    ) {
+        let mut handle = message.handle.lock().await;
+        // the message may be acked before the lock is taken, its handle must stay the one
+        // the ack uses.
+        let removed = !inflight
+            .read()
+            .get(&client_id)
+            .and_then(|messages| messages.get(&key))
+            .is_some_and(|current| Arc::ptr_eq(&current.handle, &message.handle));
+        if removed {
+            return;
+        }
+        let result = change_invisible_time(
+            mq_client,
+            route_service,
+            &message.group,
+            &message.topic,
+            &handle,
+            RENEW_SLICE,
+        )
+        .await;
+        match result {
+            Ok(renewed) => *handle = renewed,
+            Err(e) => {
+                println!(
+                    "renew message {} of client {} failed: {:?}",
+                    key, client_id, e
+                );
+                if handle.is_expired() {
+                    RenewService::remove_inflight(inflight, &client_id, &key);
+                }
+            }
+        }
+    }
+
+    fn remove_inflight(inflight: &RwLock<InflightTable>, client_id: &str, key: &str) {
+        let mut inflight = inflight.write();
+        if let Some(messages) = inflight.get_mut(client_id) {
+            messages.remove(key);
+            if messages.is_empty() {
+                inflight.remove(client_id);
+            }
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use gmq_remoting::common::{
+        code::{RequestCode, ResponseCode},
+        command::Command,
+    };
+
+    use super::*;
+    use crate::service::{mock_broker::MockBroker, receipt_handle::ReceiptTopicType};
+
+    fn handle(offset: i64, invisible_time: i64) -> ReceiptHandle {
+        ReceiptHandle {
+            start_offset: offset,
+            retrieve_time: now_millis(),
+            invisible_time,
+            revive_queue_id: 0,
+            topic_type: ReceiptTopicType::Normal,
+            broker_name: "broker-a".to_string(),
+            queue_id: 0,
+            offset,
+            commit_log_offset: 0,
+        }
+    }
+
+    /// Returns the number of renewals the broker receives, each answered after `renew_delay`.
+    async fn setup(renew_delay: Duration) -> (MockBroker, Arc<AtomicUsize>, Arc<RenewService>) {
+        let broker = MockBroker::start().await;
+        let renewed = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&renewed);
+        broker.handle_slowly(
+            RequestCode::ChangeMessageInvisibleTime,
+            renew_delay,
+            move |request| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut response = Command::new_response(request, ResponseCode::Success);
+                response.add_property("popTime", now_millis().to_string());
+                response.add_property(
+                    "invisibleTime",
+                    request.get_property("invisibleTime").unwrap().as_str(),
+                );
+                response.add_property("reviveQid", "1");
+                response
+            },
+        );
+        let renew_service = Arc::new(RenewService::new(
+            broker.mq_client(),
+            broker.route_service(),
+        ));
+        (broker, renewed, renew_service)
+    }
+
+    async fn renew_expiring(renew_service: &RenewService) {
+        RenewService::renew_expiring(
+            &renew_service.mq_client,
+            &renew_service.route_service,
+            &renew_service.inflight,
+            renew_service.max_renew_duration,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_renew_inflight_messages() {
+        let (broker, renewed, renew_service) = setup(Duration::ZERO).await;
+
+        // only the message about to become visible is renewed.
+        let expiring = handle(1, 5000);
+        let fresh = handle(2, 60000);
+        renew_service.add("client", "group", "test", expiring.clone());
+        renew_service.add("client", "group", "test", fresh.clone());
+        renew_expiring(&renew_service).await;
+        assert_eq!(1, renewed.load(Ordering::Relaxed));
+
+        let latest = renew_service
+            .remove("client", &expiring.to_string())
+            .await
+            .unwrap();
+        assert_eq!(RENEW_SLICE.as_millis() as i64, latest.invisible_time);
+        assert_eq!(1, latest.revive_queue_id);
+        assert!(renew_service
+            .remove("client", &expiring.to_string())
+            .await
+            .is_none());
+
+        // messages held for too long are no longer renewed, their handles are kept for acks
+        // until they expire.
+        let expired = handle(3, -1000);
+        renew_service.add("client", "group", "test", expiring.clone());
+        renew_service.add("client", "group", "test", expired.clone());
+        RenewService::renew_expiring(
+            &renew_service.mq_client,
+            &renew_service.route_service,
+            &renew_service.inflight,
+            Duration::ZERO,
+        )
+        .await;
+        assert_eq!(1, renewed.load(Ordering::Relaxed));
+        assert_eq!(
+            Some(expiring.clone()),
+            renew_service.remove("client", &expiring.to_string()).await
+        );
+        assert!(renew_service
+            .remove("client", &expired.to_string())
+            .await
+            .is_none());
+
+        assert_eq!(1, renew_service.remove_client("client"));
+        assert_eq!(0, renew_service.remove_client("client"));
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_renew_concurrently() {
+        let renew_delay = Duration::from_millis(200);
+        let (broker, renewed, renew_service) = setup(renew_delay).await;
+        for offset in 0..4 {
+            renew_service.add("client", "group", "test", handle(offset, 5000));
+        }
+
+        let start = Instant::now();
+        renew_expiring(&renew_service).await;
+        assert_eq!(4, renewed.load(Ordering::Relaxed));
+        assert!(start.elapsed() < renew_delay * 4);
+
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_remove_while_renewing() {
+        let (broker, renewed, renew_service) = setup(Duration::from_millis(100)).await;
+        let expiring = handle(1, 5000);
+        renew_service.add("client", "group", "test", expiring.clone());
+        let renewing = tokio::spawn({
+            let renew_service = Arc::clone(&renew_service);
+            async move { renew_expiring(&renew_service).await }
+        });
+        while renewed.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the ack waits for the renewal and gets the handle replacing the one it holds.
+        let latest = renew_service
+            .remove("client", &expiring.to_string())
+            .await
+            .unwrap();
+        assert_eq!(RENEW_SLICE.as_millis() as i64, latest.invisible_time);
+        renewing.await.unwrap();
+        assert_eq!(0, renew_service.remove_client("client"));
+
+        broker.shutdown().await;
+    }
+}
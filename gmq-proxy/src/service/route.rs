@@ -1,15 +1,407 @@
-use crate::pb;
+use std::{collections::HashMap, iter, sync::Arc, time::Duration};
 
-use super::topic_config::TopicConfigManager;
+use anyhow::anyhow;
 use parking_lot::RwLock;
-use std::sync::Arc;
+use tokio::time::interval;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
+use super::{
+    message_queue::MessageQueue,
+    topic_config::{TopicConfigManager, TopicType},
+};
+use crate::{
+    common::Error,
+    pb,
+    remoting::client::{MQClient, QueueData, TopicRouteData, MASTER_ID},
+};
+
+const ROUTE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/**
+ * RouteService caches the topic routes fetched from the NameServer and keeps them
+ * up to date in the background.
+ */
+#[derive(Debug)]
 pub struct RouteService {
+    mq_client: Arc<MQClient>,
     topic_config_manager: Arc<RwLock<TopicConfigManager>>,
+    route_table: Arc<RwLock<HashMap<String, Arc<Route>>>>,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
 }
 
-pub struct Route {}
+#[derive(Debug)]
+pub struct Route {
+    topic: String,
+    route_data: TopicRouteData,
+}
+
+impl Route {
+    pub fn new(topic: &str, route_data: TopicRouteData) -> Self {
+        Self {
+            topic: topic.to_string(),
+            route_data,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn route_data(&self) -> &TopicRouteData {
+        &self.route_data
+    }
+
+    pub fn master_addr(&self, broker_name: &str) -> Option<&str> {
+        self.route_data
+            .broker_datas()
+            .iter()
+            .find(|b| b.broker_name() == broker_name)
+            .and_then(|b| b.master_addr())
+            .map(|addr| addr.as_str())
+    }
+
+    /// Queues that can be written to, only brokers with a live master are included.
+    pub fn writable_queues(&self) -> Vec<MessageQueue> {
+        self.queues(|q| q.is_writeable(), |q| q.write_queue_nums())
+    }
+
+    /// Queues that can be read from, only brokers with a live master are included.
+    pub fn readable_queues(&self) -> Vec<MessageQueue> {
+        self.queues(|q| q.is_readable(), |q| q.read_queue_nums())
+    }
+
+    fn queues(
+        &self,
+        filter: impl Fn(&QueueData) -> bool,
+        queue_nums: impl Fn(&QueueData) -> i32,
+    ) -> Vec<MessageQueue> {
+        let mut queues = Vec::new();
+        for queue_data in self.route_data.queue_datas() {
+            if !filter(queue_data) || self.master_addr(queue_data.broker_name()).is_none() {
+                continue;
+            }
+            for queue_id in 0..queue_nums(queue_data) {
+                queues.push(MessageQueue::new(
+                    &self.topic,
+                    queue_data.broker_name(),
+                    queue_id,
+                ));
+            }
+        }
+        queues
+    }
+
+    /// Converts the route into gRPC message queues. Every queue is served by the master
+    /// broker of its broker name, and brokers are advertised with the proxy `endpoints`
+    /// so that clients keep talking to the proxy.
+    pub fn to_message_queues(
+        &self,
+        topic: &pb::Resource,
+        endpoints: &pb::Endpoints,
+        message_type: pb::MessageType,
+    ) -> Vec<pb::MessageQueue> {
+        let mut message_queues = Vec::new();
+        for queue_data in self.route_data.queue_datas() {
+            if self.master_addr(queue_data.broker_name()).is_none() {
+                continue;
+            }
+
+            let mut read = 0;
+            let mut write = 0;
+            let mut read_write = 0;
+            if queue_data.is_readable() && queue_data.is_writeable() {
+                read_write = queue_data
+                    .read_queue_nums()
+                    .min(queue_data.write_queue_nums());
+                read = queue_data.read_queue_nums() - read_write;
+                write = queue_data.write_queue_nums() - read_write;
+            } else if queue_data.is_writeable() {
+                write = queue_data.write_queue_nums();
+            } else if queue_data.is_readable() {
+                read = queue_data.read_queue_nums();
+            }
+
+            let broker = pb::Broker {
+                name: queue_data.broker_name().to_string(),
+                id: MASTER_ID as i32,
+                endpoints: Some(endpoints.clone()),
+            };
+            let permissions = iter::repeat_n(pb::Permission::Read, read as usize)
+                .chain(iter::repeat_n(pb::Permission::Write, write as usize))
+                .chain(iter::repeat_n(
+                    pb::Permission::ReadWrite,
+                    read_write as usize,
+                ));
+            for (id, permission) in permissions.enumerate() {
+                message_queues.push(pb::MessageQueue {
+                    topic: Some(topic.clone()),
+                    id: id as i32,
+                    permission: permission as i32,
+                    broker: Some(broker.clone()),
+                    accept_message_types: vec![message_type as i32],
+                });
+            }
+        }
+        message_queues
+    }
+}
 
 impl RouteService {
-    pub fn get_topic_route(&self, topic_name: &str) {}
+    pub fn new(
+        mq_client: Arc<MQClient>,
+        topic_config_manager: Arc<RwLock<TopicConfigManager>>,
+    ) -> Self {
+        Self {
+            mq_client,
+            topic_config_manager,
+            route_table: Arc::new(RwLock::new(HashMap::new())),
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
+        }
+    }
+
+    /// Starts refreshing the cached routes periodically.
+    pub fn start(&self) {
+        let mq_client = Arc::clone(&self.mq_client);
+        let route_table = Arc::clone(&self.route_table);
+        let shutdown_token = self.shutdown_token.clone();
+        self.shutdown_tracker.spawn(async move {
+            let mut ticker = interval(ROUTE_REFRESH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        RouteService::refresh(&mq_client, &route_table).await;
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn refresh(mq_client: &MQClient, route_table: &RwLock<HashMap<String, Arc<Route>>>) {
+        let topics: Vec<String> = route_table.read().keys().cloned().collect();
+        for topic in topics {
+            match mq_client.query_route(&topic).await {
+                Ok(route_data) => {
+                    route_table
+                        .write()
+                        .insert(topic.clone(), Arc::new(Route::new(&topic, route_data)));
+                }
+                Err(Error::TopicNotFound(..)) => {
+                    println!("route of topic {} disappears, invalidate it", topic);
+                    route_table.write().remove(&topic);
+                }
+                Err(e) => {
+                    // keep the stale route, the NameServer may be temporarily unavailable.
+                    println!("refresh route of topic {} failed: {:?}", topic, e);
+                }
+            }
+        }
+    }
+
+    /// Returns the route of the topic, querying the NameServer if it is not cached yet.
+    pub async fn get_topic_route(&self, topic_name: &str) -> Result<Arc<Route>, Error> {
+        if let Some(route) = self.route_table.read().get(topic_name) {
+            return Ok(Arc::clone(route));
+        }
+        let route_data = self.mq_client.query_route(topic_name).await?;
+        let route = Arc::new(Route::new(topic_name, route_data));
+        self.route_table
+            .write()
+            .insert(topic_name.to_string(), Arc::clone(&route));
+        Ok(route)
+    }
+
+    pub fn invalidate(&self, topic_name: &str) {
+        self.route_table.write().remove(topic_name);
+    }
+
+    pub async fn writable_queues(&self, topic_name: &str) -> Result<Vec<MessageQueue>, Error> {
+        Ok(self.get_topic_route(topic_name).await?.writable_queues())
+    }
+
+    pub async fn readable_queues(&self, topic_name: &str) -> Result<Vec<MessageQueue>, Error> {
+        Ok(self.get_topic_route(topic_name).await?.readable_queues())
+    }
+
+    /// Returns the master addresses of all the brokers in the cached routes.
+    pub fn master_addrs(&self) -> Vec<String> {
+        let mut addrs: Vec<String> = self
+            .route_table
+            .read()
+            .values()
+            .flat_map(|route| {
+                route
+                    .route_data()
+                    .broker_datas()
+                    .iter()
+                    .filter_map(|broker| broker.master_addr().cloned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        addrs.sort();
+        addrs.dedup();
+        addrs
+    }
+
+    /// Looks up the master address of the broker among all cached routes.
+    pub fn master_addr(&self, broker_name: &str) -> Option<String> {
+        self.route_table
+            .read()
+            .values()
+            .find_map(|route| route.master_addr(broker_name).map(|addr| addr.to_string()))
+    }
+
+    /// Returns the master address of the broker serving the topic.
+    pub async fn get_master_addr(
+        &self,
+        topic_name: &str,
+        broker_name: &str,
+    ) -> Result<String, Error> {
+        self.get_topic_route(topic_name)
+            .await?
+            .master_addr(broker_name)
+            .map(|addr| addr.to_string())
+            .ok_or_else(|| {
+                Error::InternalError(anyhow!(
+                    "no master of broker {} for topic {}",
+                    broker_name,
+                    topic_name
+                ))
+            })
+    }
+
+    pub fn accept_message_type(&self, topic_name: &str) -> pb::MessageType {
+        match self
+            .topic_config_manager
+            .read()
+            .get_topic_config(topic_name)
+        {
+            Some(config) => match config.topic_type() {
+                TopicType::NORMAL => pb::MessageType::Normal,
+                TopicType::DELAY => pb::MessageType::Delay,
+                TopicType::FIFO => pb::MessageType::Fifo,
+                TopicType::TRANSACTION => pb::MessageType::Transaction,
+            },
+            None => pb::MessageType::Normal,
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn route() -> Route {
+        let route_data = serde_json::from_value(json!({
+            "queueDatas": [
+                {"brokerName": "broker-a", "readQueueNums": 4, "writeQueueNums": 2, "perm": 6},
+                {"brokerName": "broker-b", "readQueueNums": 2, "writeQueueNums": 2, "perm": 4},
+                {"brokerName": "broker-c", "readQueueNums": 2, "writeQueueNums": 2, "perm": 6}
+            ],
+            "brokerDatas": [
+                {"cluster": "c1", "brokerName": "broker-a", "brokerAddrs": {"0": "127.0.0.1:10911"}},
+                {"cluster": "c1", "brokerName": "broker-b", "brokerAddrs": {"0": "127.0.0.1:10921"}},
+                {"cluster": "c1", "brokerName": "broker-c", "brokerAddrs": {"1": "127.0.0.1:10931"}}
+            ]
+        }))
+        .unwrap();
+        Route::new("test", route_data)
+    }
+
+    #[test]
+    fn test_queues() {
+        let route = route();
+        let writable = route.writable_queues();
+        assert_eq!(
+            vec![
+                MessageQueue::new("test", "broker-a", 0),
+                MessageQueue::new("test", "broker-a", 1)
+            ],
+            writable
+        );
+
+        // broker-c has no master and is skipped.
+        let readable = route.readable_queues();
+        assert_eq!(6, readable.len());
+        assert_eq!(
+            2,
+            readable
+                .iter()
+                .filter(|q| q.broker_name() == "broker-b")
+                .count()
+        );
+
+        assert_eq!(Some("127.0.0.1:10911"), route.master_addr("broker-a"));
+        assert_eq!(None, route.master_addr("broker-c"));
+    }
+
+    #[test]
+    fn test_to_message_queues() {
+        let topic = pb::Resource {
+            resource_namespace: "".to_string(),
+            name: "test".to_string(),
+        };
+        let endpoints = pb::Endpoints {
+            scheme: pb::AddressScheme::IPv4 as i32,
+            addresses: vec![pb::Address {
+                host: "127.0.0.1".to_string(),
+                port: 8081,
+            }],
+        };
+        let queues = route().to_message_queues(&topic, &endpoints, pb::MessageType::Fifo);
+
+        assert_eq!(6, queues.len());
+        let broker_a: Vec<_> = queues
+            .iter()
+            .filter(|q| q.broker.as_ref().unwrap().name == "broker-a")
+            .collect();
+        assert_eq!(4, broker_a.len());
+        assert_eq!(
+            2,
+            broker_a
+                .iter()
+                .filter(|q| q.permission == pb::Permission::Read as i32)
+                .count()
+        );
+        assert_eq!(
+            2,
+            broker_a
+                .iter()
+                .filter(|q| q.permission == pb::Permission::ReadWrite as i32)
+                .count()
+        );
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            broker_a.iter().map(|q| q.id).collect::<Vec<_>>()
+        );
+
+        let broker_b: Vec<_> = queues
+            .iter()
+            .filter(|q| q.broker.as_ref().unwrap().name == "broker-b")
+            .collect();
+        assert_eq!(2, broker_b.len());
+        assert!(broker_b
+            .iter()
+            .all(|q| q.permission == pb::Permission::Read as i32));
+
+        for queue in queues {
+            assert_eq!(Some(endpoints.clone()), queue.broker.unwrap().endpoints);
+            assert_eq!(
+                vec![pb::MessageType::Fifo as i32],
+                queue.accept_message_types
+            );
+        }
+    }
 }
@@ -1,39 +1,124 @@
-use std::collections::HashMap;
 use std::error::Error;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use async_stream::try_stream;
+use gmq_remoting::server::RemotingServer;
 use parking_lot::RwLock;
-use tokio_stream::Stream;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
 use tonic::transport::Server;
 use tonic::Response;
 
+use crate::common::{ok_status, status};
 use crate::pb::messaging_service_server::{MessagingService, MessagingServiceServer};
-use crate::pb::{self, ClientType, Code, Settings, Status, TelemetryCommand};
-use crate::pb::telemetry_command::Command;
+use crate::pb::{self, Code};
+use crate::remoting::client::MQClient;
 
-pub struct GrpcMessagingServer {}
+use super::admin::AdminService;
+use super::assignment::AssignmentService;
+use super::consumer::ConsumerService;
+use super::group_config::GroupConfigManager;
+use super::producer::ProducerService;
+use super::pull::PullService;
+use super::renew::RenewService;
+use super::route::RouteService;
+use super::session::{
+    ClientSettingManager, SettingPolicy, TelemetrySender, TELEMETRY_CHANNEL_CAPACITY,
+};
+use super::topic_config::TopicConfigManager;
+use super::transaction::TransactionService;
 
-#[derive(Debug, Clone)]
-pub struct ClientSettingManager {
-    client_settings_map: Arc<RwLock<HashMap<String, Settings>>>,
+/// The metadata key gRPC clients identify themselves with.
+const CLIENT_ID_KEY: &str = "x-mq-client-id";
+/// The responses of a streaming call buffered for the client to read.
+const STREAM_CHANNEL_CAPACITY: usize = 16;
 
+pub struct GrpcMessagingServer {
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+    renew_service: Arc<RenewService>,
+    setting_manager: Arc<ClientSettingManager>,
+    group_config_manager: Arc<RwLock<GroupConfigManager>>,
+    transaction_service: Arc<TransactionService>,
+    /// The address admin tools send requests about the gRPC clients to, none if disabled.
+    admin_addr: Option<String>,
 }
 
 impl GrpcMessagingServer {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(
+        mq_client: Arc<MQClient>,
+        topic_config_manager: Arc<RwLock<TopicConfigManager>>,
+        group_config_manager: Arc<RwLock<GroupConfigManager>>,
+        setting_policy: SettingPolicy,
+        admin_addr: Option<String>,
+    ) -> Self {
+        let route_service = Arc::new(RouteService::new(
+            Arc::clone(&mq_client),
+            topic_config_manager,
+        ));
+        let renew_service = Arc::new(RenewService::new(
+            Arc::clone(&mq_client),
+            Arc::clone(&route_service),
+        ));
+        let setting_manager = Arc::new(ClientSettingManager::new(
+            Arc::clone(&renew_service),
+            Arc::clone(&group_config_manager),
+            setting_policy,
+        ));
+        let transaction_service = Arc::new(TransactionService::new(
+            Arc::clone(&mq_client),
+            Arc::clone(&route_service),
+            Arc::clone(&setting_manager),
+        ));
+        Self {
+            mq_client,
+            route_service,
+            renew_service,
+            setting_manager,
+            group_config_manager,
+            transaction_service,
+            admin_addr,
+        }
     }
 
     pub async fn start(&mut self) -> Result<(), Box<dyn Error>> {
-        let service_inner = MessagingServiceServer::new(MessagingServer::new());
+        self.route_service.start();
+        self.renew_service.start();
+        self.setting_manager.start();
+        self.transaction_service.start();
+        let admin_server = match &self.admin_addr {
+            Some(addr) => {
+                let admin_server = RemotingServer::new(addr);
+                AdminService::new(Arc::clone(&self.setting_manager))
+                    .register_processors(&admin_server);
+                admin_server.start().await?;
+                Some(admin_server)
+            }
+            None => None,
+        };
+        let service_inner = MessagingServiceServer::new(MessagingServer::new(
+            Arc::clone(&self.mq_client),
+            Arc::clone(&self.route_service),
+            Arc::clone(&self.renew_service),
+            Arc::clone(&self.setting_manager),
+            Arc::clone(&self.group_config_manager),
+            Arc::clone(&self.transaction_service),
+        ));
 
         let addr = "0.0.0.0:8081".parse().unwrap();
-        Server::builder()
+        let result = Server::builder()
             .add_service(service_inner)
             .serve(addr)
-            .await?;
+            .await;
+        if let Some(admin_server) = admin_server {
+            admin_server.shutdown().await;
+        }
+        self.transaction_service.shutdown().await;
+        self.setting_manager.shutdown().await;
+        self.renew_service.shutdown().await;
+        self.route_service.shutdown().await;
+        result?;
 
         Ok(())
     }
@@ -41,165 +126,325 @@ impl GrpcMessagingServer {
 
 #[derive(Debug)]
 pub struct MessagingServer {
-    setting_manager: ClientSettingManager,
+    setting_manager: Arc<ClientSettingManager>,
+    route_service: Arc<RouteService>,
+    producer_service: ProducerService,
+    consumer_service: Arc<ConsumerService>,
+    pull_service: Arc<PullService>,
+    assignment_service: AssignmentService,
+    renew_service: Arc<RenewService>,
+    transaction_service: Arc<TransactionService>,
 }
 
 impl MessagingServer {
-
-    pub fn new() -> Self {
+    pub fn new(
+        mq_client: Arc<MQClient>,
+        route_service: Arc<RouteService>,
+        renew_service: Arc<RenewService>,
+        setting_manager: Arc<ClientSettingManager>,
+        group_config_manager: Arc<RwLock<GroupConfigManager>>,
+        transaction_service: Arc<TransactionService>,
+    ) -> Self {
         Self {
-            setting_manager: ClientSettingManager::new(),
+            assignment_service: AssignmentService::new(
+                Arc::clone(&route_service),
+                Arc::clone(&setting_manager),
+                group_config_manager,
+            ),
+            producer_service: ProducerService::new(
+                Arc::clone(&mq_client),
+                Arc::clone(&route_service),
+                Arc::clone(&transaction_service),
+                setting_manager.policy().clone(),
+            ),
+            setting_manager,
+            consumer_service: Arc::new(ConsumerService::new(
+                Arc::clone(&mq_client),
+                Arc::clone(&route_service),
+                Arc::clone(&renew_service),
+            )),
+            pull_service: Arc::new(PullService::new(mq_client, Arc::clone(&route_service))),
+            route_service,
+            renew_service,
+            transaction_service,
         }
     }
 }
 
+/**
+ * TelemetryGuard is owned by the telemetry stream of a client and unbinds the stream when it is
+ * dropped, whether the client closes it or the connection breaks.
+ */
+struct TelemetryGuard {
+    setting_manager: Arc<ClientSettingManager>,
+    client_id: String,
+    sender: TelemetrySender,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        self.setting_manager
+            .unregister_channel(&self.client_id, &self.sender);
+        println!("telemetry stream of client {} closed", self.client_id);
+    }
+}
+
+/// Returns the id of the client sending the request, empty if the client does not tell.
+fn client_id<T>(request: &tonic::Request<T>) -> String {
+    request
+        .metadata()
+        .get(CLIENT_ID_KEY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
 #[tonic::async_trait]
 impl MessagingService for MessagingServer {
-
     type TelemetryStream =
         Pin<Box<dyn Stream<Item = Result<pb::TelemetryCommand, tonic::Status>> + Send + 'static>>;
-    type ReceiveMessageStream = tonic::Streaming<pb::ReceiveMessageResponse>;
-    type PullMessageStream = tonic::Streaming<pb::PullMessageResponse>;
+    type ReceiveMessageStream = Pin<
+        Box<dyn Stream<Item = Result<pb::ReceiveMessageResponse, tonic::Status>> + Send + 'static>,
+    >;
+    type PullMessageStream = Pin<
+        Box<dyn Stream<Item = Result<pb::PullMessageResponse, tonic::Status>> + Send + 'static>,
+    >;
     async fn query_assignment(
         &self,
-        _request: tonic::Request<pb::QueryAssignmentRequest>,
+        request: tonic::Request<pb::QueryAssignmentRequest>,
     ) -> Result<tonic::Response<pb::QueryAssignmentResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        let request = request.into_inner();
+        let response = self
+            .assignment_service
+            .query_assignment(&client_id, request)
+            .await;
+        Ok(Response::new(response))
     }
 
     async fn query_route(
         &self,
-        _request: tonic::Request<pb::QueryRouteRequest>,
+        request: tonic::Request<pb::QueryRouteRequest>,
     ) -> Result<tonic::Response<pb::QueryRouteResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let request = request.into_inner();
+        let topic = request
+            .topic
+            .ok_or_else(|| tonic::Status::invalid_argument("topic is required"))?;
+        let endpoints = request
+            .endpoints
+            .ok_or_else(|| tonic::Status::invalid_argument("endpoints is required"))?;
+
+        let response = match self.route_service.get_topic_route(&topic.name).await {
+            Ok(route) => {
+                let message_type = self.route_service.accept_message_type(&topic.name);
+                pb::QueryRouteResponse {
+                    status: Some(ok_status()),
+                    message_queues: route.to_message_queues(&topic, &endpoints, message_type),
+                }
+            }
+            Err(e) => pb::QueryRouteResponse {
+                status: Some(e.to_status()),
+                message_queues: vec![],
+            },
+        };
+        Ok(Response::new(response))
     }
 
     async fn heartbeat(
         &self,
-        _request: tonic::Request<pb::HeartbeatRequest>,
+        request: tonic::Request<pb::HeartbeatRequest>,
     ) -> Result<tonic::Response<pb::HeartbeatResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        if client_id.is_empty() {
+            return Ok(Response::new(pb::HeartbeatResponse {
+                status: Some(status(Code::ClientIdRequired, "client id is required")),
+            }));
+        }
+        let request = request.into_inner();
+        let client_type =
+            pb::ClientType::try_from(request.client_type).unwrap_or(pb::ClientType::Unspecified);
+        let group = request
+            .group
+            .map(|group| group.name)
+            .filter(|name| !name.is_empty());
+        self.setting_manager
+            .heartbeat(&client_id, client_type, group);
+        Ok(Response::new(pb::HeartbeatResponse {
+            status: Some(ok_status()),
+        }))
     }
 
     async fn send_message(
         &self,
-        _request: tonic::Request<pb::SendMessageRequest>,
+        request: tonic::Request<pb::SendMessageRequest>,
     ) -> Result<tonic::Response<pb::SendMessageResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        let request = request.into_inner();
+        let response = self
+            .producer_service
+            .send_message(&client_id, request.messages)
+            .await;
+        Ok(Response::new(response))
     }
 
     async fn receive_message(
         &self,
-        _request: tonic::Request<pb::ReceiveMessageRequest>,
+        request: tonic::Request<pb::ReceiveMessageRequest>,
     ) -> Result<tonic::Response<Self::ReceiveMessageStream>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        let request = request.into_inner();
+        let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let consumer_service = Arc::clone(&self.consumer_service);
+        tokio::spawn(async move {
+            consumer_service
+                .receive_message(&client_id, request, sender)
+                .await;
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
     }
 
     async fn ack_message(
         &self,
-        _request: tonic::Request<pb::AckMessageRequest>,
+        request: tonic::Request<pb::AckMessageRequest>,
     ) -> Result<tonic::Response<pb::AckMessageResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        let request = request.into_inner();
+        let response = self.consumer_service.ack_message(&client_id, request).await;
+        Ok(Response::new(response))
     }
 
     async fn forward_message_to_dead_letter_queue(
         &self,
-        _request: tonic::Request<pb::ForwardMessageToDeadLetterQueueRequest>,
+        request: tonic::Request<pb::ForwardMessageToDeadLetterQueueRequest>,
     ) -> Result<tonic::Response<pb::ForwardMessageToDeadLetterQueueResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        let request = request.into_inner();
+        let response = self
+            .consumer_service
+            .forward_message_to_dead_letter_queue(&client_id, request)
+            .await;
+        Ok(Response::new(response))
     }
 
     async fn pull_message(
         &self,
-        _request: tonic::Request<pb::PullMessageRequest>,
+        request: tonic::Request<pb::PullMessageRequest>,
     ) -> Result<tonic::Response<Self::PullMessageStream>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let request = request.into_inner();
+        let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let pull_service = Arc::clone(&self.pull_service);
+        tokio::spawn(async move {
+            pull_service.pull_message(request, sender).await;
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
     }
 
     async fn update_offset(
         &self,
-        _request: tonic::Request<pb::UpdateOffsetRequest>,
+        request: tonic::Request<pb::UpdateOffsetRequest>,
     ) -> Result<tonic::Response<pb::UpdateOffsetResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let request = request.into_inner();
+        let response = self.pull_service.update_offset(request).await;
+        Ok(Response::new(response))
     }
 
     async fn get_offset(
         &self,
-        _request: tonic::Request<pb::GetOffsetRequest>,
+        request: tonic::Request<pb::GetOffsetRequest>,
     ) -> Result<tonic::Response<pb::GetOffsetResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let request = request.into_inner();
+        let response = self.pull_service.get_offset(request).await;
+        Ok(Response::new(response))
     }
 
     async fn query_offset(
         &self,
-        _request: tonic::Request<pb::QueryOffsetRequest>,
+        request: tonic::Request<pb::QueryOffsetRequest>,
     ) -> Result<tonic::Response<pb::QueryOffsetResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let request = request.into_inner();
+        let response = self.pull_service.query_offset(request).await;
+        Ok(Response::new(response))
     }
 
     async fn end_transaction(
         &self,
-        _request: tonic::Request<pb::EndTransactionRequest>,
+        request: tonic::Request<pb::EndTransactionRequest>,
     ) -> Result<tonic::Response<pb::EndTransactionResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let request = request.into_inner();
+        let response = self.transaction_service.end_transaction(request).await;
+        Ok(Response::new(response))
     }
 
     async fn telemetry(
         &self,
         request: tonic::Request<tonic::Streaming<pb::TelemetryCommand>>,
     ) -> Result<tonic::Response<Self::TelemetryStream>, tonic::Status> {
+        let client_id = client_id(&request);
+        if client_id.is_empty() {
+            return Err(tonic::Status::invalid_argument("client id is required"));
+        }
+        let setting_manager = Arc::clone(&self.setting_manager);
+        // commands pushed by the proxy are sent along with the replies to the client.
+        let (sender, mut receiver) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+        setting_manager.register_channel(&client_id, sender.clone());
+        let guard = TelemetryGuard {
+            setting_manager: Arc::clone(&setting_manager),
+            client_id: client_id.clone(),
+            sender,
+        };
         let mut stream = request.into_inner();
         let output = try_stream! {
-            while let Ok(message) = stream.message().await {
-                if let Some(command) = message {
-                    if let Some(command) = command.command {
-                        match command {
-                            Command::Settings(settings) => {
-                                //TODO: add detail implementation.
-                                yield TelemetryCommand {
-                                    status: Some(Status {
-                                        code: Code::Ok as i32,
-                                        message: "ok".to_string(),
-                                    }),
-                                    command: Some(Command::Settings(settings.clone())),
-                                }
-                            }
-                            _ => {
-
-                            }
-                        }
-                    }
-               }
+            let _guard = guard;
+            loop {
+                let command = tokio::select! {
+                    message = stream.message() => match message {
+                        Ok(Some(command)) => setting_manager.on_telemetry(&client_id, command),
+                        _ => break,
+                    },
+                    Some(command) = receiver.recv() => Some(command),
+                };
+                if let Some(command) = command {
+                    yield command;
+                }
             }
-            println!("Still working on this command!")
         };
         Ok(Response::new(Box::pin(output)))
     }
 
     async fn notify_client_termination(
         &self,
-        _request: tonic::Request<pb::NotifyClientTerminationRequest>,
+        request: tonic::Request<pb::NotifyClientTerminationRequest>,
     ) -> Result<tonic::Response<pb::NotifyClientTerminationResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        if client_id.is_empty() {
+            return Ok(Response::new(pb::NotifyClientTerminationResponse {
+                status: Some(status(Code::ClientIdRequired, "client id is required")),
+            }));
+        }
+        self.setting_manager.remove(&client_id);
+        // in-flight messages of the client are redelivered once their invisible time runs out.
+        let released = self.renew_service.remove_client(&client_id);
+        if released > 0 {
+            println!(
+                "client {} terminated, stop renewing {} messages",
+                client_id, released
+            );
+        }
+        Ok(Response::new(pb::NotifyClientTerminationResponse {
+            status: Some(ok_status()),
+        }))
     }
 
     async fn change_invisible_duration(
         &self,
-        _request: tonic::Request<pb::ChangeInvisibleDurationRequest>,
+        request: tonic::Request<pb::ChangeInvisibleDurationRequest>,
     ) -> Result<tonic::Response<pb::ChangeInvisibleDurationResponse>, tonic::Status> {
-        Err(tonic::Status::aborted("not implemented"))
+        let client_id = client_id(&request);
+        let request = request.into_inner();
+        let response = self
+            .consumer_service
+            .change_invisible_duration(&client_id, request)
+            .await;
+        Ok(Response::new(response))
     }
 }
-
-impl ClientSettingManager {
-    pub fn new() -> Self {
-        let client_settings_map = Arc::new(RwLock::new(HashMap::new()));
-        ClientSettingManager {
-            client_settings_map,
-        }
-    }
-
-    pub fn add_setting(&mut self, client_id: String, settings: Settings) {
-        self.client_settings_map.write().insert(client_id, settings);
-    }
-}
\ No newline at end of file
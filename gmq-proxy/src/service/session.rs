@@ -0,0 +1,550 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use gmq_remoting::util;
+use parking_lot::RwLock;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{interval, timeout},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use super::{
+    group_config::{GroupConfig, GroupConfigManager},
+    producer::MAX_BODY_SIZE,
+    renew::RenewService,
+};
+use crate::{
+    common::{ok_status, Error},
+    pb::{self, settings::PubSub, telemetry_command::Command, Settings, TelemetryCommand},
+};
+
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+/// Clients neither sending heartbeats nor telemetry commands for this long are expired.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// The number of commands buffered for a client before pushing fails.
+pub const TELEMETRY_CHANNEL_CAPACITY: usize = 64;
+
+/// Pushes commands to the telemetry stream of a client.
+pub type TelemetrySender = mpsc::Sender<TelemetryCommand>;
+
+/**
+ * The settings the proxy imposes on all the clients, overriding what they report.
+ */
+#[derive(Debug, Clone)]
+pub struct SettingPolicy {
+    pub max_body_size: i32,
+    pub validate_message_type: bool,
+    /// The endpoints clients export metrics to, metrics are off if absent.
+    pub metric_endpoints: Option<pb::Endpoints>,
+}
+
+impl Default for SettingPolicy {
+    fn default() -> Self {
+        Self {
+            max_body_size: MAX_BODY_SIZE as i32,
+            validate_message_type: true,
+            metric_endpoints: None,
+        }
+    }
+}
+
+/**
+ * The session of a gRPC client connected to the proxy.
+ */
+#[derive(Debug, Clone)]
+pub struct ClientSession {
+    client_id: String,
+    client_type: pb::ClientType,
+    group: Option<String>,
+    settings: Option<Settings>,
+    last_heartbeat: Instant,
+    channel: Option<TelemetrySender>,
+}
+
+impl ClientSession {
+    fn new(client_id: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_type: pb::ClientType::Unspecified,
+            group: None,
+            settings: None,
+            last_heartbeat: Instant::now(),
+            channel: None,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn client_type(&self) -> pb::ClientType {
+        self.client_type
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn settings(&self) -> Option<&Settings> {
+        self.settings.as_ref()
+    }
+
+    pub fn last_heartbeat(&self) -> Instant {
+        self.last_heartbeat
+    }
+
+    /// Whether the client keeps a telemetry stream the proxy can push commands to.
+    pub fn is_connected(&self) -> bool {
+        self.channel
+            .as_ref()
+            .is_some_and(|channel| !channel.is_closed())
+    }
+}
+
+/**
+ * ClientSettingManager keeps the sessions of the connected clients, keyed by client id. Sessions
+ * are refreshed by heartbeats and telemetry commands, and expired once the client goes idle.
+ */
+#[derive(Debug)]
+pub struct ClientSettingManager {
+    sessions: Arc<RwLock<HashMap<String, ClientSession>>>,
+    /// Nonce of a pushed command -> the client it is pushed to and the caller waiting for the
+    /// reply.
+    pending_replies: RwLock<HashMap<String, (String, oneshot::Sender<TelemetryCommand>)>>,
+    renew_service: Arc<RenewService>,
+    group_config_manager: Arc<RwLock<GroupConfigManager>>,
+    policy: SettingPolicy,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
+}
+
+impl ClientSettingManager {
+    pub fn new(
+        renew_service: Arc<RenewService>,
+        group_config_manager: Arc<RwLock<GroupConfigManager>>,
+        policy: SettingPolicy,
+    ) -> Self {
+        Self {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            pending_replies: RwLock::new(HashMap::new()),
+            renew_service,
+            group_config_manager,
+            policy,
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
+        }
+    }
+
+    /// The settings imposed on all the clients.
+    pub fn policy(&self) -> &SettingPolicy {
+        &self.policy
+    }
+
+    /// Starts expiring idle clients periodically, their in-flight messages are no longer renewed.
+    pub fn start(&self) {
+        let sessions = Arc::clone(&self.sessions);
+        let renew_service = Arc::clone(&self.renew_service);
+        let shutdown_token = self.shutdown_token.clone();
+        self.shutdown_tracker.spawn(async move {
+            let mut ticker = interval(EXPIRE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        for client_id in ClientSettingManager::expire_idle(&sessions, CLIENT_IDLE_TIMEOUT) {
+                            println!("client {} expired", client_id);
+                            renew_service.remove_client(&client_id);
+                        }
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Refreshes the session of the client, the session is created if absent.
+    pub fn heartbeat(&self, client_id: &str, client_type: pb::ClientType, group: Option<String>) {
+        let mut sessions = self.sessions.write();
+        let session = sessions
+            .entry(client_id.to_string())
+            .or_insert_with(|| ClientSession::new(client_id));
+        if client_type != pb::ClientType::Unspecified {
+            session.client_type = client_type;
+        }
+        if group.is_some() {
+            session.group = group;
+        }
+        session.last_heartbeat = Instant::now();
+    }
+
+    /// Stores the settings reported by the client, which also tell its type and group. Returns
+    /// the settings merged with the server side policy, which the client should apply.
+    pub fn update_settings(&self, client_id: &str, settings: Settings) -> Settings {
+        let settings = self.merge_settings(settings);
+        let client_type = settings
+            .client_type
+            .and_then(|t| pb::ClientType::try_from(t).ok())
+            .unwrap_or(pb::ClientType::Unspecified);
+        let group = match &settings.pub_sub {
+            Some(PubSub::Subscription(subscription)) => {
+                subscription.group.as_ref().map(|group| group.name.clone())
+            }
+            _ => None,
+        };
+        self.heartbeat(client_id, client_type, group);
+        if let Some(session) = self.sessions.write().get_mut(client_id) {
+            session.settings = Some(settings.clone());
+        }
+        settings
+    }
+
+    /// Overrides the settings reported by the client with the proxy policy, and the group
+    /// config for consumers.
+    fn merge_settings(&self, mut settings: Settings) -> Settings {
+        match &mut settings.pub_sub {
+            Some(PubSub::Publishing(publishing)) => {
+                publishing.max_body_size = self.policy.max_body_size;
+                publishing.validate_message_type = self.policy.validate_message_type;
+            }
+            Some(PubSub::Subscription(subscription)) => {
+                let group_name = subscription
+                    .group
+                    .as_ref()
+                    .map(|group| group.name.clone())
+                    .unwrap_or_default();
+                let group_config = self
+                    .group_config_manager
+                    .read()
+                    .get_group_config(&group_name)
+                    .cloned()
+                    .unwrap_or_else(|| GroupConfig::new(group_name));
+                subscription.fifo = Some(group_config.fifo());
+                subscription.receive_batch_size = Some(group_config.receive_batch_size());
+                subscription.long_polling_timeout =
+                    prost_types::Duration::try_from(group_config.long_polling_timeout()).ok();
+                settings.backoff_policy = Some(group_config.retry_policy());
+            }
+            None => {}
+        }
+        settings.metric = Some(pb::Metric {
+            on: self.policy.metric_endpoints.is_some(),
+            endpoints: self.policy.metric_endpoints.clone(),
+        });
+        settings
+    }
+
+    pub fn get_settings(&self, client_id: &str) -> Option<Settings> {
+        self.sessions
+            .read()
+            .get(client_id)
+            .and_then(|session| session.settings.clone())
+    }
+
+    pub fn get_session(&self, client_id: &str) -> Option<ClientSession> {
+        self.sessions.read().get(client_id).cloned()
+    }
+
+    pub fn sessions(&self) -> Vec<ClientSession> {
+        self.sessions.read().values().cloned().collect()
+    }
+
+    /// Handles a command the client sends over its telemetry stream, returns the reply if any.
+    pub fn on_telemetry(
+        &self,
+        client_id: &str,
+        command: TelemetryCommand,
+    ) -> Option<TelemetryCommand> {
+        let nonce = match &command.command {
+            Some(Command::Settings(settings)) => {
+                let settings = self.update_settings(client_id, settings.clone());
+                return Some(TelemetryCommand {
+                    status: Some(ok_status()),
+                    command: Some(Command::Settings(settings)),
+                });
+            }
+            Some(Command::ThreadStackTrace(trace)) => Some(trace.nonce.clone()),
+            Some(Command::VerifyMessageResult(result)) => Some(result.nonce.clone()),
+            _ => None,
+        };
+        // any command from the client proves it is alive.
+        self.heartbeat(client_id, pb::ClientType::Unspecified, None);
+        let nonce = nonce?;
+        let mut pending_replies = self.pending_replies.write();
+        // only the client the command is pushed to may reply it.
+        match pending_replies.get(&nonce) {
+            Some((target, _)) if target == client_id => {
+                if let Some((_, sender)) = pending_replies.remove(&nonce) {
+                    let _ = sender.send(command);
+                }
+            }
+            Some((target, _)) => println!(
+                "drop reply {} from client {}, the command is pushed to {}",
+                nonce, client_id, target
+            ),
+            None => {}
+        }
+        None
+    }
+
+    /// Binds the telemetry stream of the client, replacing the previous one.
+    pub fn register_channel(&self, client_id: &str, channel: TelemetrySender) {
+        self.heartbeat(client_id, pb::ClientType::Unspecified, None);
+        if let Some(session) = self.sessions.write().get_mut(client_id) {
+            session.channel = Some(channel);
+        }
+    }
+
+    /// Unbinds the telemetry stream of the client, unless the client has opened a new one.
+    pub fn unregister_channel(&self, client_id: &str, channel: &TelemetrySender) {
+        if let Some(session) = self.sessions.write().get_mut(client_id) {
+            if session
+                .channel
+                .as_ref()
+                .is_some_and(|current| current.same_channel(channel))
+            {
+                session.channel = None;
+            }
+        }
+    }
+
+    /// Pushes the command to the client over its telemetry stream.
+    pub fn push_command(&self, client_id: &str, command: Command) -> Result<(), Error> {
+        let channel = self
+            .sessions
+            .read()
+            .get(client_id)
+            .and_then(|session| session.channel.clone())
+            .ok_or_else(|| Error::ClientNotConnected(client_id.to_string()))?;
+        let command = TelemetryCommand {
+            status: Some(ok_status()),
+            command: Some(command),
+        };
+        channel.try_send(command).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => Error::InternalError(anyhow!(
+                "too many commands pending for client {}",
+                client_id
+            )),
+            mpsc::error::TrySendError::Closed(_) => {
+                self.unregister_channel(client_id, &channel);
+                Error::ClientNotConnected(client_id.to_string())
+            }
+        })
+    }
+
+    /// Pushes the command tagged with the nonce to the client, and waits for the reply carrying
+    /// the same nonce.
+    pub async fn invoke_command(
+        &self,
+        client_id: &str,
+        nonce: &str,
+        command: Command,
+        timeout_duration: Duration,
+    ) -> Result<TelemetryCommand, Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending_replies
+            .write()
+            .insert(nonce.to_string(), (client_id.to_string(), sender));
+        let result = match self.push_command(client_id, command) {
+            Ok(()) => timeout(timeout_duration, receiver).await,
+            Err(e) => {
+                self.pending_replies.write().remove(nonce);
+                return Err(e);
+            }
+        };
+        self.pending_replies.write().remove(nonce);
+        match result {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(Error::ClientNotConnected(client_id.to_string())),
+            Err(_) => Err(Error::InternalError(util::Error::Timeout.into())),
+        }
+    }
+
+    /// Removes the session of the client, returns the removed session if any.
+    pub fn remove(&self, client_id: &str) -> Option<ClientSession> {
+        self.sessions.write().remove(client_id)
+    }
+
+    /// Removes the sessions idle for the timeout, returns their client ids. Clients keeping a
+    /// telemetry stream open are still connected however quiet they are.
+    fn expire_idle(
+        sessions: &RwLock<HashMap<String, ClientSession>>,
+        idle_timeout: Duration,
+    ) -> Vec<String> {
+        let mut sessions = sessions.write();
+        let expired: Vec<String> = sessions
+            .values()
+            .filter(|session| {
+                !session.is_connected() && session.last_heartbeat.elapsed() >= idle_timeout
+            })
+            .map(|session| session.client_id.clone())
+            .collect();
+        for client_id in &expired {
+            sessions.remove(client_id);
+        }
+        expired
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::mock_broker::{resource, setting_manager};
+
+    #[test]
+    fn test_merge_settings() {
+        let manager = setting_manager();
+        let publishing = Settings {
+            client_type: Some(pb::ClientType::Producer as i32),
+            pub_sub: Some(PubSub::Publishing(pb::Publishing {
+                topics: vec![resource("test").unwrap()],
+                max_body_size: 1024,
+                validate_message_type: false,
+            })),
+            ..Default::default()
+        };
+        let merged = manager.update_settings("producer", publishing);
+        let Some(PubSub::Publishing(publishing)) = merged.pub_sub else {
+            panic!("publishing expected");
+        };
+        assert_eq!(MAX_BODY_SIZE as i32, publishing.max_body_size);
+        assert!(publishing.validate_message_type);
+        assert_eq!(1, publishing.topics.len());
+        assert!(!merged.metric.unwrap().on);
+
+        let subscription = Settings {
+            client_type: Some(pb::ClientType::PushConsumer as i32),
+            pub_sub: Some(PubSub::Subscription(pb::Subscription {
+                group: resource("group"),
+                fifo: Some(true),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let merged = manager.update_settings("consumer", subscription);
+        let Some(PubSub::Subscription(subscription)) = merged.pub_sub else {
+            panic!("subscription expected");
+        };
+        assert_eq!(Some(false), subscription.fifo);
+        assert_eq!(Some(32), subscription.receive_batch_size);
+        assert_eq!(
+            Some(20),
+            subscription
+                .long_polling_timeout
+                .map(|timeout| timeout.seconds)
+        );
+        assert_eq!(17, merged.backoff_policy.unwrap().max_attempts);
+    }
+
+    #[tokio::test]
+    async fn test_push_and_invoke_command() {
+        let manager = Arc::new(setting_manager());
+        assert!(matches!(
+            manager.push_command(
+                "client",
+                Command::PrintThreadStackTraceCommand(Default::default())
+            ),
+            Err(Error::ClientNotConnected(_))
+        ));
+
+        let (sender, mut receiver) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+        manager.register_channel("client", sender.clone());
+        assert!(manager.get_session("client").unwrap().is_connected());
+
+        // the client replies the pushed command with the same nonce.
+        let client = Arc::clone(&manager);
+        let replier = tokio::spawn(async move {
+            let pushed = receiver.recv().await.unwrap();
+            let Some(Command::PrintThreadStackTraceCommand(command)) = pushed.command else {
+                panic!("print thread stack trace command expected");
+            };
+            let reply = TelemetryCommand {
+                status: Some(ok_status()),
+                command: Some(Command::ThreadStackTrace(pb::ThreadStackTrace {
+                    nonce: command.nonce,
+                    thread_stack_trace: Some("main".to_string()),
+                })),
+            };
+            // replies from other clients are ignored.
+            assert!(client.on_telemetry("other", reply.clone()).is_none());
+            assert_eq!(1, client.pending_replies.read().len());
+            assert!(client.on_telemetry("client", reply).is_none());
+            receiver
+        });
+        let command = Command::PrintThreadStackTraceCommand(pb::PrintThreadStackTraceCommand {
+            nonce: "nonce".to_string(),
+        });
+        let reply = manager
+            .invoke_command("client", "nonce", command.clone(), Duration::from_secs(3))
+            .await
+            .unwrap();
+        assert!(matches!(reply.command, Some(Command::ThreadStackTrace(_))));
+
+        // nobody replies.
+        let receiver = replier.await.unwrap();
+        let result = manager
+            .invoke_command("client", "nonce", command, Duration::from_millis(10))
+            .await;
+        assert_eq!(
+            Some(pb::Code::ProxyTimeout as i32),
+            result.err().map(|e| e.to_status().code)
+        );
+
+        drop(receiver);
+        manager.unregister_channel("client", &sender);
+        assert!(!manager.get_session("client").unwrap().is_connected());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let manager = setting_manager();
+        manager.heartbeat("producer", pb::ClientType::Producer, None);
+        let settings = Settings {
+            client_type: Some(pb::ClientType::SimpleConsumer as i32),
+            pub_sub: Some(PubSub::Subscription(pb::Subscription {
+                group: resource("group"),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let merged = manager.update_settings("consumer", settings);
+
+        let session = manager.get_session("consumer").unwrap();
+        assert_eq!(pb::ClientType::SimpleConsumer, session.client_type());
+        assert_eq!(Some("group"), session.group());
+        assert_eq!(Some(merged), manager.get_settings("consumer"));
+
+        // heartbeats without the group keep the one reported by the settings.
+        manager.heartbeat("consumer", pb::ClientType::SimpleConsumer, None);
+        assert_eq!(
+            Some("group"),
+            manager.get_session("consumer").unwrap().group()
+        );
+        assert_eq!(
+            pb::ClientType::Producer,
+            manager.get_session("producer").unwrap().client_type()
+        );
+        assert!(manager.get_settings("producer").is_none());
+
+        assert!(manager.remove("producer").is_some());
+        assert!(manager.remove("producer").is_none());
+
+        let (sender, _receiver) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+        manager.register_channel("connected", sender);
+        let expired = ClientSettingManager::expire_idle(&manager.sessions, Duration::ZERO);
+        assert_eq!(vec!["consumer".to_string()], expired);
+        assert!(manager.get_session("consumer").is_none());
+        assert!(manager.get_session("connected").unwrap().is_connected());
+    }
+}
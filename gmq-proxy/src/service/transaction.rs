@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use gmq_remoting::{
+    common::{code::RequestCode, command::Command},
+    processor::RequestProcessor,
+    util,
+};
+use parking_lot::RwLock;
+use tokio::time::interval;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use super::{
+    consumer::to_pb_message,
+    producer::PRODUCER_GROUP,
+    route::RouteService,
+    session::{ClientSession, ClientSettingManager},
+};
+use crate::{
+    common::{ok_status, status, Error},
+    pb::{self, settings::PubSub, telemetry_command, Code, Status},
+    remoting::{
+        client::{HeartbeatData, MQClient, ProducerData},
+        header::{
+            CheckTransactionStateRequestHeader, EndTransactionRequestHeader,
+            PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX,
+        },
+        message::{decode_messages, TRANSACTION_COMMIT_TYPE, TRANSACTION_ROLLBACK_TYPE},
+    },
+};
+
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+/// Brokers only check the transaction states with the producers sending heartbeats.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// Half messages not resolved in this long are forgotten, brokers keep checking their states.
+const MAX_TRANSACTION_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/**
+ * A half message sent by a client, which stays invisible to consumers until the transaction is
+ * committed.
+ */
+#[derive(Debug, Clone)]
+pub struct TransactionData {
+    pub client_id: String,
+    pub producer_group: String,
+    pub topic: String,
+    pub broker_name: String,
+    pub message_id: String,
+    pub transaction_id: String,
+    pub tran_state_table_offset: i64,
+    pub commit_log_offset: i64,
+    pub created_at: Instant,
+}
+
+/**
+ * TransactionService remembers the broker holding each half message, and ends the transactions
+ * there on behalf of the clients. It also registers the proxy as the producer group on brokers,
+ * and relays their transaction checks to the connected producers.
+ */
+#[derive(Debug)]
+pub struct TransactionService {
+    mq_client: Arc<MQClient>,
+    route_service: Arc<RouteService>,
+    setting_manager: Arc<ClientSettingManager>,
+    client_id: String,
+    transactions: Arc<RwLock<HashMap<String, TransactionData>>>,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
+}
+
+impl TransactionService {
+    pub fn new(
+        mq_client: Arc<MQClient>,
+        route_service: Arc<RouteService>,
+        setting_manager: Arc<ClientSettingManager>,
+    ) -> Self {
+        Self {
+            mq_client,
+            route_service,
+            setting_manager,
+            client_id: format!("PROXY@{:016X}", rand::random::<u64>()),
+            transactions: Arc::new(RwLock::new(HashMap::new())),
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
+        }
+    }
+
+    /// Starts handling transaction checks, sending heartbeats to brokers and forgetting the
+    /// transactions left unresolved for too long periodically.
+    pub fn start(&self) {
+        self.mq_client.register_processor(
+            RequestCode::CheckTransactionState,
+            Arc::new(TransactionCheckProcessor {
+                setting_manager: Arc::clone(&self.setting_manager),
+                transactions: Arc::clone(&self.transactions),
+            }),
+        );
+
+        let mq_client = Arc::clone(&self.mq_client);
+        let route_service = Arc::clone(&self.route_service);
+        let heartbeat = HeartbeatData {
+            client_id: self.client_id.clone(),
+            producer_data_set: vec![ProducerData {
+                group_name: PRODUCER_GROUP.to_string(),
+            }],
+            consumer_data_set: vec![],
+        };
+        let transactions = Arc::clone(&self.transactions);
+        let shutdown_token = self.shutdown_token.clone();
+        self.shutdown_tracker.spawn(async move {
+            let mut heartbeat_ticker = interval(HEARTBEAT_INTERVAL);
+            let mut expire_ticker = interval(EXPIRE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = heartbeat_ticker.tick() => {
+                        for addr in route_service.master_addrs() {
+                            if let Err(e) = mq_client.send_heartbeat(&addr, &heartbeat).await {
+                                println!("send heartbeat to broker {} failed: {:?}", addr, e);
+                            }
+                        }
+                    }
+                    _ = expire_ticker.tick() => {
+                        transactions
+                            .write()
+                            .retain(|_, data| data.created_at.elapsed() < MAX_TRANSACTION_DURATION);
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn add(&self, data: TransactionData) {
+        self.transactions
+            .write()
+            .insert(data.transaction_id.clone(), data);
+    }
+
+    pub fn get(&self, transaction_id: &str) -> Option<TransactionData> {
+        self.transactions.read().get(transaction_id).cloned()
+    }
+
+    /// Commits or rolls back the half message on the broker holding it.
+    pub async fn end_transaction(
+        &self,
+        request: pb::EndTransactionRequest,
+    ) -> pb::EndTransactionResponse {
+        let status = match self.end(request).await {
+            Ok(()) => ok_status(),
+            Err(status) => status,
+        };
+        pb::EndTransactionResponse {
+            status: Some(status),
+        }
+    }
+
+    async fn end(&self, request: pb::EndTransactionRequest) -> Result<(), Status> {
+        let topic = request
+            .topic
+            .map(|topic| topic.name)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| status(Code::IllegalTopic, "topic is required"))?;
+        if request.transaction_id.is_empty() {
+            return Err(status(
+                Code::InvalidTransactionId,
+                "transaction id is required",
+            ));
+        }
+        let commit_or_rollback = match pb::TransactionResolution::try_from(request.resolution) {
+            Ok(pb::TransactionResolution::Commit) => TRANSACTION_COMMIT_TYPE,
+            Ok(pb::TransactionResolution::Rollback) => TRANSACTION_ROLLBACK_TYPE,
+            _ => {
+                return Err(status(
+                    Code::BadRequest,
+                    "transaction resolution should be commit or rollback",
+                ))
+            }
+        };
+        let data = self
+            .get(&request.transaction_id)
+            .filter(|data| data.topic == topic)
+            .ok_or_else(|| {
+                status(
+                    Code::InvalidTransactionId,
+                    format!("transaction {} is not found", request.transaction_id),
+                )
+            })?;
+
+        let addr = self
+            .route_service
+            .get_master_addr(&data.topic, &data.broker_name)
+            .await
+            .map_err(|e| e.to_status())?;
+        let header = EndTransactionRequestHeader {
+            producer_group: data.producer_group,
+            topic: data.topic,
+            tran_state_table_offset: data.tran_state_table_offset,
+            commit_log_offset: data.commit_log_offset,
+            commit_or_rollback,
+            from_transaction_check: request.source
+                == pb::TransactionSource::SourceServerCheck as i32,
+            msg_id: data.message_id,
+            transaction_id: data.transaction_id,
+            broker_name: data.broker_name,
+        };
+        self.mq_client
+            .end_transaction(&addr, header)
+            .await
+            .map_err(|e| e.to_status())?;
+        self.transactions.write().remove(&request.transaction_id);
+        Ok(())
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+    }
+}
+
+/**
+ * TransactionCheckProcessor relays the CHECK_TRANSACTION_STATE requests of brokers to a
+ * connected producer, which resolves the transaction with EndTransaction later.
+ */
+struct TransactionCheckProcessor {
+    setting_manager: Arc<ClientSettingManager>,
+    transactions: Arc<RwLock<HashMap<String, TransactionData>>>,
+}
+
+#[async_trait]
+impl RequestProcessor for TransactionCheckProcessor {
+    async fn process(&self, request: &Command) -> Result<Option<Command>, util::Error> {
+        // brokers send the checks oneway and check again later if nobody resolves them.
+        if let Err(e) = self.check(request) {
+            println!("check transaction state failed: {:?}", e);
+        }
+        Ok(None)
+    }
+}
+
+impl TransactionCheckProcessor {
+    fn check(&self, request: &Command) -> Result<(), Error> {
+        let header = CheckTransactionStateRequestHeader::decode(request)?;
+        let message = decode_messages(request.body().unwrap_or_default())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::InternalError(anyhow!("no message to check")))?;
+        let broker_name = header
+            .broker_name
+            .ok_or_else(|| Error::InternalError(anyhow!("broker name is missing")))?;
+        let topic = header.topic.unwrap_or_else(|| message.topic.clone());
+        let message_id = message
+            .properties
+            .get(PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX)
+            .cloned()
+            .or(header.msg_id)
+            .unwrap_or_else(|| message.offset_msg_id.clone());
+        let transaction_id = header.transaction_id.unwrap_or_else(|| message_id.clone());
+
+        let preferred = self
+            .transactions
+            .read()
+            .get(&transaction_id)
+            .map(|data| data.client_id.clone());
+        let client_id = pick_producer(
+            self.setting_manager.sessions(),
+            preferred.as_deref(),
+            &topic,
+        )
+        .ok_or_else(|| {
+            Error::InternalError(anyhow!(
+                "no producer connected to resolve transaction {}",
+                transaction_id
+            ))
+        })?;
+
+        // the producer resolves the transaction with the data of the broker checking it.
+        self.transactions.write().insert(
+            transaction_id.clone(),
+            TransactionData {
+                client_id: client_id.clone(),
+                producer_group: PRODUCER_GROUP.to_string(),
+                topic: topic.clone(),
+                broker_name,
+                message_id,
+                transaction_id: transaction_id.clone(),
+                tran_state_table_offset: header.tran_state_table_offset,
+                commit_log_offset: header.commit_log_offset,
+                created_at: Instant::now(),
+            },
+        );
+        let resource = pb::Resource {
+            resource_namespace: String::new(),
+            name: topic,
+        };
+        let command = telemetry_command::Command::RecoverOrphanedTransactionCommand(
+            pb::RecoverOrphanedTransactionCommand {
+                message: Some(to_pb_message(message, &resource, None, None)),
+                transaction_id,
+            },
+        );
+        self.setting_manager.push_command(&client_id, command)
+    }
+}
+
+/// Picks the connected producer to resolve a transaction: the one sending the half message if it
+/// is still connected, otherwise one publishing to the topic, otherwise any producer.
+fn pick_producer(
+    sessions: Vec<ClientSession>,
+    preferred: Option<&str>,
+    topic: &str,
+) -> Option<String> {
+    let producers: Vec<ClientSession> = sessions
+        .into_iter()
+        .filter(|session| {
+            session.is_connected() && session.client_type() == pb::ClientType::Producer
+        })
+        .collect();
+    if let Some(preferred) = preferred {
+        if producers
+            .iter()
+            .any(|session| session.client_id() == preferred)
+        {
+            return Some(preferred.to_string());
+        }
+    }
+    let publishes = |session: &&ClientSession| match session
+        .settings()
+        .and_then(|settings| settings.pub_sub.as_ref())
+    {
+        Some(PubSub::Publishing(publishing)) => publishing.topics.iter().any(|t| t.name == topic),
+        _ => false,
+    };
+    producers
+        .iter()
+        .find(publishes)
+        .or(producers.first())
+        .map(|session| session.client_id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        remoting::message::{tests::encode_message, MessageExt},
+        service::{mock_broker::setting_manager, session::TELEMETRY_CHANNEL_CAPACITY},
+    };
+
+    fn processor() -> TransactionCheckProcessor {
+        TransactionCheckProcessor {
+            setting_manager: Arc::new(setting_manager()),
+            transactions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn check_request() -> Command {
+        let mut message = MessageExt {
+            topic: "test".to_string(),
+            body: b"hello".to_vec(),
+            ..Default::default()
+        };
+        message.properties.insert(
+            PROPERTY_UNIQ_CLIENT_MESSAGE_ID_KEYIDX.to_string(),
+            "id-1".to_string(),
+        );
+        let mut request = Command::new(RequestCode::CheckTransactionState);
+        request.add_property("topic", "test");
+        request.add_property("tranStateTableOffset", "42");
+        request.add_property("commitLogOffset", "1024");
+        request.add_property("msgId", "id-1");
+        request.add_property("transactionId", "tx-1");
+        request.add_property("brokerName", "broker-a");
+        request.set_body(encode_message(&message));
+        request
+    }
+
+    #[tokio::test]
+    async fn test_check_transaction_state() {
+        let processor = processor();
+        let setting_manager = Arc::clone(&processor.setting_manager);
+        setting_manager.heartbeat("consumer", pb::ClientType::PushConsumer, None);
+        let (consumer_tx, mut consumer_rx) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+        setting_manager.register_channel("consumer", consumer_tx);
+
+        // nobody can resolve the transaction without producers connected.
+        assert!(processor.process(&check_request()).await.unwrap().is_none());
+        assert!(processor.transactions.read().is_empty());
+
+        setting_manager.heartbeat("producer", pb::ClientType::Producer, None);
+        let (producer_tx, mut producer_rx) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+        setting_manager.register_channel("producer", producer_tx);
+        assert!(processor.process(&check_request()).await.unwrap().is_none());
+
+        let pushed = producer_rx.try_recv().unwrap();
+        let Some(telemetry_command::Command::RecoverOrphanedTransactionCommand(command)) =
+            pushed.command
+        else {
+            panic!("unexpected command {:?}", pushed);
+        };
+        assert_eq!("tx-1", command.transaction_id);
+        let message = command.message.unwrap();
+        assert_eq!("test", message.topic.unwrap().name);
+        assert_eq!("id-1", message.system_properties.unwrap().message_id);
+        assert_eq!(b"hello".to_vec(), message.body);
+        assert!(consumer_rx.try_recv().is_err());
+
+        let data = processor.transactions.read()["tx-1"].clone();
+        assert_eq!("producer", data.client_id);
+        assert_eq!(PRODUCER_GROUP, data.producer_group);
+        assert_eq!("broker-a", data.broker_name);
+        assert_eq!("id-1", data.message_id);
+        assert_eq!(42, data.tran_state_table_offset);
+        assert_eq!(1024, data.commit_log_offset);
+    }
+
+    #[test]
+    fn test_pick_producer() {
+        let processor = processor();
+        let setting_manager = &processor.setting_manager;
+        let publishing = |topic: &str| pb::Settings {
+            client_type: Some(pb::ClientType::Producer as i32),
+            pub_sub: Some(PubSub::Publishing(pb::Publishing {
+                topics: vec![pb::Resource {
+                    resource_namespace: String::new(),
+                    name: topic.to_string(),
+                }],
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut receivers = Vec::new();
+        for (client_id, topic) in [("p1", "other"), ("p2", "test")] {
+            setting_manager.update_settings(client_id, publishing(topic));
+            let (tx, rx) = mpsc::channel(TELEMETRY_CHANNEL_CAPACITY);
+            setting_manager.register_channel(client_id, tx);
+            receivers.push(rx);
+        }
+
+        let sessions = setting_manager.sessions();
+        assert_eq!(
+            Some("p1".to_string()),
+            pick_producer(sessions.clone(), Some("p1"), "test")
+        );
+        assert_eq!(
+            Some("p2".to_string()),
+            pick_producer(sessions.clone(), Some("gone"), "test")
+        );
+        assert!(pick_producer(sessions, None, "unknown").is_some());
+        assert_eq!(None, pick_producer(vec![], None, "test"));
+    }
+}
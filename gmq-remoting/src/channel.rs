@@ -1,23 +1,60 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use futures_util::{SinkExt, StreamExt};
 use parking_lot::RwLock;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpSocket, TcpStream,
     },
     select,
-    sync::{mpsc, oneshot},
-    time::timeout,
+    sync::{mpsc, oneshot, watch},
+    time::{interval, sleep, timeout},
+};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+    task::TaskTracker,
 };
-use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::{common::command::Command, util::Error};
+use crate::{
+    common::{codec::RemotingCodec, command::Command},
+    processor::{ProcessorTable, RequestProcessor},
+    util::{Backoff, Error},
+};
+
+type ResponseTable = HashMap<i32, PendingResponse>;
+type CommandReader = FramedRead<OwnedReadHalf, RemotingCodec>;
+type CommandWriter = FramedWrite<OwnedWriteHalf, RemotingCodec>;
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The state of the connection behind a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    /// The connection is lost and the channel is reconnecting.
+    Connecting,
+    /// The connection is established.
+    Active,
+    /// The channel is shut down.
+    Closed,
+}
 
 #[derive(Debug)]
 pub struct Channel {
     command_sender: mpsc::Sender<Request>,
+    response_table: Arc<RwLock<ResponseTable>>,
+    processor_table: ProcessorTable,
+    state: watch::Receiver<ChannelState>,
     timeout: Duration,
     shutdown_token: CancellationToken,
     shutdown_tracker: TaskTracker,
@@ -25,7 +62,40 @@ pub struct Channel {
 
 struct Request {
     cmd: Command,
+    // None for oneway requests, which never get a response.
+    response: Option<PendingResponse>,
+}
+
+#[derive(Debug)]
+struct PendingResponse {
     response_tx: oneshot::Sender<Result<Command, Error>>,
+    deadline: Instant,
+}
+
+/// Removes the entry of the request from the response table when the caller stops waiting,
+/// either on timeout or because the request future is dropped.
+struct ResponseGuard<'a> {
+    opaque: i32,
+    response_table: &'a RwLock<ResponseTable>,
+}
+
+impl Drop for ResponseGuard<'_> {
+    fn drop(&mut self) {
+        self.response_table.write().remove(&self.opaque);
+    }
+}
+
+/// The task driving the connection of a channel: it writes requests, dispatches what it reads
+/// and reconnects when the connection is lost.
+struct Connection {
+    addr: SocketAddr,
+    codec: RemotingCodec,
+    response_table: Arc<RwLock<ResponseTable>>,
+    processor_table: ProcessorTable,
+    response_sender: mpsc::Sender<Request>,
+    state: watch::Sender<ChannelState>,
+    shutdown_token: CancellationToken,
+    tracker: TaskTracker,
 }
 
 /**
@@ -33,191 +103,420 @@ struct Request {
  */
 impl Channel {
     pub async fn new(addr: &str) -> Result<Self, Error> {
+        Self::new_with_codec(addr, RemotingCodec::default()).await
+    }
+
+    /// Creates a channel framing commands with the codec, e.g. to limit the frame size.
+    /// It fails if the first connection can not be established, later connection losses are
+    /// recovered by reconnecting in the background.
+    pub async fn new_with_codec(addr: &str, codec: RemotingCodec) -> Result<Self, Error> {
+        Self::connect(addr, codec, ProcessorTable::new()).await
+    }
+
+    /// Creates a channel dispatching requests sent by the peer to the processor table, which
+    /// may be shared by several channels.
+    pub(crate) async fn connect(
+        addr: &str,
+        codec: RemotingCodec,
+        processor_table: ProcessorTable,
+    ) -> Result<Self, Error> {
         let addr = addr
             .parse()
             .map_err(|_| Error::InvalidAddress(addr.to_string()))?;
-        let (tx, mut request_rx) = mpsc::channel(1024);
+        let stream = Channel::new_stream(addr).await?;
+
+        let (tx, request_rx) = mpsc::channel(1024);
         let command_sender: mpsc::Sender<Request> = tx;
-        let response_table: Arc<RwLock<HashMap<usize, oneshot::Sender<Result<Command, Error>>>>> =
-            Arc::new(RwLock::new(HashMap::new()));
+        let (state_tx, state_rx) = watch::channel(ChannelState::Active);
+        let response_table = Arc::new(RwLock::new(HashMap::new()));
         let token = CancellationToken::new();
-
-        let (create_stream_tx, mut create_stream_rx) = mpsc::channel(32);
-        let create_stream_tx: mpsc::Sender<()> = create_stream_tx;
-
-        let (reader_tx, mut reader_rx) = mpsc::channel(32);
-        let reader_tx: mpsc::Sender<OwnedReadHalf> = reader_tx;
-        let (writer_tx, mut writer_rx) = mpsc::channel(32);
-        let writer_tx: mpsc::Sender<OwnedWriteHalf> = writer_tx;
-
-        let shutdown_token = token.clone();
-
         let task_tracker = TaskTracker::new();
-        task_tracker.spawn(async move {
-            loop {
-                select! {
-                    _ = create_stream_rx.recv() => {
-                        let result = Channel::new_stream(addr).await;
-                        if let Ok(stream) = result {
-                            let (reader, writer) = stream.into_split();
-                            let _reader = reader_tx.send(reader).await;
-                            let _writer = writer_tx.send(writer).await;
-                        } else {
-                            println!("create stream failed: {:?}", result);
-                        }
-                    }
-                    _ = shutdown_token.cancelled() => {
-                        break;
-                    }
-                }
-            }
-        });
-
-        create_stream_tx
-            .send(())
-            .await
-            .map_err(|e| Error::InternalError(e.into()))?;
-
-        let response_table_for_reader = Arc::clone(&response_table);
-        let shutdown_token_2 = token.clone();
-
-        task_tracker.spawn(async move {
-            loop {
-                select! {
-                    Some(mut reader) = reader_rx.recv() => {
-                        loop {
-                            if shutdown_token_2.is_cancelled() {
-                                println!("shutdown reader loop");
-                                break;
-                            }
-                            match Channel::read_command(&mut reader).await {
-                                Ok(command) => {
-                                    // send out command
-                                    let opaque = command.opaque();
-                                    if let Some(sender) = response_table_for_reader.write().remove(&opaque) {
-                                        if let Err(e) = sender.send(Ok(command)) {
-                                            println!("Send response command error: {:?}", e);
-                                        }
-                                    } else {
-                                        println!("no entry of {} in response table, drop command", opaque);
-                                    }
-                                }
-                                Err(Error::TryLater) => {
-                                    // try later
-                                }
-                                Err(e) => {
-                                    println!("read stream encounters error {:?}", e);
-                                    let _ = create_stream_tx.send(()).await;
-                                    break;
-                                }
-                            }
-
-                        }
-                    }
-                    _ = shutdown_token_2.cancelled() => {
-                        println!("shutdown reader task");
-                        break;
-                    }
-                }
-            }
-        });
 
-        let response_table_for_writer = Arc::clone(&response_table);
-        let shutdown_token3 = token.clone();
-
-        task_tracker.spawn(async move {
-            loop {
-                select! {
-                    Some(mut writer) = writer_rx.recv() => {
-                        loop {
-                            select! {
-                                Some(request) = request_rx.recv() => {
-                                    let opaque = request.cmd.opaque();
-                                    match Channel::write_command(&mut writer, request.cmd).await {
-                                        Ok(_) => {
-                                           response_table_for_writer.write().insert(opaque, request.response_tx);
-                                        }
-                                        Err(e) => {
-                                            let _ = request.response_tx.send(Err(e));
-                                        }
-                                    }
-                                }
-                                _ = shutdown_token3.cancelled() => {
-                                    println!("shutdown write loop");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    _ = shutdown_token3.cancelled() => {
-                        println!("shutdown writer task");
-                        break;
-                    }
-                }
-            }
-        });
-        
+        let connection = Connection {
+            addr,
+            codec,
+            response_table: Arc::clone(&response_table),
+            processor_table: processor_table.clone(),
+            response_sender: command_sender.clone(),
+            state: state_tx,
+            shutdown_token: token.clone(),
+            tracker: task_tracker.clone(),
+        };
+        task_tracker.spawn(connection.run(stream, request_rx));
+        task_tracker.spawn(Channel::sweep(Arc::clone(&response_table), token.clone()));
         task_tracker.close();
 
         Ok(Self {
             command_sender,
-            timeout: Duration::from_secs(10),
-            shutdown_token: token.clone(),
+            response_table,
+            processor_table,
+            state: state_rx,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            shutdown_token: token,
             shutdown_tracker: task_tracker,
         })
     }
 
     async fn new_stream(addr: SocketAddr) -> Result<TcpStream, Error> {
         println!("create new stream with {:?}", addr);
-        let socket = TcpSocket::new_v4()?;
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
         socket.set_nodelay(true)?;
-        let stream = socket.connect(addr).await?;
+        let stream = timeout(CONNECT_TIMEOUT, socket.connect(addr))
+            .await
+            .map_err(|_| Error::Timeout)??;
         Ok(stream)
     }
 
-    async fn read_command(reader: &mut OwnedReadHalf) -> Result<Command, Error> {
-        let mut frame_length_bytes = [0; 4];
-        if reader.peek(&mut frame_length_bytes).await? != 4 {
-            return Err(Error::TryLater);
+    /// Reaps the entries of requests whose deadlines have passed or whose callers are gone,
+    /// e.g. a request written after its caller has given up.
+    async fn sweep(response_table: Arc<RwLock<ResponseTable>>, shutdown_token: CancellationToken) {
+        let mut ticker = interval(SWEEP_INTERVAL);
+        loop {
+            select! {
+                _ = ticker.tick() => {
+                    let now = Instant::now();
+                    let expired: Vec<_> = {
+                        let mut response_table = response_table.write();
+                        let opaques: Vec<i32> = response_table
+                            .iter()
+                            .filter(|(_, r)| r.deadline <= now || r.response_tx.is_closed())
+                            .map(|(opaque, _)| *opaque)
+                            .collect();
+                        opaques
+                            .iter()
+                            .filter_map(|opaque| response_table.remove(opaque))
+                            .collect()
+                    };
+                    for response in expired {
+                        let _ = response.response_tx.send(Err(Error::Timeout));
+                    }
+                }
+                _ = shutdown_token.cancelled() => {
+                    break;
+                }
+            }
         }
-        let frame_length = 4 + u32::from_be_bytes(frame_length_bytes) as usize;
-        let mut buf: Vec<u8> = Vec::with_capacity(frame_length);
+    }
 
-        while buf.len() < frame_length {
-            reader.read_buf(&mut buf).await?;
-        }
+    pub fn state(&self) -> ChannelState {
+        *self.state.borrow()
+    }
 
-        return Command::decode_vec(buf);
+    /// Returns a receiver notified whenever the state of the channel changes.
+    pub fn state_watcher(&self) -> watch::Receiver<ChannelState> {
+        self.state.clone()
     }
 
-    async fn write_command(writer: &mut OwnedWriteHalf, cmd: Command) -> Result<(), Error> {
-        let mut raw_data = cmd.encode();
-        let _ = writer
-            .write_all(&mut raw_data)
-            .await
-            .map_err(|e| Error::WriteError(e.into()))?;
-        Ok(())
+    /// Registers the processor of requests with the code sent by the peer.
+    pub fn register_processor(&self, code: impl Into<i32>, processor: Arc<dyn RequestProcessor>) {
+        self.processor_table.register(code, processor);
     }
 
     pub async fn request(&self, cmd: Command) -> Result<Command, Error> {
+        self.request_with_timeout(cmd, self.timeout).await
+    }
+
+    /// Sends the request and waits for its response at most `timeout`.
+    pub async fn request_with_timeout(
+        &self,
+        cmd: Command,
+        timeout_duration: Duration,
+    ) -> Result<Command, Error> {
         let (response_tx, response_rx) = oneshot::channel();
-        let request = Request { cmd, response_tx };
+        let _guard = ResponseGuard {
+            opaque: cmd.opaque(),
+            response_table: &self.response_table,
+        };
+        let request = Request {
+            cmd,
+            response: Some(PendingResponse {
+                response_tx,
+                deadline: Instant::now() + timeout_duration,
+            }),
+        };
         let result = self.command_sender.try_send(request);
         if let Err(e) = result {
             return Err(Error::WriteError(e.into()));
         }
-        match timeout(self.timeout, response_rx).await {
+        match timeout(timeout_duration, response_rx).await {
             Ok(response) => match response {
                 Ok(Ok(command)) => Ok(command),
                 Ok(Err(e)) => Err(e),
-                Err(_) => Err(Error::ReadError),
+                Err(_) => Err(Error::ConnectionClosed),
             },
             Err(_) => Err(Error::Timeout),
         }
     }
 
+    /// Queues the command to the connection without waiting for it to be written, the peer is
+    /// told not to respond.
+    pub fn oneway(&self, mut cmd: Command) -> Result<(), Error> {
+        cmd.mark_oneway_rpc();
+        let request = Request {
+            cmd,
+            response: None,
+        };
+        self.command_sender
+            .try_send(request)
+            .map_err(|e| Error::WriteError(e.into()))
+    }
+
     pub async fn shutdown(&self) {
         self.shutdown_token.cancel();
         self.shutdown_tracker.wait().await;
     }
 }
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        // the connection task holds a sender of its own requests, so it never sees the channel
+        // closed and would keep reconnecting without this.
+        self.shutdown_token.cancel();
+    }
+}
+
+impl Connection {
+    async fn run(self, mut stream: TcpStream, mut request_rx: mpsc::Receiver<Request>) {
+        loop {
+            self.state.send_replace(ChannelState::Active);
+            self.serve(stream, &mut request_rx).await;
+            self.fail_pending_requests();
+            if self.shutdown_token.is_cancelled() {
+                break;
+            }
+
+            self.state.send_replace(ChannelState::Connecting);
+            match self.reconnect().await {
+                Some(new_stream) => stream = new_stream,
+                None => break,
+            }
+        }
+
+        self.state.send_replace(ChannelState::Closed);
+        request_rx.close();
+        while let Ok(request) = request_rx.try_recv() {
+            if let Some(response) = request.response {
+                let _ = response.response_tx.send(Err(Error::ConnectionClosed));
+            }
+        }
+        self.fail_pending_requests();
+    }
+
+    /// Serves the connection until it is broken or the channel is shut down.
+    async fn serve(&self, stream: TcpStream, request_rx: &mut mpsc::Receiver<Request>) {
+        let (reader, writer) = stream.into_split();
+        let reader = FramedRead::new(reader, self.codec.clone());
+        let mut writer = FramedWrite::new(writer, self.codec.clone());
+        let connection_token = self.shutdown_token.child_token();
+
+        let reader_task = self.tracker.spawn(Connection::read_loop(
+            reader,
+            Arc::clone(&self.response_table),
+            self.processor_table.clone(),
+            self.response_sender.clone(),
+            self.tracker.clone(),
+            connection_token.clone(),
+        ));
+
+        loop {
+            select! {
+                Some(request) = request_rx.recv() => {
+                    if let Err(e) = self.write_request(&mut writer, request).await {
+                        println!("write stream encounters error {:?}", e);
+                        break;
+                    }
+                }
+                _ = connection_token.cancelled() => {
+                    break;
+                }
+            }
+        }
+        connection_token.cancel();
+        let _ = reader_task.await;
+    }
+
+    async fn write_request(
+        &self,
+        writer: &mut CommandWriter,
+        request: Request,
+    ) -> Result<(), Error> {
+        let opaque = request.cmd.opaque();
+        let expect_response = request.response.is_some();
+        // register before writing, the response may arrive before the write returns.
+        if let Some(response) = request.response {
+            self.response_table.write().insert(opaque, response);
+        }
+        if let Err(e) = writer.send(request.cmd).await {
+            if !expect_response {
+                println!("write oneway command {} error: {:?}", opaque, e);
+            } else if let Some(response) = self.response_table.write().remove(&opaque) {
+                let _ = response.response_tx.send(Err(Error::ConnectionClosed));
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn read_loop(
+        mut reader: CommandReader,
+        response_table: Arc<RwLock<ResponseTable>>,
+        processor_table: ProcessorTable,
+        response_sender: mpsc::Sender<Request>,
+        tracker: TaskTracker,
+        connection_token: CancellationToken,
+    ) {
+        loop {
+            let result = select! {
+                result = reader.next() => result.unwrap_or(Err(Error::ConnectionClosed)),
+                _ = connection_token.cancelled() => break,
+            };
+            match result {
+                Ok(command) if !command.is_response_type() => {
+                    // a request initiated by the peer
+                    let processor_table = processor_table.clone();
+                    let response_sender = response_sender.clone();
+                    tracker.spawn(async move {
+                        if let Some(response) = processor_table.process(command).await {
+                            let request = Request {
+                                cmd: response,
+                                response: None,
+                            };
+                            if let Err(e) = response_sender.send(request).await {
+                                println!("send response command error: {:?}", e);
+                            }
+                        }
+                    });
+                }
+                Ok(command) => {
+                    let opaque = command.opaque();
+                    let response = response_table.write().remove(&opaque);
+                    if let Some(response) = response {
+                        if let Err(e) = response.response_tx.send(Ok(command)) {
+                            println!("Send response command error: {:?}", e);
+                        }
+                    } else {
+                        println!("no entry of {} in response table, drop command", opaque);
+                    }
+                }
+                Err(e) => {
+                    println!("read stream encounters error {:?}", e);
+                    break;
+                }
+            }
+        }
+        connection_token.cancel();
+    }
+
+    /// Completes the requests waiting for responses, which will never come on a lost connection.
+    fn fail_pending_requests(&self) {
+        let pending: Vec<_> = self.response_table.write().drain().collect();
+        for (_, response) in pending {
+            let _ = response.response_tx.send(Err(Error::ConnectionClosed));
+        }
+    }
+
+    /// Reconnects with exponential backoff, returns None if the channel is shut down.
+    async fn reconnect(&self) -> Option<TcpStream> {
+        let mut backoff = Backoff::new(RECONNECT_INITIAL_BACKOFF, RECONNECT_MAX_BACKOFF);
+        loop {
+            select! {
+                _ = sleep(backoff.next_delay()) => {}
+                _ = self.shutdown_token.cancelled() => return None,
+            }
+            match Channel::new_stream(self.addr).await {
+                Ok(stream) => return Some(stream),
+                Err(e) => println!("reconnect to {:?} failed: {:?}", self.addr, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::common::code::RequestCode;
+
+    #[tokio::test]
+    async fn test_fail_pending_requests_on_connection_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let channel = Channel::new(&addr.to_string()).await.unwrap();
+        let mut state = channel.state_watcher();
+        assert_eq!(ChannelState::Active, channel.state());
+
+        // accept the connection and close it once the request is received.
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = FramedRead::new(stream, RemotingCodec::default());
+            reader.next().await.unwrap().unwrap();
+        });
+
+        let result = channel.request(Command::new(RequestCode::HeartBeat)).await;
+        assert!(matches!(result, Err(Error::ConnectionClosed)));
+        server.await.unwrap();
+
+        state
+            .wait_for(|s| *s == ChannelState::Connecting)
+            .await
+            .unwrap();
+        channel.shutdown().await;
+        assert_eq!(ChannelState::Closed, channel.state());
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let channel = Channel::new(&addr.to_string()).await.unwrap();
+        // accept the connection but never respond.
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let result = channel
+            .request_with_timeout(
+                Command::new(RequestCode::HeartBeat),
+                Duration::from_millis(50),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(channel.response_table.read().is_empty());
+
+        // a dropped request future removes its entry as well.
+        let request = channel.request(Command::new(RequestCode::HeartBeat));
+        let _ = timeout(Duration::from_millis(50), request).await;
+        assert!(channel.response_table.read().is_empty());
+
+        drop(stream);
+        channel.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_drop_without_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let channel = Channel::new(&addr.to_string()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut state = channel.state_watcher();
+
+        // the peer closing the connection puts the channel in the reconnect loop.
+        drop(stream);
+        state
+            .wait_for(|s| *s == ChannelState::Connecting)
+            .await
+            .unwrap();
+
+        drop(channel);
+        timeout(
+            Duration::from_secs(1),
+            state.wait_for(|s| *s == ChannelState::Closed),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use crate::util::Error;
+
+macro_rules! codes {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:expr,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(i32)]
+        pub enum $name {
+            $($variant = $value,)*
+        }
+
+        impl From<$name> for i32 {
+            fn from(code: $name) -> i32 {
+                code as i32
+            }
+        }
+
+        impl TryFrom<i32> for $name {
+            type Error = Error;
+
+            fn try_from(code: i32) -> Result<Self, Self::Error> {
+                match code {
+                    $($value => Ok($name::$variant),)*
+                    _ => Err(Error::UnknownCode(code)),
+                }
+            }
+        }
+    };
+}
+
+codes! {
+    /// Codes of the requests sent to NameServers and brokers, or sent by brokers to clients.
+    RequestCode {
+        SendMessage = 10,
+        PullMessage = 11,
+        QueryMessage = 12,
+        QueryBrokerOffset = 13,
+        QueryConsumerOffset = 14,
+        UpdateConsumerOffset = 15,
+        UpdateAndCreateTopic = 17,
+        GetAllTopicConfig = 21,
+        GetTopicConfigList = 22,
+        GetTopicNameList = 23,
+        UpdateBrokerConfig = 25,
+        GetBrokerConfig = 26,
+        GetBrokerRuntimeInfo = 28,
+        SearchOffsetByTimestamp = 29,
+        GetMaxOffset = 30,
+        GetMinOffset = 31,
+        GetEarliestMsgStoreTime = 32,
+        ViewMessageById = 33,
+        HeartBeat = 34,
+        UnregisterClient = 35,
+        ConsumerSendMsgBack = 36,
+        EndTransaction = 37,
+        GetConsumerListByGroup = 38,
+        CheckTransactionState = 39,
+        NotifyConsumerIdsChanged = 40,
+        LockBatchMq = 41,
+        UnlockBatchMq = 42,
+        GetAllConsumerOffset = 43,
+        GetAllDelayOffset = 45,
+        PutKvConfig = 100,
+        GetKvConfig = 101,
+        DeleteKvConfig = 102,
+        RegisterBroker = 103,
+        UnregisterBroker = 104,
+        GetTopicRouteInfo = 105,
+        GetBrokerClusterInfo = 106,
+        GetConsumerConnectionList = 203,
+        GetProducerConnectionList = 204,
+        GetAllTopicListFromNameServer = 206,
+        GetConsumerRunningInfo = 307,
+        ConsumeMessageDirectly = 309,
+        SendMessageV2 = 310,
+        SendBatchMessage = 320,
+        QueryAssignment = 400,
+        SetMessageRequestMode = 401,
+        PopMessage = 200050,
+        AckMessage = 200051,
+        PeekMessage = 200052,
+        ChangeMessageInvisibleTime = 200053,
+        Notification = 200054,
+        PollingInfo = 200055,
+        BatchAckMessage = 200151,
+    }
+}
+
+codes! {
+    /// Codes carried by the responses of NameServers and brokers.
+    ResponseCode {
+        Success = 0,
+        SystemError = 1,
+        SystemBusy = 2,
+        RequestCodeNotSupported = 3,
+        TransactionFailed = 4,
+        FlushDiskTimeout = 10,
+        SlaveNotAvailable = 11,
+        FlushSlaveTimeout = 12,
+        MessageIllegal = 13,
+        ServiceNotAvailable = 14,
+        VersionNotSupported = 15,
+        NoPermission = 16,
+        TopicNotExist = 17,
+        TopicExistAlready = 18,
+        PullNotFound = 19,
+        PullRetryImmediately = 20,
+        PullOffsetMoved = 21,
+        QueryNotFound = 22,
+        SubscriptionParseFailed = 23,
+        SubscriptionNotExist = 24,
+        SubscriptionNotLatest = 25,
+        SubscriptionGroupNotExist = 26,
+        FilterDataNotExist = 27,
+        FilterDataNotLatest = 28,
+        TransactionShouldCommit = 200,
+        TransactionShouldRollback = 201,
+        TransactionStateUnknown = 202,
+        TransactionStateGroupWrong = 203,
+        NoBuyerId = 204,
+        NotInCurrentUnit = 205,
+        ConsumerNotOnline = 206,
+        ConsumeMsgTimeout = 207,
+        NoMessage = 208,
+        PollingFull = 209,
+        PollingTimeout = 210,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_code() {
+        assert_eq!(310, i32::from(RequestCode::SendMessageV2));
+        assert_eq!(
+            RequestCode::PopMessage,
+            RequestCode::try_from(200050).unwrap()
+        );
+        assert_eq!(
+            ResponseCode::PullNotFound,
+            ResponseCode::try_from(19).unwrap()
+        );
+        assert!(matches!(
+            ResponseCode::try_from(-1),
+            Err(Error::UnknownCode(-1))
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::anyhow;
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::command::Command;
+use crate::util::{read_u32, Error};
+
+/// The default max size of a frame, excluding the 4 bytes of the length field.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/**
+ * A codec frames Commands on a byte stream, each frame is prefixed with its length.
+ */
+#[derive(Debug, Clone)]
+pub struct RemotingCodec {
+    max_frame_size: usize,
+}
+
+impl RemotingCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl Default for RemotingCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for RemotingCodec {
+    type Item = Command;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let frame_length = read_u32(src) as usize;
+        if frame_length > self.max_frame_size {
+            return Err(Error::DecodeCommandError(anyhow!(
+                "frame of {} bytes exceeds the max frame size {}",
+                frame_length,
+                self.max_frame_size
+            )));
+        }
+        if src.len() < 4 + frame_length {
+            src.reserve(4 + frame_length - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(4 + frame_length);
+        Command::decode(&frame).map(Some)
+    }
+}
+
+impl Encoder<Command> for RemotingCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Command, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let data = item.encode();
+        if data.len() - 4 > self.max_frame_size {
+            return Err(Error::WriteError(anyhow!(
+                "frame of {} bytes exceeds the max frame size {}",
+                data.len() - 4,
+                self.max_frame_size
+            )));
+        }
+        dst.put_slice(&data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_pipelined_frames() {
+        let mut codec = RemotingCodec::default();
+        let mut first = Command::new(1);
+        first.set_body(vec![1; 100]);
+        let second = Command::new(2);
+        let (first_opaque, second_opaque) = (first.opaque(), second.opaque());
+
+        let mut data = BytesMut::new();
+        codec.encode(first, &mut data).unwrap();
+        codec.encode(second, &mut data).unwrap();
+
+        // feed the frames byte by byte, as they may be split arbitrarily by the stream.
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in data.iter() {
+            src.put_u8(*byte);
+            if let Some(command) = codec.decode(&mut src).unwrap() {
+                decoded.push(command);
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(2, decoded.len());
+        assert_eq!(first_opaque, decoded[0].opaque());
+        assert_eq!(vec![1; 100], decoded[0].body().unwrap());
+        assert_eq!(second_opaque, decoded[1].opaque());
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let mut codec = RemotingCodec::new(64);
+        let mut command = Command::new(1);
+        command.set_body(vec![0; 128]);
+
+        let mut dst = BytesMut::new();
+        assert!(codec.encode(command.clone(), &mut dst).is_err());
+
+        let mut src = BytesMut::from(&command.encode()[..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
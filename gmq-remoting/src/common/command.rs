@@ -1,22 +1,59 @@
 use std::{
     collections::HashMap,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicI32, Ordering},
 };
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
 use crate::util::{read_u32, Error};
 
-static REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
+static REQUEST_ID: AtomicI32 = AtomicI32::new(0);
+
+const RPC_TYPE: i32 = 0;
+const RPC_ONEWAY: i32 = 1;
+
+/// Language codes used by the ROCKETMQ header format, indexed by their byte value.
+const LANGUAGES: [&str; 13] = [
+    "JAVA", "CPP", "DOTNET", "PYTHON", "DELPHI", "ERLANG", "RUBY", "OTHER", "HTTP", "GO", "PHP",
+    "OMS", "RUST",
+];
+
+/// The format used to serialize the header of a command, stored in the highest byte of the
+/// header length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum SerializeType {
+    #[default]
+    Json = 0,
+    RocketMQ = 1,
+}
+
+impl TryFrom<u8> for SerializeType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SerializeType::Json),
+            1 => Ok(SerializeType::RocketMQ),
+            _ => Err(Error::DecodeCommandError(anyhow!(
+                "unknown serialize type {}",
+                value
+            ))),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
-    code: u8,
-    flag: u8,
+    code: i32,
+    flag: i32,
     language: String,
-    opaque: usize,
-    remark: Option<String>,
     #[serde(default)]
+    version: i32,
+    opaque: i32,
+    remark: Option<String>,
+    #[serde(default, rename = "extFields")]
     ext_fields: HashMap<String, String>,
 }
 
@@ -24,35 +61,93 @@ pub struct Header {
 pub struct Command {
     header: Header,
     body: Option<Vec<u8>>,
+    serialize_type: SerializeType,
 }
 
 impl Command {
-    pub fn new(code: u8) -> Self {
+    pub fn new(code: impl Into<i32>) -> Self {
         Self::new_with_header(code, HashMap::new())
     }
 
-    pub fn new_with_header(code: u8, custom_header: impl Into<HashMap<String, String>>) -> Self {
+    pub fn new_with_header(
+        code: impl Into<i32>,
+        custom_header: impl Into<HashMap<String, String>>,
+    ) -> Self {
         Self {
             body: None,
             header: Header {
-                code,
+                code: code.into(),
                 flag: 0,
                 language: "RUST".to_string(),
+                version: 0,
                 opaque: REQUEST_ID.fetch_add(1, Ordering::Relaxed),
                 remark: None,
                 ext_fields: custom_header.into(),
             },
+            serialize_type: SerializeType::Json,
         }
     }
 
-    pub fn code(&self) -> u8 {
+    /// Creates the response of the request, it shares the opaque of the request.
+    pub fn new_response(request: &Command, code: impl Into<i32>) -> Self {
+        let mut response = Self::new(code);
+        response.header.opaque = request.opaque();
+        response.serialize_type = request.serialize_type();
+        response.mark_response_type();
+        response
+    }
+
+    pub fn code(&self) -> i32 {
         self.header.code
     }
 
-    pub fn opaque(&self) -> usize {
+    pub fn remark(&self) -> Option<&str> {
+        self.header.remark.as_deref()
+    }
+
+    pub fn set_remark(&mut self, remark: impl Into<String>) {
+        self.header.remark = Some(remark.into());
+    }
+
+    pub fn opaque(&self) -> i32 {
         self.header.opaque
     }
 
+    pub fn set_opaque(&mut self, opaque: i32) {
+        self.header.opaque = opaque;
+    }
+
+    pub fn flag(&self) -> i32 {
+        self.header.flag
+    }
+
+    /// Marks the command as a response to a request with the same opaque.
+    pub fn mark_response_type(&mut self) {
+        self.header.flag |= 1 << RPC_TYPE;
+    }
+
+    pub fn is_response_type(&self) -> bool {
+        self.header.flag & (1 << RPC_TYPE) != 0
+    }
+
+    /// Marks the command as oneway, the peer does not respond to it.
+    pub fn mark_oneway_rpc(&mut self) {
+        self.header.flag |= 1 << RPC_ONEWAY;
+    }
+
+    pub fn is_oneway_rpc(&self) -> bool {
+        self.header.flag & (1 << RPC_ONEWAY) != 0
+    }
+
+    pub fn serialize_type(&self) -> SerializeType {
+        self.serialize_type
+    }
+
+    /// Sets the format the header is encoded with, JSON is used by default.
+    pub fn set_serialize_type(&mut self, serialize_type: SerializeType) {
+        self.serialize_type = serialize_type;
+    }
+
     pub fn add_property(&mut self, key: impl Into<String>, value: impl Into<String>) {
         self.header.ext_fields.insert(key.into(), value.into());
     }
@@ -70,16 +165,17 @@ impl Command {
     }
 
     pub fn encode(self) -> Vec<u8> {
-        let mut length: u32 = 4;
-
-        let header_data = serde_json::to_vec(&self.header).unwrap();
-        length = length + header_data.len() as u32;
+        let header_data = match self.serialize_type {
+            SerializeType::Json => serde_json::to_vec(&self.header).unwrap(),
+            SerializeType::RocketMQ => self.header.encode_rocketmq(),
+        };
 
-        let ref_body = self.body.as_ref();
-        if let Some(body) = ref_body {
-            length = length + body.len() as u32;
+        let mut length = 4 + header_data.len() as u32;
+        if let Some(body) = self.body.as_ref() {
+            length += body.len() as u32;
         }
-        let header_len = (0 << 24) | (header_data.len() & 0x00FFFFFF) as u32;
+        let header_len =
+            ((self.serialize_type as u32) << 24) | (header_data.len() & 0x00FFFFFF) as u32;
         let mut result = Vec::with_capacity(4 + length as usize);
         result.extend(length.to_be_bytes());
         result.extend(header_len.to_be_bytes());
@@ -92,30 +188,167 @@ impl Command {
     }
 
     pub fn decode(data: &[u8]) -> Result<Self, Error> {
-        let length = read_u32(data);
-        let header_length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-        let header: Header = serde_json::from_slice(&data[8..8 + header_length as usize])
-            .map_err(|e| Error::DecodeCommandError(e.into()))?;
-        let total_length = 4 + length;
+        if data.len() < 8 {
+            return Err(Error::DecodeCommandError(anyhow!(
+                "frame of {} bytes is too short",
+                data.len()
+            )));
+        }
+        let total_length = 4 + read_u32(data) as usize;
+        let header_len = read_u32(&data[4..]);
+        let serialize_type = SerializeType::try_from((header_len >> 24) as u8)?;
+        let header_end = 8 + (header_len & 0x00FFFFFF) as usize;
+        if data.len() < total_length || total_length < header_end {
+            return Err(Error::DecodeCommandError(anyhow!(
+                "frame length {} does not match header length {}",
+                total_length,
+                header_end
+            )));
+        }
+        let header = match serialize_type {
+            SerializeType::Json => serde_json::from_slice(&data[8..header_end])
+                .map_err(|e| Error::DecodeCommandError(e.into()))?,
+            SerializeType::RocketMQ => Header::decode_rocketmq(&data[8..header_end])?,
+        };
         Ok(Self {
             header,
-            body: Some(data[8 + header_length as usize..total_length as usize].to_vec()),
+            body: Some(data[header_end..total_length].to_vec()),
+            serialize_type,
         })
     }
 
     pub fn decode_vec(data: Vec<u8>) -> Result<Self, Error> {
-        let length = read_u32(data.as_ref());
-        let header_length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-        let header: Header = serde_json::from_slice(&data[8..8 + header_length as usize])
-            .map_err(|e| Error::DecodeCommandError(e.into()))?;
-        let total_length = 4 + length;
+        Self::decode(&data)
+    }
+}
+
+/**
+ * The ROCKETMQ header format: code(2) language(1) version(2) opaque(4) flag(4)
+ * remark_len(4) remark ext_fields_len(4) {key_len(2) key value_len(4) value}*.
+ * Like the Java implementation, the code is written as a 16-bit integer.
+ */
+impl Header {
+    fn encode_rocketmq(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((self.code as i16).to_be_bytes());
+        let language = LANGUAGES
+            .iter()
+            .position(|l| *l == self.language)
+            .unwrap_or(7) as u8;
+        data.push(language);
+        data.extend((self.version as i16).to_be_bytes());
+        data.extend(self.opaque.to_be_bytes());
+        data.extend(self.flag.to_be_bytes());
+        match self.remark.as_ref() {
+            Some(remark) => {
+                data.extend((remark.len() as i32).to_be_bytes());
+                data.extend(remark.as_bytes());
+            }
+            None => data.extend(0i32.to_be_bytes()),
+        }
+        let mut ext_fields = Vec::new();
+        for (key, value) in self.ext_fields.iter() {
+            ext_fields.extend((key.len() as i16).to_be_bytes());
+            ext_fields.extend(key.as_bytes());
+            ext_fields.extend((value.len() as i32).to_be_bytes());
+            ext_fields.extend(value.as_bytes());
+        }
+        data.extend((ext_fields.len() as i32).to_be_bytes());
+        data.extend(ext_fields);
+        data
+    }
+
+    fn decode_rocketmq(data: &[u8]) -> Result<Self, Error> {
+        let mut reader = HeaderReader { data, pos: 0 };
+        let code = i16::from_be_bytes(reader.read()?) as i32;
+        let [language] = reader.read()?;
+        let version = i16::from_be_bytes(reader.read()?) as i32;
+        let opaque = i32::from_be_bytes(reader.read()?);
+        let flag = i32::from_be_bytes(reader.read()?);
+        let remark_len = reader.read_len()?;
+        let remark = if remark_len > 0 {
+            Some(reader.read_string(remark_len)?)
+        } else {
+            None
+        };
+        let ext_fields_len = reader.read_len()?;
+        let ext_fields_end = reader.end_of(ext_fields_len)?;
+        let mut ext_fields = HashMap::new();
+        while reader.pos < ext_fields_end {
+            let key_len = reader.read_short_len()?;
+            let key = reader.read_string(key_len)?;
+            let value_len = reader.read_len()?;
+            let value = reader.read_string(value_len)?;
+            ext_fields.insert(key, value);
+        }
         Ok(Self {
-            header,
-            body: Some(data[8 + header_length as usize..total_length as usize].to_vec()),
+            code,
+            flag,
+            language: LANGUAGES
+                .get(language as usize)
+                .unwrap_or(&"OTHER")
+                .to_string(),
+            version,
+            opaque,
+            remark,
+            ext_fields,
         })
     }
 }
 
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl HeaderReader<'_> {
+    /// Returns the offset `len` bytes after the current one, which must be within the header.
+    fn end_of(&self, len: usize) -> Result<usize, Error> {
+        self.pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| {
+                Error::DecodeCommandError(anyhow!("header is truncated at {}", self.pos))
+            })
+    }
+
+    /// Reads a 4-byte length, which must not be negative.
+    fn read_len(&mut self) -> Result<usize, Error> {
+        let len = i32::from_be_bytes(self.read()?);
+        self.check_len(len)
+    }
+
+    /// Reads a 2-byte length, which must not be negative.
+    fn read_short_len(&mut self) -> Result<usize, Error> {
+        let len = i16::from_be_bytes(self.read()?);
+        self.check_len(len.into())
+    }
+
+    fn check_len(&self, len: i32) -> Result<usize, Error> {
+        usize::try_from(len).map_err(|_| {
+            Error::DecodeCommandError(anyhow!("negative length {} at {}", len, self.pos))
+        })
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&[u8], Error> {
+        let end = self.end_of(len)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.read_bytes(N)?);
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self, len: usize) -> Result<String, Error> {
+        String::from_utf8(self.read_bytes(len)?.to_vec())
+            .map_err(|e| Error::DecodeCommandError(e.into()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,12 +358,93 @@ mod tests {
         let mut command = Command::new(1);
         command.add_property("test-key", "value");
         command.set_body(vec![1, 2, 3]);
+        let opaque = command.opaque();
 
         let encoded = command.encode();
         let decoded = Command::decode(&encoded).unwrap();
         assert_eq!(1, decoded.code());
-        assert_eq!(0, decoded.opaque());
+        assert_eq!(opaque, decoded.opaque());
         assert_eq!("value", decoded.get_property("test-key").unwrap());
         assert_eq!(vec![1, 2, 3], decoded.body().unwrap());
     }
+
+    #[test]
+    fn test_encode_decode_rocketmq() {
+        let mut command = Command::new(310);
+        command.add_property("a", "topic");
+        command.add_property("b", "");
+        command.set_remark("remark");
+        command.set_body(vec![4, 5, 6]);
+        command.set_serialize_type(SerializeType::RocketMQ);
+        let opaque = command.opaque();
+
+        let encoded = command.encode();
+        assert_eq!(SerializeType::RocketMQ as u8, encoded[4]);
+        let decoded = Command::decode(&encoded).unwrap();
+        assert_eq!(SerializeType::RocketMQ, decoded.serialize_type());
+        assert_eq!(310, decoded.code());
+        assert_eq!(opaque, decoded.opaque());
+        assert_eq!(Some("remark"), decoded.remark());
+        assert_eq!("topic", decoded.get_property("a").unwrap());
+        assert_eq!("", decoded.get_property("b").unwrap());
+        assert_eq!(vec![4, 5, 6], decoded.body().unwrap());
+    }
+
+    #[test]
+    fn test_decode_java_json_header() {
+        let header = br#"{"code":0,"extFields":{"msgId":"abc"},"flag":1,"language":"JAVA","opaque":7,"serializeTypeCurrentRPC":"JSON","version":453}"#;
+        let mut data = Vec::new();
+        data.extend((4 + header.len() as u32).to_be_bytes());
+        data.extend((header.len() as u32).to_be_bytes());
+        data.extend(header);
+
+        let decoded = Command::decode(&data).unwrap();
+        assert_eq!(SerializeType::Json, decoded.serialize_type());
+        assert_eq!(7, decoded.opaque());
+        assert_eq!("abc", decoded.get_property("msgId").unwrap());
+    }
+
+    #[test]
+    fn test_flag() {
+        let mut command = Command::new(34);
+        assert!(!command.is_response_type());
+        assert!(!command.is_oneway_rpc());
+
+        command.mark_oneway_rpc();
+        let decoded = Command::decode(&command.encode()).unwrap();
+        assert!(decoded.is_oneway_rpc());
+        assert!(!decoded.is_response_type());
+
+        let mut command = Command::new(0);
+        command.mark_response_type();
+        assert_eq!(1, command.flag());
+        assert!(command.is_response_type());
+        assert!(!command.is_oneway_rpc());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let mut command = Command::new(1);
+        command.set_serialize_type(SerializeType::RocketMQ);
+        let encoded = command.encode();
+        assert!(Command::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Command::decode(&encoded[..6]).is_err());
+    }
+
+    #[test]
+    fn test_decode_negative_length() {
+        let mut command = Command::new(1);
+        command.set_serialize_type(SerializeType::RocketMQ);
+        let encoded = command.encode();
+        // the remark length follows the code, language, version, opaque and flag in the header.
+        let remark_len = 8 + 13;
+        for offset in [remark_len, remark_len + 4] {
+            let mut malformed = encoded.clone();
+            malformed[offset..offset + 4].copy_from_slice(&(-1i32).to_be_bytes());
+            assert!(matches!(
+                Command::decode(&malformed),
+                Err(Error::DecodeCommandError(_))
+            ));
+        }
+    }
 }
@@ -1 +1,3 @@
+pub mod code;
+pub mod codec;
 pub mod command;
\ No newline at end of file
@@ -1,3 +1,6 @@
 pub mod channel;
 pub mod common;
-pub mod util;
\ No newline at end of file
+pub mod pool;
+pub mod processor;
+pub mod server;
+pub mod util;
@@ -1,4 +1,3 @@
-use gmq_remoting::{channel::Channel, common::command::Command};
 #[tokio::main]
 async fn main() {
 
@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Mutex, RwLock};
+use tokio::{select, time::interval};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+    channel::{Channel, ChannelState},
+    common::{codec::RemotingCodec, command::Command},
+    processor::{ProcessorTable, RequestProcessor},
+    util::Error,
+};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Address -> the lock held while connecting to the address.
+type CreateLocks = Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+#[derive(Debug)]
+struct PooledChannel {
+    channel: Arc<Channel>,
+    last_used: Mutex<Instant>,
+}
+
+/**
+ * A pool of channels keyed by address. Channels are created on first use, shared by all the
+ * callers of the same address, and evicted once closed or idle.
+ */
+#[derive(Debug)]
+pub struct ChannelPool {
+    channels: Arc<RwLock<HashMap<String, Arc<PooledChannel>>>>,
+    // serializes the connects to the same address, connects to different ones run concurrently.
+    create_locks: Arc<CreateLocks>,
+    codec: RemotingCodec,
+    processor_table: ProcessorTable,
+    idle_timeout: Duration,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
+}
+
+impl Default for ChannelPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelPool {
+    pub fn new() -> Self {
+        Self::new_with_idle_timeout(DEFAULT_IDLE_TIMEOUT)
+    }
+
+    /// Creates a pool that evicts channels not used for `idle_timeout`.
+    pub fn new_with_idle_timeout(idle_timeout: Duration) -> Self {
+        Self {
+            channels: Arc::new(RwLock::new(HashMap::new())),
+            create_locks: Arc::new(Mutex::new(HashMap::new())),
+            codec: RemotingCodec::default(),
+            processor_table: ProcessorTable::new(),
+            idle_timeout,
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
+        }
+    }
+
+    /// Starts checking the health of the pooled channels periodically.
+    pub fn start(&self) {
+        let channels = Arc::clone(&self.channels);
+        let create_locks = Arc::clone(&self.create_locks);
+        let idle_timeout = self.idle_timeout;
+        let shutdown_token = self.shutdown_token.clone();
+        let tracker = self.shutdown_tracker.clone();
+        self.shutdown_tracker.spawn(async move {
+            let mut ticker = interval(HEALTH_CHECK_INTERVAL);
+            loop {
+                select! {
+                    _ = ticker.tick() => {
+                        for channel in ChannelPool::remove_unhealthy(&channels, &create_locks, idle_timeout) {
+                            tracker.spawn(async move { channel.shutdown().await });
+                        }
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Registers the processor on every channel of the pool, including the ones created later.
+    pub fn register_processor(&self, code: impl Into<i32>, processor: Arc<dyn RequestProcessor>) {
+        self.processor_table.register(code, processor);
+    }
+
+    /// Returns the channel of the address, connecting to it if there is none in the pool.
+    pub async fn get_channel(&self, addr: &str) -> Result<Arc<Channel>, Error> {
+        if let Some(channel) = self.get_pooled(addr) {
+            return Ok(channel);
+        }
+
+        let create_lock = Arc::clone(
+            self.create_locks
+                .lock()
+                .entry(addr.to_string())
+                .or_default(),
+        );
+        let _guard = create_lock.lock().await;
+        if let Some(channel) = self.get_pooled(addr) {
+            return Ok(channel);
+        }
+        if self.shutdown_token.is_cancelled() {
+            return Err(Error::ConnectionClosed);
+        }
+        let channel = Arc::new(
+            Channel::connect(addr, self.codec.clone(), self.processor_table.clone()).await?,
+        );
+        self.channels.write().insert(
+            addr.to_string(),
+            Arc::new(PooledChannel {
+                channel: Arc::clone(&channel),
+                last_used: Mutex::new(Instant::now()),
+            }),
+        );
+        Ok(channel)
+    }
+
+    fn get_pooled(&self, addr: &str) -> Option<Arc<Channel>> {
+        let channels = self.channels.read();
+        let pooled = channels.get(addr)?;
+        if pooled.channel.state() == ChannelState::Closed {
+            return None;
+        }
+        *pooled.last_used.lock() = Instant::now();
+        Some(Arc::clone(&pooled.channel))
+    }
+
+    pub async fn invoke(&self, addr: &str, cmd: Command) -> Result<Command, Error> {
+        self.get_channel(addr).await?.request(cmd).await
+    }
+
+    pub async fn invoke_with_timeout(
+        &self,
+        addr: &str,
+        cmd: Command,
+        timeout: Duration,
+    ) -> Result<Command, Error> {
+        self.get_channel(addr)
+            .await?
+            .request_with_timeout(cmd, timeout)
+            .await
+    }
+
+    pub async fn invoke_oneway(&self, addr: &str, cmd: Command) -> Result<(), Error> {
+        self.get_channel(addr).await?.oneway(cmd)
+    }
+
+    /// Removes the channel of the address from the pool and shuts it down.
+    pub async fn evict(&self, addr: &str) {
+        let pooled = self.channels.write().remove(addr);
+        ChannelPool::remove_create_lock(&self.create_locks, addr);
+        if let Some(pooled) = pooled {
+            pooled.channel.shutdown().await;
+        }
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.channels.read().keys().cloned().collect()
+    }
+
+    /// Removes the channels which are closed or stay idle for too long, the ones reconnecting
+    /// are kept so that the requests waiting on them go on once reconnected.
+    fn remove_unhealthy(
+        channels: &RwLock<HashMap<String, Arc<PooledChannel>>>,
+        create_locks: &CreateLocks,
+        idle_timeout: Duration,
+    ) -> Vec<Arc<Channel>> {
+        let mut channels = channels.write();
+        let unhealthy: Vec<String> = channels
+            .iter()
+            .filter(|(_, pooled)| {
+                pooled.channel.state() == ChannelState::Closed
+                    || pooled.last_used.lock().elapsed() > idle_timeout
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        unhealthy
+            .iter()
+            .filter_map(|addr| {
+                println!("evict channel of {}", addr);
+                ChannelPool::remove_create_lock(create_locks, addr);
+                channels.remove(addr).map(|pooled| pooled.channel.clone())
+            })
+            .collect()
+    }
+
+    /// Drops the lock of the address unless a connect to it is in progress.
+    fn remove_create_lock(create_locks: &CreateLocks, addr: &str) {
+        let mut create_locks = create_locks.lock();
+        if create_locks
+            .get(addr)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            create_locks.remove(addr);
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        let channels: Vec<_> = self.channels.write().drain().collect();
+        for (_, pooled) in channels {
+            pooled.channel.shutdown().await;
+        }
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        common::code::{RequestCode, ResponseCode},
+        server::RemotingServer,
+    };
+
+    struct HeartbeatProcessor;
+
+    #[async_trait]
+    impl RequestProcessor for HeartbeatProcessor {
+        async fn process(&self, request: &Command) -> Result<Option<Command>, Error> {
+            Ok(Some(Command::new_response(request, ResponseCode::Success)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoke_and_evict() {
+        let server = RemotingServer::new("127.0.0.1:0");
+        server.register_processor(RequestCode::HeartBeat, Arc::new(HeartbeatProcessor));
+        let addr = server.start().await.unwrap().to_string();
+
+        let pool = ChannelPool::new();
+        let response = pool
+            .invoke(&addr, Command::new(RequestCode::HeartBeat))
+            .await
+            .unwrap();
+        assert_eq!(i32::from(ResponseCode::Success), response.code());
+        let channel = pool.get_channel(&addr).await.unwrap();
+        let again = pool.get_channel(&addr).await.unwrap();
+        assert!(Arc::ptr_eq(&channel, &again));
+        assert_eq!(vec![addr.clone()], pool.addresses());
+
+        // channels reconnecting are kept, and the closed ones are evicted.
+        server.shutdown().await;
+        let mut state = channel.state_watcher();
+        state
+            .wait_for(|s| *s == ChannelState::Connecting)
+            .await
+            .unwrap();
+        let evicted =
+            ChannelPool::remove_unhealthy(&pool.channels, &pool.create_locks, DEFAULT_IDLE_TIMEOUT);
+        assert!(evicted.is_empty());
+        assert_eq!(vec![addr.clone()], pool.addresses());
+        channel.shutdown().await;
+        let evicted =
+            ChannelPool::remove_unhealthy(&pool.channels, &pool.create_locks, DEFAULT_IDLE_TIMEOUT);
+        assert_eq!(1, evicted.len());
+        assert!(pool.addresses().is_empty());
+        assert!(pool.create_locks.lock().is_empty());
+
+        assert!(pool.get_channel(&addr).await.is_err());
+        pool.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_while_another_address_is_connecting() {
+        let server = RemotingServer::new("127.0.0.1:0");
+        let addr = server.start().await.unwrap().to_string();
+
+        let pool = ChannelPool::new();
+        // a slow connect to another address holds the lock of that address only.
+        let create_lock = Arc::clone(
+            pool.create_locks
+                .lock()
+                .entry("127.0.0.1:1".to_string())
+                .or_default(),
+        );
+        let _guard = create_lock.lock().await;
+        tokio::time::timeout(Duration::from_secs(1), pool.get_channel(&addr))
+            .await
+            .unwrap()
+            .unwrap();
+
+        pool.shutdown().await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_evict_idle_channels() {
+        let server = RemotingServer::new("127.0.0.1:0");
+        let addr = server.start().await.unwrap().to_string();
+
+        let pool = ChannelPool::new_with_idle_timeout(Duration::from_millis(10));
+        pool.get_channel(&addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let evicted =
+            ChannelPool::remove_unhealthy(&pool.channels, &pool.create_locks, pool.idle_timeout);
+        assert_eq!(1, evicted.len());
+        for channel in evicted {
+            channel.shutdown().await;
+        }
+
+        pool.shutdown().await;
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,115 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use parking_lot::RwLock;
+
+use crate::{
+    common::{code::ResponseCode, command::Command},
+    util::Error,
+};
+
+/**
+ * A processor handles requests sent by the peer, such as the requests brokers send to clients.
+ */
+#[async_trait]
+pub trait RequestProcessor: Send + Sync {
+    /// Processes the request and returns the response built with `Command::new_response`, or
+    /// `None` if nothing should be sent back.
+    async fn process(&self, request: &Command) -> Result<Option<Command>, Error>;
+}
+
+/// Processors registered by request code.
+#[derive(Clone, Default)]
+pub struct ProcessorTable {
+    processors: Arc<RwLock<HashMap<i32, Arc<dyn RequestProcessor>>>>,
+}
+
+impl ProcessorTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, code: impl Into<i32>, processor: Arc<dyn RequestProcessor>) {
+        self.processors.write().insert(code.into(), processor);
+    }
+
+    /// Dispatches the request to the processor of its code and returns the response to write
+    /// back. Oneway requests never get a response.
+    pub async fn process(&self, request: Command) -> Option<Command> {
+        let code = request.code();
+        let processor = self.processors.read().get(&code).cloned();
+        let response = match processor {
+            Some(processor) => match processor.process(&request).await {
+                Ok(response) => response,
+                Err(e) => {
+                    let mut response = Command::new_response(&request, ResponseCode::SystemError);
+                    response.set_remark(e.to_string());
+                    Some(response)
+                }
+            },
+            None => {
+                let mut response =
+                    Command::new_response(&request, ResponseCode::RequestCodeNotSupported);
+                response.set_remark(format!("request code {} not supported", code));
+                Some(response)
+            }
+        };
+        if request.is_oneway_rpc() {
+            return None;
+        }
+        response
+    }
+}
+
+impl fmt::Debug for ProcessorTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProcessorTable")
+            .field("codes", &self.processors.read().keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::code::RequestCode;
+
+    struct EchoProcessor;
+
+    #[async_trait]
+    impl RequestProcessor for EchoProcessor {
+        async fn process(&self, request: &Command) -> Result<Option<Command>, Error> {
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            if let Some(body) = request.body() {
+                response.set_body(body.to_vec());
+            }
+            Ok(Some(response))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process() {
+        let table = ProcessorTable::new();
+        table.register(RequestCode::CheckTransactionState, Arc::new(EchoProcessor));
+
+        let mut request = Command::new(RequestCode::CheckTransactionState);
+        request.set_body(vec![1, 2]);
+        let response = table.process(request.clone()).await.unwrap();
+        assert_eq!(request.opaque(), response.opaque());
+        assert!(response.is_response_type());
+        assert_eq!(i32::from(ResponseCode::Success), response.code());
+        assert_eq!(Some(&[1, 2][..]), response.body());
+
+        let request = Command::new(RequestCode::GetConsumerRunningInfo);
+        let response = table.process(request.clone()).await.unwrap();
+        assert_eq!(request.opaque(), response.opaque());
+        assert_eq!(
+            i32::from(ResponseCode::RequestCodeNotSupported),
+            response.code()
+        );
+
+        let mut request = Command::new(RequestCode::CheckTransactionState);
+        request.mark_oneway_rpc();
+        assert!(table.process(request).await.is_none());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc,
+};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+    task::TaskTracker,
+};
+
+use crate::{
+    common::{codec::RemotingCodec, command::Command},
+    processor::{ProcessorTable, RequestProcessor},
+    util::Error,
+};
+
+/**
+ * A server accepts connections and serves the Command requests sent by clients with the
+ * processors registered by request code.
+ */
+#[derive(Debug)]
+pub struct RemotingServer {
+    addr: String,
+    codec: RemotingCodec,
+    processor_table: ProcessorTable,
+    shutdown_token: CancellationToken,
+    shutdown_tracker: TaskTracker,
+}
+
+impl RemotingServer {
+    pub fn new(addr: &str) -> Self {
+        Self::new_with_codec(addr, RemotingCodec::default())
+    }
+
+    pub fn new_with_codec(addr: &str, codec: RemotingCodec) -> Self {
+        Self {
+            addr: addr.to_string(),
+            codec,
+            processor_table: ProcessorTable::new(),
+            shutdown_token: CancellationToken::new(),
+            shutdown_tracker: TaskTracker::new(),
+        }
+    }
+
+    pub fn register_processor(&self, code: impl Into<i32>, processor: Arc<dyn RequestProcessor>) {
+        self.processor_table.register(code, processor);
+    }
+
+    /// Binds the listener and starts accepting connections, returns the bound address.
+    pub async fn start(&self) -> Result<SocketAddr, Error> {
+        let addr: SocketAddr = self
+            .addr
+            .parse()
+            .map_err(|_| Error::InvalidAddress(self.addr.clone()))?;
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let codec = self.codec.clone();
+        let processor_table = self.processor_table.clone();
+        let shutdown_token = self.shutdown_token.clone();
+        let tracker = self.shutdown_tracker.clone();
+        self.shutdown_tracker.spawn(async move {
+            loop {
+                select! {
+                    result = listener.accept() => {
+                        match result {
+                            Ok((stream, peer)) => {
+                                println!("accept connection from {:?}", peer);
+                                let processor_table = processor_table.clone();
+                                let shutdown_token = shutdown_token.clone();
+                                let connection_tracker = tracker.clone();
+                                tracker.spawn(RemotingServer::serve_connection(
+                                    stream,
+                                    codec.clone(),
+                                    processor_table,
+                                    shutdown_token,
+                                    connection_tracker,
+                                ));
+                            }
+                            Err(e) => {
+                                println!("accept connection error: {:?}", e);
+                            }
+                        }
+                    }
+                    _ = shutdown_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    async fn serve_connection(
+        stream: TcpStream,
+        codec: RemotingCodec,
+        processor_table: ProcessorTable,
+        shutdown_token: CancellationToken,
+        tracker: TaskTracker,
+    ) {
+        let _ = stream.set_nodelay(true);
+        let (reader, writer) = stream.into_split();
+        let mut reader = FramedRead::new(reader, codec.clone());
+        let mut writer = FramedWrite::new(writer, codec);
+        let (response_tx, mut response_rx) = mpsc::channel::<Command>(1024);
+        let connection_token = shutdown_token.child_token();
+
+        let writer_token = connection_token.clone();
+        tracker.spawn(async move {
+            loop {
+                select! {
+                    Some(response) = response_rx.recv() => {
+                        if let Err(e) = writer.send(response).await {
+                            println!("write response error: {:?}", e);
+                            writer_token.cancel();
+                            break;
+                        }
+                    }
+                    _ = writer_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        });
+
+        loop {
+            select! {
+                result = reader.next() => {
+                    match result.unwrap_or(Err(Error::ReadError)) {
+                        Ok(command) if command.is_response_type() => {
+                            println!("server receives response {}, drop command", command.opaque());
+                        }
+                        Ok(command) => {
+                            let processor_table = processor_table.clone();
+                            let response_tx = response_tx.clone();
+                            tracker.spawn(async move {
+                                if let Some(response) = processor_table.process(command).await {
+                                    let _ = response_tx.send(response).await;
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            println!("read connection encounters error {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                _ = connection_token.cancelled() => {
+                    break;
+                }
+            }
+        }
+        connection_token.cancel();
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown_token.cancel();
+        self.shutdown_tracker.close();
+        self.shutdown_tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        channel::Channel,
+        common::code::{RequestCode, ResponseCode},
+    };
+
+    struct TopicRouteProcessor;
+
+    #[async_trait]
+    impl RequestProcessor for TopicRouteProcessor {
+        async fn process(&self, request: &Command) -> Result<Option<Command>, Error> {
+            let mut response = Command::new_response(request, ResponseCode::Success);
+            let topic = request.get_property("topic").cloned().unwrap_or_default();
+            response.set_body(topic.into_bytes());
+            Ok(Some(response))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_requests() {
+        let server = RemotingServer::new("127.0.0.1:0");
+        server.register_processor(
+            RequestCode::GetTopicRouteInfo,
+            Arc::new(TopicRouteProcessor),
+        );
+        let addr = server.start().await.unwrap();
+
+        let channel = Channel::new(&addr.to_string()).await.unwrap();
+        for topic in ["topic-a", "topic-b"] {
+            let mut request = Command::new(RequestCode::GetTopicRouteInfo);
+            request.add_property("topic", topic);
+            let opaque = request.opaque();
+            let response = channel.request(request).await.unwrap();
+            assert_eq!(opaque, response.opaque());
+            assert_eq!(i32::from(ResponseCode::Success), response.code());
+            assert_eq!(topic.as_bytes(), response.body().unwrap());
+        }
+
+        let response = channel
+            .request(Command::new(RequestCode::HeartBeat))
+            .await
+            .unwrap();
+        assert_eq!(
+            i32::from(ResponseCode::RequestCodeNotSupported),
+            response.code()
+        );
+
+        channel.shutdown().await;
+        server.shutdown().await;
+    }
+}
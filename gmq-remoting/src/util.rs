@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use rand::Rng;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -16,12 +19,62 @@ pub enum Error {
     ReadError,
     #[error("write to server error: {0}")]
     WriteError(anyhow::Error),
-    #[error("not available currently")]
-    TryLater,
     #[error("internal error: {0}")]
-    InternalError(anyhow::Error)
+    InternalError(anyhow::Error),
+    #[error("unknown code {0}")]
+    UnknownCode(i32),
+    #[error("connection closed")]
+    ConnectionClosed,
 }
 
 pub fn read_u32(data: &[u8]) -> u32 {
     u32::from_be_bytes([data[0], data[1], data[2], data[3]])
 }
+
+/// Exponential backoff with jitter, each delay is picked randomly from the upper half of the
+/// current backoff so that peers retrying together spread out.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let backoff = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let half = backoff / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+        let bounds = [100, 200, 400, 500, 500];
+        for bound in bounds {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(bound / 2));
+            assert!(delay <= Duration::from_millis(bound));
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}